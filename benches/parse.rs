@@ -0,0 +1,360 @@
+//! Compares parsing dump lines into `serde_json::Value` (how entities used to be read) with the
+//! typed entity model, and measures the whole extraction of a line and the pre-filter that skips
+//! lines before extraction.
+//!
+//! The lines are generated to look like those of the dump, since the dump is too large to include.
+//! The `fixture` group parses real lines of the dump instead: a few entities in
+//! `benches/fixtures/lines.json`, or the lines of another file given in `GEO_DB_BENCH_LINES`, e.g.
+//! from `bzcat latest-all.json.bz2 | head -n 10000`. With the `simd-json` feature, it compares
+//! simd-json to `serde_json::Value`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use crossbeam::channel;
+use geo_db::prefilter::Prefilter;
+use geo_db::rules::Rules;
+use geo_db::wiki_data_line::{handle_line, EntityFormat};
+use geo_db::wiki_entity::Entity;
+use geo_db::wiki_sparql::Classes;
+use geo_db::wikibase::Wikibase;
+use serde_json::{json, Map, Value};
+
+const LANGUAGES: &[&str] = &[
+    "ar", "be", "bg", "ca", "cs", "cy", "da", "de", "el", "en", "eo", "es", "et", "eu", "fa", "fi",
+    "fr", "ga", "gl", "he", "hi", "hr", "hu", "hy", "id", "it", "ja", "ka", "ko", "la", "lt", "lv",
+    "mk", "ms", "nl", "nn", "no", "pl", "pt", "ro", "ru", "sk", "sl", "sq", "sr", "sv", "th", "tr",
+    "uk", "ur", "uz", "vi", "zh",
+];
+
+fn snak(property: &str, datatype: &str, value: Value, value_type: &str) -> Value {
+    json!({
+        "snaktype": "value",
+        "property": property,
+        "hash": "8d2a7b1b4f0e1d8c2d3e4f5a6b7c8d9e0f1a2b3c",
+        "datavalue": { "value": value, "type": value_type },
+        "datatype": datatype,
+    })
+}
+
+fn item(property: &str, id: u64) -> Value {
+    snak(
+        property,
+        "wikibase-item",
+        json!({ "entity-type": "item", "numeric-id": id, "id": format!("Q{}", id) }),
+        "wikibase-entityid",
+    )
+}
+
+fn time(property: &str, year: u32) -> Value {
+    snak(
+        property,
+        "time",
+        json!({
+            "time": format!("+{}-01-01T00:00:00Z", year),
+            "timezone": 0,
+            "before": 0,
+            "after": 0,
+            "precision": 9,
+            "calendarmodel": "http://www.wikidata.org/entity/Q1985727",
+        }),
+        "time",
+    )
+}
+
+fn statement(id: &str, mainsnak: Value, qualifiers: Vec<Value>) -> Value {
+    let mut qualifier_map = Map::new();
+    for qualifier in qualifiers {
+        let property = qualifier["property"].as_str().unwrap().to_string();
+        qualifier_map
+            .entry(property)
+            .or_insert_with(|| json!([]))
+            .as_array_mut()
+            .unwrap()
+            .push(qualifier);
+    }
+    json!({
+        "mainsnak": mainsnak,
+        "type": "statement",
+        "qualifiers": qualifier_map,
+        "id": format!("{}$5A1B2C3D-4E5F-6A7B-8C9D-0E1F2A3B4C5D", id),
+        "rank": "normal",
+        "references": [{
+            "hash": "fa278ebfc458360e5aed63d5058cca83c46134f1",
+            "snaks": {
+                "P143": [item("P143", 328)],
+                "P4656": [snak(
+                    "P4656",
+                    "url",
+                    json!("https://en.wikipedia.org/w/index.php?title=Example&oldid=123456789"),
+                    "string",
+                )],
+                "P813": [time("P813", 2020)],
+            },
+            "snaks-order": ["P143", "P4656", "P813"],
+        }],
+    })
+}
+
+/// An entity with the given statements, and labels, descriptions, aliases and sitelinks in many
+/// languages, like a well-known city.
+fn entity(id: &str, claims: Map<String, Value>) -> String {
+    let mut labels = Map::new();
+    let mut descriptions = Map::new();
+    let mut aliases = Map::new();
+    let mut sitelinks = Map::new();
+    for lang in LANGUAGES {
+        labels.insert(
+            lang.to_string(),
+            json!({ "language": lang, "value": format!("Example {}", lang) }),
+        );
+        descriptions.insert(
+            lang.to_string(),
+            json!({ "language": lang, "value": "a city somewhere in a country, described at length" }),
+        );
+        aliases.insert(
+            lang.to_string(),
+            json!([
+                { "language": lang, "value": format!("Alias {}", lang) },
+                { "language": lang, "value": format!("Other alias {}", lang) },
+            ]),
+        );
+        sitelinks.insert(
+            format!("{}wiki", lang),
+            json!({ "site": format!("{}wiki", lang), "title": "Example", "badges": [] }),
+        );
+    }
+    let entity = json!({
+        "type": "item",
+        "id": id,
+        "labels": labels,
+        "descriptions": descriptions,
+        "aliases": aliases,
+        "claims": claims,
+        "sitelinks": sitelinks,
+        "lastrevid": 1234567890,
+    });
+    format!("{},", entity)
+}
+
+fn city() -> String {
+    let mut claims = Map::new();
+    claims.insert(
+        "P31".into(),
+        json!([statement("Q1", item("P31", 515), vec![])]),
+    );
+    claims.insert(
+        "P17".into(),
+        json!([statement("Q1", item("P17", 183), vec![time("P580", 1990)])]),
+    );
+    claims.insert(
+        "P131".into(),
+        json!([statement("Q1", item("P131", 64), vec![])]),
+    );
+    claims.insert(
+        "P625".into(),
+        json!([statement(
+            "Q1",
+            snak(
+                "P625",
+                "globe-coordinate",
+                json!({
+                    "latitude": 52.516666666667,
+                    "longitude": 13.383333333333,
+                    "altitude": null,
+                    "precision": 0.00027777777777778,
+                    "globe": "http://www.wikidata.org/entity/Q2",
+                }),
+                "globecoordinate",
+            ),
+            vec![],
+        )]),
+    );
+    let populations: Vec<Value> = (1990..2020)
+        .map(|year| {
+            let amount = snak(
+                "P1082",
+                "quantity",
+                json!({ "amount": format!("+{}", 3_000_000 + year), "unit": "1" }),
+                "quantity",
+            );
+            statement("Q1", amount, vec![time("P585", year), item("P459", 39825)])
+        })
+        .collect();
+    claims.insert("P1082".into(), Value::Array(populations));
+    claims.insert(
+        "P1705".into(),
+        json!([statement(
+            "Q1",
+            snak(
+                "P1705",
+                "monolingualtext",
+                json!({ "text": "Example", "language": "de" }),
+                "monolingualtext",
+            ),
+            vec![],
+        )]),
+    );
+    // properties that are not extracted, like most of a city's statements
+    for property in 2000..2040 {
+        let property = format!("P{}", property);
+        let value = snak(&property, "external-id", json!("0123456789"), "string");
+        claims.insert(property, json!([statement("Q1", value, vec![])]));
+    }
+    entity("Q1", claims)
+}
+
+/// An entity that is not extracted, like most of the dump.
+fn person() -> String {
+    let mut claims = Map::new();
+    claims.insert(
+        "P31".into(),
+        json!([statement("Q2", item("P31", 5), vec![])]),
+    );
+    claims.insert(
+        "P569".into(),
+        json!([statement("Q2", time("P569", 1900), vec![])]),
+    );
+    for property in 2000..2030 {
+        let property = format!("P{}", property);
+        let value = snak(&property, "external-id", json!("abcdef"), "string");
+        claims.insert(property, json!([statement("Q2", value, vec![])]));
+    }
+    entity("Q2", claims)
+}
+
+fn classes() -> Classes {
+    let set = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+    Classes {
+        territorial_entities: set(&["Q56061", "Q6256", "Q10864048"]),
+        excluded_territorial_entities: set(&["Q123705"]),
+        second_level_admin_div: set(&["Q10864048"]),
+        human_settlements: set(&["Q515", "Q486972"]),
+        excluded_settlements: set(&["Q123705", "Q159313"]),
+        languages: set(&["Q34770"]),
+    }
+}
+
+fn parse(c: &mut Criterion) {
+    let lines = [("city", city()), ("person", person())];
+
+    let mut group = c.benchmark_group("parse");
+    for (name, line) in &lines {
+        let json = &line[..line.len() - 1];
+        group.throughput(Throughput::Bytes(json.len() as u64));
+        group.bench_with_input(BenchmarkId::new("value", name), json, |b, json| {
+            b.iter(|| serde_json::from_str::<Value>(json).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("entity", name), json, |b, json| {
+            b.iter(|| serde_json::from_str::<Entity>(json).unwrap())
+        });
+    }
+    group.finish();
+
+    let wikibase = Wikibase::default();
+    let rules = Rules::default();
+    let classes = classes();
+    let mut group = c.benchmark_group("handle_line");
+    for (name, line) in &lines {
+        group.throughput(Throughput::Bytes(line.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), line, |b, line| {
+            let (send, recv) = channel::unbounded();
+            b.iter(|| {
+                handle_line(
+                    line,
+                    EntityFormat::Json,
+                    &wikibase,
+                    &rules,
+                    &classes,
+                    &send,
+                    false,
+                )
+                .unwrap();
+                while recv.try_recv().is_ok() {}
+            })
+        });
+    }
+    group.finish();
+
+    let prefilter = Prefilter::new(&wikibase, &rules, &classes);
+    let mut group = c.benchmark_group("prefilter");
+    for (name, line) in &lines {
+        group.throughput(Throughput::Bytes(line.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), line, |b, line| {
+            b.iter(|| prefilter.is_relevant(line))
+        });
+    }
+    group.finish();
+
+    let fixture = fixture_lines();
+    let fixture: Vec<_> = fixture
+        .iter()
+        .map(|line| line.strip_suffix(',').unwrap_or(line))
+        .filter(|line| line.len() > 1)
+        .collect();
+    let fixture_bytes = fixture.iter().map(|line| line.len() as u64).sum();
+    let mut group = c.benchmark_group("fixture");
+    group.throughput(Throughput::Bytes(fixture_bytes));
+    group.bench_function("value", |b| {
+        b.iter(|| {
+            for line in &fixture {
+                serde_json::from_str::<Value>(line).unwrap();
+            }
+        })
+    });
+    group.bench_function("entity", |b| {
+        b.iter(|| {
+            for line in &fixture {
+                serde_json::from_str::<Entity>(line).unwrap();
+            }
+        })
+    });
+    #[cfg(feature = "simd-json")]
+    {
+        use criterion::BatchSize;
+
+        // simd-json parses in place, so every iteration needs fresh copies of the lines
+        let copy = || -> Vec<Vec<u8>> {
+            fixture
+                .iter()
+                .map(|line| line.as_bytes().to_vec())
+                .collect()
+        };
+        group.bench_function("simd_value", |b| {
+            b.iter_batched_ref(
+                copy,
+                |lines| {
+                    for line in lines {
+                        simd_json::to_owned_value(line).unwrap();
+                    }
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function("simd_entity", |b| {
+            let mut buffers = simd_json::Buffers::default();
+            b.iter_batched_ref(
+                copy,
+                |lines| {
+                    for line in lines {
+                        simd_json::serde::from_slice_with_buffers::<Entity>(line, &mut buffers)
+                            .unwrap();
+                    }
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Reads the lines of the file in `GEO_DB_BENCH_LINES`, or of the fixture taken from the dump
+/// (Wikidata is CC0).
+fn fixture_lines() -> Vec<String> {
+    let text = match std::env::var_os("GEO_DB_BENCH_LINES") {
+        Some(path) => std::fs::read_to_string(&path).expect("could not read GEO_DB_BENCH_LINES"),
+        None => include_str!("fixtures/lines.json").into(),
+    };
+    text.lines().map(String::from).collect()
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,4 @@
+-- a resumable database must survive a crash in the middle of a transaction,
+-- so that it's left at its last checkpoint
+PRAGMA journal_mode = TRUNCATE;
+PRAGMA synchronous = FULL;
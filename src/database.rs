@@ -1,8 +1,9 @@
+use crate::input::ResumePosition;
+use crate::places::ReachablePlaces;
 use crossbeam::channel::Receiver;
-use rusqlite::{params, Connection, Transaction};
-use std::collections::VecDeque;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum DataEntry {
     TerritorialEntity {
         id: String,
@@ -22,11 +23,43 @@ pub enum DataEntry {
         id: String,
         code: String,
     },
+    ObjectTimeZone {
+        id: String,
+        time_zone_id: String,
+        index: u32,
+    },
+    TimeZone {
+        id: String,
+        /// IANA time zone identifier, e.g. `Europe/Berlin`.
+        tz: String,
+    },
     City {
         id: String,
         population: Option<u64>,
+        /// Date of the population, as far as it is known (e.g. `2021` or `2021-06-15`).
+        population_date: Option<String>,
         lat: Option<f64>,
         lon: Option<f64>,
+        /// In metres.
+        elevation: Option<f64>,
+        /// In square kilometres.
+        area: Option<f64>,
+    },
+    /// A population of a city, of which there is one for every date.
+    PopulationHistory {
+        id: String,
+        population: u64,
+        date: Option<String>,
+        /// ID of the determination method, e.g. a census.
+        method: Option<String>,
+        /// URL or item ID of the source.
+        reference: Option<String>,
+    },
+    /// An identifier of a city in another database, e.g. GeoNames.
+    ExternalId {
+        id: String,
+        property: String,
+        value: String,
     },
     CityCountry {
         id: String,
@@ -46,17 +79,91 @@ pub enum DataEntry {
     MissingP17 {
         id: String,
     },
+    /// The dump the database is built from.
+    DumpInfo {
+        source: String,
+        etag: Option<String>,
+        /// Date of the dump (YYYYMMDD), if known. If the file name has no date, this is the date
+        /// the file was last modified on the server, which is a few days after the date of the
+        /// dump.
+        date: Option<String>,
+    },
+    /// All entries for lines before the checkpoint have been sent.
+    Checkpoint(Checkpoint),
+}
+
+/// Position in the dump up to which all entries have been written to the database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    /// The URL or path of the dump.
+    pub source: String,
+    pub etag: Option<String>,
+    /// Length of the compressed data, which is compared instead of the etag for mirrors.
+    pub length: Option<u64>,
+    /// Start of the block containing the next line.
+    pub position: ResumePosition,
+    /// Offset of the next line in the decompressed data.
+    pub line_offset: u64,
+    /// Number of lines read so far.
+    pub line_number: u64,
+    /// Whether the entire dump has been read.
+    pub complete: bool,
+}
+
+/// Loads the checkpoint of a previous build from the database.
+pub fn load_checkpoint(out_file: &str) -> rusqlite::Result<Option<Checkpoint>> {
+    let conn = Connection::open(out_file)?;
+    conn.query_row(
+        "select source, etag, length, bit_offset, level, out_offset, line_offset, line_number,
+        complete from build_checkpoint",
+        [],
+        |row| {
+            Ok(Checkpoint {
+                source: row.get(0)?,
+                etag: row.get(1)?,
+                length: row.get::<_, Option<i64>>(2)?.map(|length| length as u64),
+                position: ResumePosition {
+                    bit_offset: row.get::<_, i64>(3)? as u64,
+                    level: row.get(4)?,
+                    out_offset: row.get::<_, i64>(5)? as u64,
+                },
+                line_offset: row.get::<_, i64>(6)? as u64,
+                line_number: row.get::<_, i64>(7)? as u64,
+                complete: row.get(8)?,
+            })
+        },
+    )
+    .optional()
 }
 
-pub fn db_writer(out_file: &str, recv: Receiver<DataEntry>) -> rusqlite::Result<()> {
+/// Writes entries to the database. If `resume` is set, the tables are expected to exist already.
+/// If `places` is set, only the entries it keeps are written.
+///
+/// Once a checkpoint has been written (or when resuming), entries are only committed together
+/// with the next checkpoint, so that a database left behind by a crash never contains entries
+/// past its checkpoint. Entries after the last checkpoint are discarded, since resuming will
+/// write them again.
+pub fn db_writer(
+    out_file: &str,
+    resume: bool,
+    places: Option<&ReachablePlaces>,
+    recv: Receiver<DataEntry>,
+) -> rusqlite::Result<()> {
     debug!("Setting up database");
-    let mut conn = Connection::open(out_file)?;
+    let conn = Connection::open(out_file)?;
 
-    conn.execute_batch(include_str!("setup.sql"))?;
+    conn.execute_batch(include_str!("setup_pragmas.sql"))?;
+    if resume {
+        conn.execute_batch(include_str!("checkpoint_pragmas.sql"))?;
+    } else {
+        conn.execute_batch(include_str!("setup.sql"))?;
+    }
 
     debug!("Database set up");
 
-    let mut item_buffer = VecDeque::with_capacity(128);
+    let mut checkpointed = resume;
+    let mut uncommitted = 0;
+    conn.execute_batch("begin")?;
     loop {
         let item = match recv.recv() {
             Ok(item) => item,
@@ -66,63 +173,162 @@ pub fn db_writer(out_file: &str, recv: Receiver<DataEntry>) -> rusqlite::Result<
             }
         };
 
-        item_buffer.push_back(item);
+        if let Some(places) = places {
+            if !places.keeps(&item) {
+                continue;
+            }
+        }
+
+        // checkpoints are committed in the same transaction as the entries before them
+        let is_checkpoint = matches!(item, DataEntry::Checkpoint(_));
+        insert_entry(&conn, item)?;
+        uncommitted += 1;
 
-        if item_buffer.len() >= 127 {
-            let tx = conn.transaction()?;
-            for item in item_buffer.drain(..) {
-                insert_entry(&tx, item)?;
+        if is_checkpoint || (!checkpointed && uncommitted >= 127) {
+            conn.execute_batch("commit")?;
+            if is_checkpoint && !checkpointed {
+                // the journal mode can't be changed within a transaction
+                conn.execute_batch(include_str!("checkpoint_pragmas.sql"))?;
+                checkpointed = true;
             }
-            tx.commit()?;
+            conn.execute_batch("begin")?;
+            uncommitted = 0;
         }
     }
 
-    if !item_buffer.is_empty() {
-        let tx = conn.transaction()?;
-        for item in item_buffer.drain(..) {
-            insert_entry(&tx, item)?;
-        }
-        tx.commit()?;
+    if checkpointed {
+        conn.execute_batch("rollback")?;
+    } else {
+        conn.execute_batch("commit")?;
     }
 
     Ok(())
 }
 
-fn insert_entry(tx: &Transaction, entry: DataEntry) -> rusqlite::Result<()> {
+/// Tables that contain rows of entities, and the column with the entity ID.
+const ENTITY_TABLES: &[(&str, &str)] = &[
+    ("countries", "id"),
+    ("object_languages", "id"),
+    ("languages", "id"),
+    ("territorial_entities", "id"),
+    ("territorial_entities_parents", "id"),
+    ("object_time_zones", "id"),
+    ("time_zones", "id"),
+    ("cities", "id"),
+    ("cities_countries", "city"),
+    ("population_history", "id"),
+    ("cities_external_ids", "id"),
+    ("object_labels", "id"),
+    ("missing_p17", "id"),
+];
+
+/// Replaces all rows of an entity with the given entries, which must all be about the entity.
+pub fn replace_entity(tx: &Transaction, id: &str, entries: Vec<DataEntry>) -> rusqlite::Result<()> {
+    for (table, column) in ENTITY_TABLES {
+        tx.execute(
+            &format!("delete from {} where {} = ?1", table, column),
+            params![id],
+        )?;
+    }
+    for entry in entries {
+        insert_entry(tx, entry)?;
+    }
+    Ok(())
+}
+
+/// Returns whether the entity has any rows in the database.
+pub fn has_entity(conn: &Connection, id: &str) -> rusqlite::Result<bool> {
+    for (table, column) in ENTITY_TABLES {
+        let found = conn
+            .query_row(
+                &format!("select 1 from {} where {} = ?1 limit 1", table, column),
+                params![id],
+                |_| Ok(()),
+            )
+            .optional()?;
+        if found.is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn insert_entry(conn: &Connection, entry: DataEntry) -> rusqlite::Result<()> {
     match entry {
         DataEntry::TerritorialEntity { id, is_2nd, iso } => {
-            tx.execute(
+            conn.execute(
                 "insert into territorial_entities (id, is_2nd, iso) values (?1, ?2, ?3)",
                 params![id, is_2nd, iso],
             )?;
         }
         DataEntry::TerritorialEntityParent { id, parent } => {
-            tx.execute(
+            conn.execute(
                 "insert into territorial_entities_parents (id, parent) values (?1, ?2) on conflict (id, parent) do nothing",
                 params![id, parent],
             )?;
         }
         DataEntry::ObjectLanguage { id, lang_id, index } => {
-            tx.execute(
+            conn.execute(
                 "insert into object_languages (id, lang_id, lang_index) values (?1, ?2, ?3) on conflict (id, lang_id) do nothing",
                 params![id, lang_id, index],
             )?;
         }
         DataEntry::Language { id, code } => {
-            tx.execute(
+            conn.execute(
                 "insert into languages (id, code) values (?1, ?2)",
                 params![id, code],
             )?;
         }
+        DataEntry::ObjectTimeZone {
+            id,
+            time_zone_id,
+            index,
+        } => {
+            conn.execute(
+                "insert into object_time_zones (id, time_zone_id, tz_index) values (?1, ?2, ?3) on conflict (id, time_zone_id) do nothing",
+                params![id, time_zone_id, index],
+            )?;
+        }
+        DataEntry::TimeZone { id, tz } => {
+            conn.execute(
+                "insert into time_zones (id, tz) values (?1, ?2)",
+                params![id, tz],
+            )?;
+        }
         DataEntry::City {
             id,
             population,
+            population_date,
             lat,
             lon,
+            elevation,
+            area,
         } => {
-            tx.execute(
-                "insert into cities (id, population, lat, lon) values (?1, ?2, ?3, ?4)",
-                params![id, population, lat, lon],
+            conn.execute(
+                "insert into cities (id, population, population_date, lat, lon, elevation, area) values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![id, population, population_date, lat, lon, elevation, area],
+            )?;
+        }
+        DataEntry::PopulationHistory {
+            id,
+            population,
+            date,
+            method,
+            reference,
+        } => {
+            conn.execute(
+                "insert into population_history (id, population, date, method, reference) values (?1, ?2, ?3, ?4, ?5)",
+                params![id, population, date, method, reference],
+            )?;
+        }
+        DataEntry::ExternalId {
+            id,
+            property,
+            value,
+        } => {
+            conn.execute(
+                "insert into cities_external_ids (id, property, value) values (?1, ?2, ?3) on conflict (id, property, value) do nothing",
+                params![id, property, value],
             )?;
         }
         DataEntry::CityCountry {
@@ -130,7 +336,7 @@ fn insert_entry(tx: &Transaction, entry: DataEntry) -> rusqlite::Result<()> {
             country,
             priority,
         } => {
-            tx.execute(
+            conn.execute(
                 "insert or ignore into cities_countries (city, country, priority) values (?1, ?2, ?3)",
                 params![id, country, priority],
             )?;
@@ -141,20 +347,164 @@ fn insert_entry(tx: &Transaction, entry: DataEntry) -> rusqlite::Result<()> {
             label,
             native_order,
         } => {
-            tx.execute(
+            conn.execute(
                 "insert into object_labels (id, lang, label, native_order) values (?1, ?2, ?3, ?4)",
                 params![id, lang, label, native_order],
             )?;
         }
         DataEntry::Country { id, iso } => {
-            tx.execute(
+            conn.execute(
                 "insert into countries (id, iso) values (?1, ?2)",
                 params![id, iso],
             )?;
         }
         DataEntry::MissingP17 { id } => {
-            tx.execute("insert into missing_p17 (id) values (?1)", params![id])?;
+            conn.execute("insert into missing_p17 (id) values (?1)", params![id])?;
+        }
+        DataEntry::DumpInfo { source, etag, date } => {
+            conn.execute(
+                "insert or replace into dump_info (id, source, etag, date) values (0, ?1, ?2, ?3)",
+                params![source, etag, date],
+            )?;
+        }
+        DataEntry::Checkpoint(checkpoint) => {
+            conn.execute(
+                "insert or replace into build_checkpoint (id, source, etag, length, bit_offset, level, out_offset, line_offset, line_number, complete) values (0, ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    checkpoint.source,
+                    checkpoint.etag,
+                    checkpoint.length.map(|length| length as i64),
+                    checkpoint.position.bit_offset as i64,
+                    checkpoint.position.level,
+                    checkpoint.position.out_offset as i64,
+                    checkpoint.line_offset as i64,
+                    checkpoint.line_number as i64,
+                    checkpoint.complete,
+                ],
+            )?;
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::channel::Sender;
+    use std::io::{BufRead, BufReader};
+    use std::path::PathBuf;
+    use std::process::{Command, Stdio};
+    use std::time::Duration;
+    use std::{env, fs, thread};
+
+    /// Set for the writer process spawned by `resumes_after_crash`, to the database path.
+    const CRASH_DB_VAR: &str = "GEO_DB_TEST_CRASH_DB";
+
+    fn send_cities(send: &Sender<DataEntry>, ids: std::ops::Range<u64>) {
+        for i in ids {
+            send.send(DataEntry::City {
+                id: format!("Q{}", i),
+                population: Some(i),
+                population_date: None,
+                lat: None,
+                lon: None,
+                elevation: None,
+                area: None,
+            })
+            .unwrap();
+        }
+    }
+
+    fn checkpoint(line_number: u64, complete: bool) -> DataEntry {
+        DataEntry::Checkpoint(Checkpoint {
+            source: "dump.json.bz2".into(),
+            etag: None,
+            length: None,
+            position: ResumePosition {
+                bit_offset: 32 + line_number,
+                level: 9,
+                out_offset: line_number,
+            },
+            line_offset: line_number,
+            line_number,
+            complete,
+        })
+    }
+
+    fn city_count(path: &str) -> i64 {
+        let conn = Connection::open(path).unwrap();
+        conn.query_row("select count(*) from cities", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    /// Writes entries past a checkpoint, then waits to be killed by `resumes_after_crash`.
+    #[test]
+    #[ignore]
+    fn crashing_writer() {
+        let path = match env::var(CRASH_DB_VAR) {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        let (send, recv) = crossbeam::channel::unbounded();
+        send_cities(&send, 0..500);
+        send.send(checkpoint(500, false)).unwrap();
+        send_cities(&send, 500..1000);
+        let writer = recv.clone();
+        thread::spawn(move || db_writer(&path, false, None, writer).unwrap());
+
+        while !recv.is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // let the writer insert the last entry
+        thread::sleep(Duration::from_millis(100));
+        println!("ready");
+        loop {
+            thread::sleep(Duration::from_secs(60));
+        }
+    }
+
+    #[test]
+    fn resumes_after_crash() {
+        let path: PathBuf =
+            env::temp_dir().join(format!("geo-db-crash-test-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let path_str = path.to_str().unwrap();
+
+        let mut child = Command::new(env::current_exe().unwrap())
+            .args([
+                "database::tests::crashing_writer",
+                "--exact",
+                "--ignored",
+                "--nocapture",
+            ])
+            .env(CRASH_DB_VAR, path_str)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        // the test harness prints the name of the test on the same line
+        let ready = stdout
+            .lines()
+            .any(|line| line.is_ok_and(|line| line.ends_with("ready")));
+        assert!(ready, "writer process exited early");
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let checkpoint_after_crash = load_checkpoint(path_str).unwrap().unwrap();
+        assert_eq!(checkpoint_after_crash.line_number, 500);
+        assert_eq!(city_count(path_str), 500);
+
+        let (send, recv) = crossbeam::channel::unbounded();
+        send_cities(&send, 500..1000);
+        send.send(checkpoint(1000, true)).unwrap();
+        drop(send);
+        db_writer(path_str, true, None, recv).unwrap();
+
+        let checkpoint = load_checkpoint(path_str).unwrap().unwrap();
+        assert_eq!(checkpoint.line_number, 1000);
+        assert!(checkpoint.complete);
+        assert_eq!(city_count(path_str), 1000);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
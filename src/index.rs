@@ -0,0 +1,312 @@
+//! An index of the positions of entities in a local bzip2 dump, so that single entities can be
+//! read from the dump without decompressing all of it.
+
+use crate::input::file::AutoFileInput;
+use crate::input::{DataInput, InputLineIter, LineIterError, ResumePosition};
+use memchr::memmem;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::io;
+use std::time::Instant;
+use thiserror::Error;
+
+/// Number of entities inserted per transaction.
+const BATCH_SIZE: usize = 16384;
+
+#[derive(Debug, Error)]
+pub enum IndexError {
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("error reading dump: {0}")]
+    Lines(#[from] LineIterError<io::Error>),
+    #[error("database error: {0}")]
+    Sql(#[from] rusqlite::Error),
+    #[error("the dump can’t be indexed because it isn’t bzip2-compressed")]
+    NotResumable,
+    #[error("the dump has changed since it was indexed")]
+    DumpChanged,
+    #[error("the index is incomplete")]
+    Incomplete,
+}
+
+/// Position of an entity in the dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntityPosition {
+    /// Start of the block containing the line.
+    pub block: ResumePosition,
+    /// Offset of the line in the decompressed block.
+    pub line_offset: u64,
+}
+
+/// Returns the ID of the entity on a line of the dump.
+///
+/// Relies on the entity's `id` being the first `"id":"` in the line. In the dump, only `pageid`,
+/// `ns`, `title`, `lastrevid`, `modified` and `type` (and `datatype` for properties) come before
+/// it, and everything that has IDs of its own, like the statements in `claims`, comes after it.
+fn entity_id(line: &str) -> Option<&str> {
+    const KEY: &[u8] = b"\"id\":\"";
+    let start = memmem::find(line.as_bytes(), KEY)? + KEY.len();
+    let len = memchr::memchr(b'"', &line.as_bytes()[start..])?;
+    Some(&line[start..start + len])
+}
+
+/// Scans the dump and writes the position of every entity to the index file.
+pub fn build(dump: &str, index_file: &str) -> Result<(), IndexError> {
+    let input = AutoFileInput::open(dump)?;
+    let etag = input.etag();
+    let mut lines = InputLineIter::new(input);
+
+    let mut conn = Connection::open(index_file)?;
+    conn.execute_batch(include_str!("index.sql"))?;
+    conn.execute(
+        "insert into index_info (id, dump, etag, complete) values (0, ?, ?, false)",
+        params![dump, etag],
+    )?;
+
+    let mut count = 0_u64;
+    let mut last_time = Instant::now();
+    let mut tx = conn.transaction()?;
+    let mut batch = 0;
+    loop {
+        let line_offset = lines.line_offset;
+        let position = lines.resume_position();
+        let line = match lines.next() {
+            Ok(line) => line,
+            Err(LineIterError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let id = match entity_id(&line) {
+            Some(id) => id,
+            // the [ and ] lines
+            None => continue,
+        };
+        let block = position.ok_or(IndexError::NotResumable)?;
+
+        tx.prepare_cached(
+            "insert or replace into entity_positions
+            (id, bit_offset, level, block_offset, line_offset) values (?, ?, ?, ?, ?)",
+        )?
+        .execute(params![
+            id,
+            block.bit_offset as i64,
+            block.level,
+            block.out_offset as i64,
+            (line_offset - block.out_offset) as i64,
+        ])?;
+        count += 1;
+        batch += 1;
+
+        if batch >= BATCH_SIZE {
+            tx.commit()?;
+            tx = conn.transaction()?;
+            batch = 0;
+        }
+
+        if last_time.elapsed().as_secs() > 10 {
+            match lines.content_length() {
+                Some(total_bytes) => info!(
+                    "{:02.2}% | {} entities",
+                    lines.input_bytes_read() as f64 / total_bytes as f64 * 100.,
+                    count
+                ),
+                None => info!("{} entities", count),
+            }
+            last_time = Instant::now();
+        }
+    }
+    tx.execute("update index_info set complete = true", [])?;
+    tx.commit()?;
+
+    info!("Indexed {} entities", count);
+    Ok(())
+}
+
+/// A dump along with its index.
+pub struct DumpIndex<'a> {
+    dump: &'a str,
+    etag: Option<String>,
+    conn: Connection,
+}
+
+impl<'a> DumpIndex<'a> {
+    pub fn open(dump: &'a str, index_file: &str) -> Result<Self, IndexError> {
+        let conn = Connection::open(index_file)?;
+        let (etag, complete): (Option<String>, bool) =
+            conn.query_row("select etag, complete from index_info", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+        if !complete {
+            return Err(IndexError::Incomplete);
+        }
+        if AutoFileInput::open(dump)?.etag() != etag {
+            return Err(IndexError::DumpChanged);
+        }
+        Ok(DumpIndex { dump, etag, conn })
+    }
+
+    pub fn position(&self, id: &str) -> Result<Option<EntityPosition>, IndexError> {
+        Ok(self
+            .conn
+            .query_row(
+                "select bit_offset, level, block_offset, line_offset
+                from entity_positions where id = ?",
+                [id],
+                |row| {
+                    Ok(EntityPosition {
+                        block: ResumePosition {
+                            bit_offset: row.get::<_, i64>(0)? as u64,
+                            level: row.get(1)?,
+                            out_offset: row.get::<_, i64>(2)? as u64,
+                        },
+                        line_offset: row.get::<_, i64>(3)? as u64,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    /// Reads the line of the entity with the given ID from the dump.
+    pub fn entity(&self, id: &str) -> Result<Option<String>, IndexError> {
+        let position = match self.position(id)? {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+
+        let input = AutoFileInput::resume(self.dump, &position.block)?;
+        if input.etag() != self.etag {
+            return Err(IndexError::DumpChanged);
+        }
+        let line_offset = position.block.out_offset + position.line_offset;
+        let mut lines = InputLineIter::resume(input, &position.block, line_offset)?;
+        let line = lines.next()?;
+        lines.finish();
+
+        if entity_id(&line) != Some(id) {
+            return Err(IndexError::DumpChanged);
+        }
+        Ok(Some(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bzip2::write::BzEncoder;
+    use std::fs;
+    use std::io::Write;
+
+    /// Returns the lines of a dump that spans several bzip2 blocks.
+    fn dump_lines() -> Vec<String> {
+        let mut lines = vec!["[".to_string()];
+        let mut x: u64 = 1;
+        for i in 0..20_000 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            lines.push(format!(
+                "{{\"type\":\"item\",\"id\":\"Q{i}\",\"claims\":{{\"P1\":[{{\"id\":\"Q{i}${x:x}\"}}]}}}},",
+                i = i,
+                x = x
+            ));
+        }
+        lines.push("]".into());
+        lines
+    }
+
+    #[test]
+    fn finds_ids_in_dump_lines() {
+        let fixture = include_str!("../benches/fixtures/lines.json");
+        let mut count = 0;
+        for line in fixture.lines().filter(|line| line.len() > 1) {
+            let entity: serde_json::Value =
+                serde_json::from_str(line.trim_end_matches(',')).unwrap();
+            assert_eq!(entity_id(line), entity["id"].as_str());
+            count += 1;
+        }
+        assert_eq!(count, 5);
+        assert_eq!(entity_id("["), None);
+    }
+
+    #[test]
+    fn reads_entities_from_the_dump() {
+        let lines = dump_lines();
+        let data = lines
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect::<String>();
+        let dir = std::env::temp_dir().join(format!("geo-db-index-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dump = dir.join("dump.json.bz2");
+        let dump = dump.to_str().unwrap();
+        let index_file = format!("{}.index", dump);
+        let mut encoder = BzEncoder::new(Vec::new(), bzip2::Compression::new(1));
+        encoder.write_all(data.as_bytes()).unwrap();
+        fs::write(dump, encoder.finish().unwrap()).unwrap();
+
+        build(dump, &index_file).unwrap();
+        let index = DumpIndex::open(dump, &index_file).unwrap();
+
+        // the first entity is right after the [ line in the first block, after the stream header
+        let first = index.position("Q0").unwrap().unwrap();
+        let block = ResumePosition {
+            bit_offset: 32,
+            level: 1,
+            out_offset: 0,
+        };
+        assert_eq!(
+            first,
+            EntityPosition {
+                block,
+                line_offset: 2
+            }
+        );
+
+        // offset of each line in the decompressed data
+        let offsets: Vec<u64> = lines
+            .iter()
+            .scan(0, |offset, line| {
+                let start = *offset;
+                *offset += line.len() as u64 + 1;
+                Some(start)
+            })
+            .collect();
+
+        let mut blocks = Vec::new();
+        for (line, offset) in lines.iter().zip(&offsets) {
+            let id = match entity_id(line) {
+                Some(id) => id,
+                None => continue,
+            };
+            let position = index.position(id).unwrap().unwrap();
+            assert_eq!(position.block.level, 1);
+            assert_eq!(position.block.out_offset + position.line_offset, *offset);
+            if blocks.last() != Some(&position.block.out_offset) {
+                blocks.push(position.block.out_offset);
+            }
+        }
+        assert!(blocks.len() > 3, "only {} blocks", blocks.len());
+
+        // lines that continue in the next block, along with some others
+        let spanning: Vec<_> = lines
+            .iter()
+            .zip(&offsets)
+            .filter(|(line, &offset)| {
+                let end = offset + line.len() as u64;
+                blocks.iter().any(|&block| block > offset && block <= end)
+            })
+            .map(|(line, _)| line)
+            .collect();
+        assert!(!spanning.is_empty());
+        let others = lines[1..3]
+            .iter()
+            .chain(&lines[lines.len() - 3..lines.len() - 1]);
+
+        for line in spanning.into_iter().chain(others) {
+            let id = entity_id(line).unwrap();
+            assert_eq!(index.entity(id).unwrap().as_ref(), Some(line));
+        }
+        assert_eq!(index.entity("Q20000").unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,22 @@
+PRAGMA synchronous = OFF;
+PRAGMA journal_mode = MEMORY;
+
+drop table if exists index_info;
+drop table if exists entity_positions;
+
+-- the dump the index belongs to
+create table index_info (
+    id integer not null primary key check (id = 0),
+    dump string not null,
+    etag string,
+    complete boolean not null
+);
+
+-- start of the bzip2 block containing each entity, and the offset of its line in the block
+create table entity_positions (
+    id string not null primary key,
+    bit_offset integer not null,
+    level integer not null,
+    block_offset integer not null,
+    line_offset integer not null
+) without rowid;
@@ -0,0 +1,185 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Writes the compressed bytes of a download to `<path>.part`, and moves the file to `<path>`
+/// once the download is complete.
+///
+/// The etag of the download is kept in `<path>.part.etag` so that an interrupted download can be
+/// continued later.
+pub struct CacheFile {
+    path: PathBuf,
+    part_path: PathBuf,
+    etag_path: PathBuf,
+    etag: String,
+    file: BufWriter<File>,
+    len: u64,
+}
+
+impl CacheFile {
+    /// Returns the path of the partial file for the given cache path.
+    pub fn part_path(path: &Path) -> PathBuf {
+        with_suffix(path, ".part")
+    }
+
+    /// Opens the cache file for a download with the given etag, starting at the given offset.
+    ///
+    /// If the offset is not zero, the partial file from an earlier download must exist, belong
+    /// to the same etag, and contain at least `offset` bytes.
+    pub fn open(path: &Path, etag: &str, offset: u64) -> io::Result<Self> {
+        let part_path = Self::part_path(path);
+        let etag_path = with_suffix(path, ".part.etag");
+
+        let file = if offset == 0 {
+            fs::write(&etag_path, etag)?;
+            File::create(&part_path)?
+        } else {
+            if fs::read_to_string(&etag_path)? != etag {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "partial file belongs to a different version of the dump",
+                ));
+            }
+            let mut file = OpenOptions::new().write(true).open(&part_path)?;
+            if file.metadata()?.len() < offset {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "partial file is missing data",
+                ));
+            }
+            file.set_len(offset)?;
+            file.seek(SeekFrom::End(0))?;
+            file
+        };
+
+        Ok(CacheFile {
+            path: path.into(),
+            part_path,
+            etag_path,
+            etag: etag.into(),
+            file: BufWriter::new(file),
+            len: offset,
+        })
+    }
+
+    /// Changes the etag of the download, e.g. when switching to a mirror.
+    pub fn set_etag(&mut self, etag: &str) -> io::Result<()> {
+        fs::write(&self.etag_path, etag)?;
+        self.etag = etag.into();
+        Ok(())
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    /// Marks the file as complete if the etag and length of the download match. Returns false if
+    /// they don't, in which case the partial file is kept.
+    pub fn finish(mut self, etag: &str, len: Option<u64>) -> io::Result<bool> {
+        self.file.flush()?;
+
+        if etag != self.etag || len != Some(self.len) {
+            warn!(
+                "not marking {} as complete: wrote {} bytes with etag {:?}, but the dump has {:?} bytes with etag {:?}",
+                self.path.display(),
+                self.len,
+                self.etag,
+                len,
+                etag
+            );
+            return Ok(false);
+        }
+
+        fs::rename(&self.part_path, &self.path)?;
+        fs::remove_file(&self.etag_path)?;
+        Ok(true)
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an empty directory for the given test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("geo-db-cache-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn completes_only_matching_downloads() {
+        let dir = test_dir("complete");
+        let path = dir.join("dump.json.bz2");
+        let etag_path = with_suffix(&path, ".part.etag");
+
+        let mut cache = CacheFile::open(&path, "a", 0).unwrap();
+        cache.write(b"0123").unwrap();
+        cache.write(b"4567").unwrap();
+        assert!(!cache.finish("b", Some(8)).unwrap());
+        assert!(!path.exists());
+
+        // the sidecar is still there, so the download can be continued
+        assert_eq!(fs::read_to_string(&etag_path).unwrap(), "a");
+        let mut cache = CacheFile::open(&path, "a", 6).unwrap();
+        cache.write(b"67").unwrap();
+        assert!(!cache.finish("a", Some(9)).unwrap());
+        assert!(!path.exists());
+
+        let cache = CacheFile::open(&path, "a", 8).unwrap();
+        assert!(!cache.finish("a", None).unwrap());
+        let cache = CacheFile::open(&path, "a", 8).unwrap();
+        assert!(cache.finish("a", Some(8)).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"01234567");
+        assert!(!CacheFile::part_path(&path).exists());
+        assert!(!etag_path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_stale_partial_files() {
+        let dir = test_dir("stale");
+        let path = dir.join("dump.json.bz2");
+        let part_path = CacheFile::part_path(&path);
+
+        let mut cache = CacheFile::open(&path, "a", 0).unwrap();
+        cache.write(b"0123").unwrap();
+        drop(cache);
+
+        let err = CacheFile::open(&path, "b", 2).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = CacheFile::open(&path, "a", 5).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(&part_path).unwrap(), b"0123");
+
+        // starting over discards the partial file
+        let mut cache = CacheFile::open(&path, "b", 0).unwrap();
+        assert_eq!(fs::read(&part_path).unwrap(), b"");
+        cache.write(b"ab").unwrap();
+        assert!(cache.finish("b", Some(2)).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"ab");
+
+        // switching to a mirror keeps the data
+        let mut cache = CacheFile::open(&path, "a", 0).unwrap();
+        cache.write(b"01").unwrap();
+        cache.set_etag("c").unwrap();
+        cache.write(b"23").unwrap();
+        assert!(!cache.finish("a", Some(4)).unwrap());
+        let cache = CacheFile::open(&path, "c", 4).unwrap();
+        assert!(cache.finish("c", Some(4)).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"0123");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
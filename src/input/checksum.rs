@@ -0,0 +1,277 @@
+use crate::input::http::USER_AGENT;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use std::fs;
+use std::io::{self, Read};
+use thiserror::Error;
+
+/// Hash algorithms used in Wikimedia checksum files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashKind {
+    Md5,
+    Sha1,
+}
+
+/// A hash that is computed over the compressed data while it is read.
+pub enum RollingHash {
+    Md5(Md5),
+    Sha1(Sha1),
+}
+
+impl RollingHash {
+    pub fn new(kind: HashKind) -> Self {
+        match kind {
+            HashKind::Md5 => RollingHash::Md5(Md5::new()),
+            HashKind::Sha1 => RollingHash::Sha1(Sha1::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            RollingHash::Md5(hash) => hash.update(data),
+            RollingHash::Sha1(hash) => hash.update(data),
+        }
+    }
+
+    /// Updates the hash with all data from the given reader. Returns the number of bytes read.
+    pub fn update_from<R: Read>(&mut self, mut r: R) -> io::Result<u64> {
+        let mut buf = [0; 65536];
+        let mut total = 0;
+        loop {
+            let read = r.read(&mut buf)?;
+            if read == 0 {
+                break Ok(total);
+            }
+            self.update(&buf[..read]);
+            total += read as u64;
+        }
+    }
+
+    /// Returns the hash as lowercase hex.
+    pub fn finish(self) -> String {
+        match self {
+            RollingHash::Md5(hash) => format!("{:x}", hash.finalize()),
+            RollingHash::Sha1(hash) => format!("{:x}", hash.finalize()),
+        }
+    }
+}
+
+/// The checksum of a dump, as published by Wikimedia.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedChecksum {
+    pub kind: HashKind,
+    /// Lowercase hex.
+    pub hex: String,
+}
+
+impl ExpectedChecksum {
+    /// Loads the checksum of the dump with the given file name from a checksum file at a path or
+    /// URL.
+    pub fn load(location: &str, file_name: &str) -> Result<Self, ChecksumError> {
+        let contents = if location.starts_with("http://") || location.starts_with("https://") {
+            reqwest::blocking::Client::builder()
+                .user_agent(USER_AGENT)
+                .build()?
+                .get(location)
+                .send()?
+                .error_for_status()?
+                .text()?
+        } else {
+            fs::read_to_string(location)?
+        };
+
+        Self::parse(&contents, file_name)
+            .ok_or_else(|| ChecksumError::NotFound(file_name.into(), location.into()))
+    }
+
+    /// Parses checksum files in the format of `md5sum`/`sha1sum`, i.e. lines of a hash and a file
+    /// name. A file that only contains a hash is also accepted.
+    fn parse(contents: &str, file_name: &str) -> Option<Self> {
+        let mut entries = contents.lines().filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let hash = parts.next()?;
+            let name = parts.next().map(|name| name.trim_start_matches('*'));
+            let kind = match hash.len() {
+                32 => HashKind::Md5,
+                40 => HashKind::Sha1,
+                _ => return None,
+            };
+            if !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            let checksum = ExpectedChecksum {
+                kind,
+                hex: hash.to_ascii_lowercase(),
+            };
+            Some((name, checksum))
+        });
+
+        let first = entries.next()?;
+        if first.0.is_none() {
+            // just a hash
+            return Some(first.1);
+        }
+        std::iter::once(first)
+            .chain(entries)
+            .find(|(name, _)| *name == Some(file_name))
+            .map(|(_, checksum)| checksum)
+    }
+}
+
+/// Returns the file name of a path or URL.
+pub fn file_name(location: &str) -> &str {
+    let location = location.split(&['?', '#'][..]).next().unwrap_or(location);
+    location.rsplit(&['/', '\\'][..]).next().unwrap_or(location)
+}
+
+/// Returns the name under which a dump is listed in the checksum file at `location`.
+///
+/// Checksum files are only published in the dated directories, which don't list `latest-*` names,
+/// so e.g. `latest-all.json.bz2` is listed as `wikidata-20210101-all.json.bz2` in
+/// `20210101/wikidata-20210101-md5sums.txt`. Since dated dumps are sometimes named differently
+/// (`truthy-BETA.nt.bz2`), `latest_name` is replaced with `dated_name` (without the prefix).
+pub fn listed_name(
+    source: &str,
+    location: &str,
+    dump_name: &str,
+    latest_name: &str,
+    dated_name: &str,
+) -> String {
+    let name = file_name(source);
+    let (latest, date) = match (name.strip_prefix("latest-"), date_in(location)) {
+        (Some(latest), Some(date)) => (latest, date),
+        _ => return name.into(),
+    };
+    let dated = if latest == latest_name {
+        dated_name
+    } else {
+        latest
+    };
+    format!("{}-{}-{}", dump_name, date, dated)
+}
+
+/// Returns the date (YYYYMMDD) of the dated directory a path or URL is in.
+fn date_in(location: &str) -> Option<&str> {
+    let location = location.split(&['?', '#'][..]).next().unwrap_or(location);
+    location
+        .rsplit(&['/', '\\'][..])
+        .skip(1)
+        .find(|segment| segment.len() == 8 && segment.chars().all(|c| c.is_ascii_digit()))
+}
+
+#[derive(Debug, Error)]
+pub enum ChecksumError {
+    #[error("no checksum for {0} in {1}")]
+    NotFound(String, String),
+    #[error("request error: {0}")]
+    Req(#[from] reqwest::Error),
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MD5SUMS: &str = "\
+0123456789abcdef0123456789abcdef  wikidata-20210101-all.json.bz2
+11111111111111111111111111111111  wikidata-20210101-all.json.gz
+22222222222222222222222222222222  wikidata-20210101-truthy-BETA.nt.bz2
+";
+
+    #[test]
+    fn parses_checksum_files() {
+        let md5 = ExpectedChecksum::parse(MD5SUMS, "wikidata-20210101-all.json.gz").unwrap();
+        assert_eq!(md5.kind, HashKind::Md5);
+        assert_eq!(md5.hex, "11111111111111111111111111111111");
+        assert_eq!(ExpectedChecksum::parse(MD5SUMS, "latest-all.json.gz"), None);
+        assert_eq!(ExpectedChecksum::parse(MD5SUMS, "all.json.gz"), None);
+
+        // binary mode entries, upper case hex and lines that aren't checksums
+        let sha1sums = "\
+sha1sums of the dumps
+
+ABCDEF0123456789ABCDEF0123456789ABCDEF01 *wikidata-20210101-all.json.bz2
+";
+        let sha1 = ExpectedChecksum::parse(sha1sums, "wikidata-20210101-all.json.bz2").unwrap();
+        assert_eq!(sha1.kind, HashKind::Sha1);
+        assert_eq!(sha1.hex, "abcdef0123456789abcdef0123456789abcdef01");
+
+        // files with just a hash are used for any dump
+        let hash_only = "0123456789ABCDEF0123456789ABCDEF\n";
+        let checksum = ExpectedChecksum::parse(hash_only, "latest-all.json.bz2").unwrap();
+        assert_eq!(checksum.kind, HashKind::Md5);
+        assert_eq!(checksum.hex, "0123456789abcdef0123456789abcdef");
+
+        // neither md5 nor sha1
+        assert_eq!(ExpectedChecksum::parse("0123456789abcdef\n", "dump"), None);
+        assert_eq!(
+            ExpectedChecksum::parse("0123456789abcdef0123456789abcdeg  dump\n", "dump"),
+            None
+        );
+    }
+
+    #[test]
+    fn hashes_data() {
+        let hash = |kind: HashKind| {
+            let mut hash = RollingHash::new(kind);
+            hash.update(b"geo");
+            hash.update_from(&b"-db"[..]).unwrap();
+            hash.finish()
+        };
+        assert_eq!(hash(HashKind::Md5), "0a18c83115e99685d9d47c6d3fad021c");
+        assert_eq!(
+            hash(HashKind::Sha1),
+            "f6be7c2cfb4d958fab8210ae46ee0b65afe2c0a2"
+        );
+    }
+
+    #[test]
+    fn finds_latest_dumps_under_their_dated_names() {
+        let location = "https://dumps.wikimedia.org/wikidatawiki/entities/20210101/wikidata-20210101-md5sums.txt";
+        let latest = |name: &str, latest_name: &str, dated_name: &str| {
+            let source = format!("https://dumps.wikimedia.org/wikidatawiki/entities/{}", name);
+            let name = listed_name(&source, location, "wikidata", latest_name, dated_name);
+            ExpectedChecksum::parse(MD5SUMS, &name).map(|checksum| checksum.hex)
+        };
+
+        assert_eq!(
+            latest("latest-all.json.bz2", "all.json.bz2", "all.json.bz2"),
+            Some("0123456789abcdef0123456789abcdef".into())
+        );
+        assert_eq!(
+            latest(
+                "latest-truthy.nt.bz2",
+                "truthy.nt.bz2",
+                "truthy-BETA.nt.bz2"
+            ),
+            Some("22222222222222222222222222222222".into())
+        );
+        assert_eq!(
+            latest("latest-all.json.gz", "all.json.bz2", "all.json.bz2"),
+            Some("11111111111111111111111111111111".into())
+        );
+
+        // without a dated directory, the name is looked up as it is
+        assert_eq!(
+            listed_name(
+                "latest-all.json.bz2",
+                "md5sums.txt",
+                "wikidata",
+                "all.json.bz2",
+                "all.json.bz2"
+            ),
+            "latest-all.json.bz2"
+        );
+        assert_eq!(
+            listed_name(
+                "/dumps/wikidata-20210101-all.json.bz2",
+                location,
+                "wikidata",
+                "all.json.bz2",
+                "all.json.bz2"
+            ),
+            "wikidata-20210101-all.json.bz2"
+        );
+    }
+}
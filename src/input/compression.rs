@@ -1,13 +1,79 @@
+use crate::input::par_bz2::ParBzDecoder;
+use crate::input::ResumePosition;
 use bzip2::read::BzDecoder;
-use bzip2_rs::decoder::ParallelDecoder;
-use bzip2_rs::RayonThreadPool;
-use std::io::{self, Read};
+use flate2::read::MultiGzDecoder;
+use std::io::{self, BufReader, Read};
 
 pub trait DecompressingReader<R>: Read {
     fn new(r: R) -> Self;
     fn inner(&self) -> &R;
     fn inner_mut(&mut self) -> &mut R;
     fn total_in(&self) -> u64;
+
+    /// Creates a new reader, using the given compression format if the reader supports several.
+    fn with_hint(r: R, _hint: Option<Compression>) -> Self
+    where
+        Self: Sized,
+    {
+        Self::new(r)
+    }
+
+    /// Creates a reader that continues at a position previously returned by `resume_position`.
+    /// The inner reader must start at `position.byte_offset()`.
+    fn resume(_r: R, _position: &ResumePosition) -> io::Result<Self>
+    where
+        Self: Sized,
+    {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this format does not support resuming",
+        ))
+    }
+
+    /// See `DataInput::resume_position`.
+    fn resume_position(&self, _out_offset: u64) -> Option<ResumePosition> {
+        None
+    }
+}
+
+/// Compression formats a dump may be stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Bzip2,
+    Gzip,
+    Zstd,
+    Plain,
+}
+
+/// Number of bytes needed to recognize any of the supported formats.
+const MAGIC_LEN: usize = 4;
+
+impl Compression {
+    /// Guesses the compression format from a file name or URL.
+    pub fn from_extension(name: &str) -> Option<Self> {
+        let name = name.split(&['?', '#'][..]).next().unwrap_or(name);
+        let ext = name.rsplit('.').next()?;
+        match ext {
+            "bz2" => Some(Compression::Bzip2),
+            "gz" => Some(Compression::Gzip),
+            "zst" | "zstd" => Some(Compression::Zstd),
+            "json" | "nt" | "txt" => Some(Compression::Plain),
+            _ => None,
+        }
+    }
+
+    /// Detects the compression format from the first few bytes of the data.
+    pub fn from_magic(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
 }
 
 impl<R> DecompressingReader<R> for BzDecoder<R>
@@ -28,59 +94,294 @@ where
     }
 }
 
-/// Higher throughput Bzip2 decoder but it seems to occasionally decode things incorrectly?
-pub struct ParBzDecoder<R> {
-    decoder: ParallelDecoder<RayonThreadPool>,
+/// Counts the bytes read from the inner reader, and replays the magic bytes that were read from
+/// it to detect the compression format.
+///
+/// Since the count is taken directly at the source, it is also the correct offset to resume
+/// reading from, regardless of how much the decoder has buffered.
+pub struct SniffReader<R> {
     inner: R,
+    magic: [u8; MAGIC_LEN],
+    magic_len: usize,
+    magic_pos: usize,
+    /// Offset in the data up to which the inner reader has been read.
     total_in: u64,
 }
-impl<R> DecompressingReader<R> for ParBzDecoder<R>
+
+impl<R> SniffReader<R>
+where
+    R: Read,
+{
+    fn new(inner: R) -> Self {
+        Self::at_offset(inner, 0)
+    }
+
+    /// Creates a reader whose inner reader starts at the given offset of the data.
+    fn at_offset(inner: R, offset: u64) -> Self {
+        SniffReader {
+            inner,
+            magic: [0; MAGIC_LEN],
+            magic_len: 0,
+            magic_pos: 0,
+            total_in: offset,
+        }
+    }
+
+    /// Reads the magic bytes from the inner reader. They will be returned again by `read`.
+    fn sniff(&mut self) -> io::Result<&[u8]> {
+        while self.magic_len < MAGIC_LEN {
+            let read = self.inner.read(&mut self.magic[self.magic_len..])?;
+            if read == 0 {
+                break;
+            }
+            self.magic_len += read;
+            self.total_in += read as u64;
+        }
+        Ok(&self.magic[..self.magic_len])
+    }
+}
+
+impl<R> Read for SniffReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.magic_pos < self.magic_len {
+            let len = (self.magic_len - self.magic_pos).min(buf.len());
+            buf[..len].copy_from_slice(&self.magic[self.magic_pos..self.magic_pos + len]);
+            self.magic_pos += len;
+            return Ok(len);
+        }
+        let read = self.inner.read(buf)?;
+        self.total_in += read as u64;
+        Ok(read)
+    }
+}
+
+/// Decoder that detects the compression format when it is first read from.
+///
+/// The format is taken from the magic bytes if they are recognized, or from the hint (usually
+/// derived from the file extension) otherwise. Data that is neither is read as plain text.
+pub struct AutoDecoder<R: Read> {
+    hint: Option<Compression>,
+    state: AutoDecoderState<R>,
+}
+
+enum AutoDecoderState<R: Read> {
+    Sniffing(SniffReader<R>),
+    Bzip2(ParBzDecoder<SniffReader<R>>),
+    Gzip(MultiGzDecoder<SniffReader<R>>),
+    Zstd(zstd::Decoder<'static, BufReader<SniffReader<R>>>),
+    Plain(SniffReader<R>),
+    /// Only exists while switching states.
+    Poisoned,
+}
+
+impl<R> AutoDecoder<R>
+where
+    R: Read,
+{
+    fn sniff(&mut self) -> io::Result<()> {
+        let mut reader = match std::mem::replace(&mut self.state, AutoDecoderState::Poisoned) {
+            AutoDecoderState::Sniffing(reader) => reader,
+            _ => unreachable!("already sniffed"),
+        };
+
+        let compression = match reader.sniff() {
+            Ok(magic) => Compression::from_magic(magic)
+                .or(self.hint)
+                .unwrap_or(Compression::Plain),
+            Err(err) => {
+                self.state = AutoDecoderState::Sniffing(reader);
+                return Err(err);
+            }
+        };
+        debug!("reading data as {:?}", compression);
+
+        self.state = match compression {
+            Compression::Bzip2 => AutoDecoderState::Bzip2(ParBzDecoder::new(reader)),
+            Compression::Gzip => AutoDecoderState::Gzip(MultiGzDecoder::new(reader)),
+            Compression::Zstd => {
+                let mut decoder = zstd::Decoder::new(reader)?;
+                // allow files that were compressed with --long
+                decoder.window_log_max(31)?;
+                AutoDecoderState::Zstd(decoder)
+            }
+            Compression::Plain => AutoDecoderState::Plain(reader),
+        };
+        Ok(())
+    }
+
+    fn sniff_reader(&self) -> &SniffReader<R> {
+        match &self.state {
+            AutoDecoderState::Sniffing(reader) => reader,
+            AutoDecoderState::Bzip2(decoder) => decoder.inner(),
+            AutoDecoderState::Gzip(decoder) => decoder.get_ref(),
+            AutoDecoderState::Zstd(decoder) => decoder.get_ref().get_ref(),
+            AutoDecoderState::Plain(reader) => reader,
+            AutoDecoderState::Poisoned => unreachable!("decoder is poisoned"),
+        }
+    }
+
+    fn sniff_reader_mut(&mut self) -> &mut SniffReader<R> {
+        match &mut self.state {
+            AutoDecoderState::Sniffing(reader) => reader,
+            AutoDecoderState::Bzip2(decoder) => decoder.inner_mut(),
+            AutoDecoderState::Gzip(decoder) => decoder.get_mut(),
+            AutoDecoderState::Zstd(decoder) => decoder.get_mut().get_mut(),
+            AutoDecoderState::Plain(reader) => reader,
+            AutoDecoderState::Poisoned => unreachable!("decoder is poisoned"),
+        }
+    }
+}
+
+impl<R> DecompressingReader<R> for AutoDecoder<R>
 where
     R: Read,
 {
     fn new(r: R) -> Self {
-        Self {
-            decoder: ParallelDecoder::new(RayonThreadPool, 99999999999),
-            inner: r,
-            total_in: 0,
+        Self::with_hint(r, None)
+    }
+    fn with_hint(r: R, hint: Option<Compression>) -> Self {
+        AutoDecoder {
+            hint,
+            state: AutoDecoderState::Sniffing(SniffReader::new(r)),
         }
     }
+    fn resume(r: R, position: &ResumePosition) -> io::Result<Self> {
+        // only bzip2 supports resuming
+        let reader = SniffReader::at_offset(r, position.byte_offset());
+        Ok(AutoDecoder {
+            hint: Some(Compression::Bzip2),
+            state: AutoDecoderState::Bzip2(ParBzDecoder::resume(reader, position)?),
+        })
+    }
     fn inner(&self) -> &R {
-        &self.inner
+        &self.sniff_reader().inner
     }
     fn inner_mut(&mut self) -> &mut R {
-        &mut self.inner
+        &mut self.sniff_reader_mut().inner
     }
     fn total_in(&self) -> u64 {
-        self.total_in
+        self.sniff_reader().total_in
+    }
+    fn resume_position(&self, out_offset: u64) -> Option<ResumePosition> {
+        match &self.state {
+            AutoDecoderState::Bzip2(decoder) => decoder.resume_position(out_offset),
+            _ => None,
+        }
     }
 }
-impl<R> Read for ParBzDecoder<R>
+
+impl<R> Read for AutoDecoder<R>
 where
     R: Read,
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut read_zero = false;
-        let mut read_buf = [0; 16384];
-
-        loop {
-            match self.decoder.read(buf)? {
-                bzip2_rs::decoder::ReadState::NeedsWrite(space) => {
-                    let read = self.inner.read(&mut read_buf[..space.min(16384)])?;
-                    if read_zero {
-                        // FIXME: what is this exactly?
-                        return Err(io::Error::new(
-                            io::ErrorKind::UnexpectedEof,
-                            "unexpected EOF while reading compressed data",
-                        ));
-                    }
-                    self.total_in += read as u64;
-                    read_zero = read == 0;
-                    self.decoder.write(&read_buf[..read])?;
-                }
-                bzip2_rs::decoder::ReadState::Read(n) => return Ok(n),
-                bzip2_rs::decoder::ReadState::Eof => return Ok(0),
+        if let AutoDecoderState::Sniffing(_) = self.state {
+            self.sniff()?;
+        }
+
+        match &mut self.state {
+            AutoDecoderState::Bzip2(decoder) => decoder.read(buf),
+            AutoDecoderState::Gzip(decoder) => decoder.read(buf),
+            AutoDecoderState::Zstd(decoder) => decoder.read(buf),
+            AutoDecoderState::Plain(reader) => reader.read(buf),
+            AutoDecoderState::Sniffing(_) | AutoDecoderState::Poisoned => {
+                Err(io::Error::other("decoder failed to initialize"))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    const LINES: &[u8] = b"{\"id\":\"Q1\"}\n{\"id\":\"Q2\"}\n{\"id\":\"Q3\"}\n";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn bzip2(data: &[u8]) -> Vec<u8> {
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Returns one byte per read, so that sniffing has to be done in several reads.
+    struct OneByte<'a>(&'a [u8]);
+
+    impl Read for OneByte<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    /// Decodes the data and checks that all of it was counted as read.
+    fn decode<R: Read>(mut decoder: AutoDecoder<R>, compressed_len: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).unwrap();
+        assert_eq!(decoder.total_in(), compressed_len as u64);
+        out
+    }
+
+    #[test]
+    fn detects_formats() {
+        let multi_gzip = [gzip(&LINES[..12]), gzip(&LINES[12..])].concat();
+        let zstd = zstd::encode_all(LINES, 0).unwrap();
+        let cases = [
+            ("gzip", gzip(LINES)),
+            ("multi-member gzip", multi_gzip),
+            ("zstd", zstd),
+            ("bzip2", bzip2(LINES)),
+            ("plain", LINES.to_vec()),
+        ];
+
+        for (name, data) in &cases {
+            let decoder = AutoDecoder::new(&data[..]);
+            assert_eq!(decode(decoder, data.len()), LINES, "{}", name);
+            let decoder = AutoDecoder::new(OneByte(data));
+            assert_eq!(decode(decoder, data.len()), LINES, "{} read bytewise", name);
+        }
+    }
+
+    #[test]
+    fn prefers_magic_bytes_over_hint() {
+        let cases = [
+            (gzip(LINES), Compression::Zstd),
+            (zstd::encode_all(LINES, 0).unwrap(), Compression::Gzip),
+            (bzip2(LINES), Compression::Plain),
+        ];
+
+        for (data, hint) in &cases {
+            let decoder = AutoDecoder::with_hint(&data[..], Some(*hint));
+            assert_eq!(decode(decoder, data.len()), LINES, "hint {:?}", hint);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_hint() {
+        // shorter than any magic
+        let decoder = AutoDecoder::with_hint(&b"{}"[..], Some(Compression::Plain));
+        assert_eq!(decode(decoder, 2), b"{}");
+        let decoder = AutoDecoder::new(&b""[..]);
+        assert_eq!(decode(decoder, 0), b"");
+
+        // unrecognized data is decoded with the hinted format
+        let mut decoder = AutoDecoder::with_hint(&b"{}"[..], Some(Compression::Gzip));
+        assert!(decoder.read_to_end(&mut Vec::new()).is_err());
+        let mut decoder = AutoDecoder::with_hint(LINES, Some(Compression::Zstd));
+        assert!(decoder.read_to_end(&mut Vec::new()).is_err());
+    }
+}
@@ -1,36 +1,117 @@
-use crate::input::compression::{DecompressingReader, ParBzDecoder};
-use crate::input::DataInput;
+use crate::input::checksum::RollingHash;
+use crate::input::compression::{AutoDecoder, Compression, DecompressingReader};
+use crate::input::par_bz2::ParBzDecoder;
+use crate::input::{DataInput, ResumePosition};
 use bzip2::read::BzDecoder;
-use std::{fs, io};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::UNIX_EPOCH;
+use std::{fs, path::Path};
+
+/// A local source of compressed data.
+pub struct LocalSource {
+    reader: LocalReader,
+    /// Hash of the data read so far, if it is being verified.
+    hash: Option<RollingHash>,
+}
+
+enum LocalReader {
+    File(fs::File),
+    Stdin(io::Stdin),
+}
+
+impl LocalSource {
+    fn new(reader: LocalReader) -> Self {
+        LocalSource { reader, hash: None }
+    }
+}
+
+impl Read for LocalSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match &mut self.reader {
+            LocalReader::File(file) => file.read(buf)?,
+            LocalReader::Stdin(stdin) => stdin.read(buf)?,
+        };
+        if let Some(hash) = &mut self.hash {
+            hash.update(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
 
 pub struct FileInput<B> {
     read: B,
-    size: u64,
+    size: Option<u64>,
+    /// File size and modification time, to check that the file is unchanged when resuming.
+    etag: Option<String>,
 }
 
+pub type AutoFileInput = FileInput<AutoDecoder<LocalSource>>;
 #[allow(dead_code)]
-pub type Bz2FileInput = FileInput<BzDecoder<fs::File>>;
+pub type Bz2FileInput = FileInput<BzDecoder<LocalSource>>;
 #[allow(dead_code)]
-pub type ParBz2FileInput = FileInput<ParBzDecoder<fs::File>>;
+pub type ParBz2FileInput = FileInput<ParBzDecoder<LocalSource>>;
 
 impl<B> FileInput<B>
 where
-    B: DecompressingReader<fs::File>,
+    B: DecompressingReader<LocalSource>,
 {
     #[allow(dead_code)]
-    pub fn new(file: fs::File) -> Self {
-        let size = file.metadata().unwrap().len();
+    pub fn new(file: fs::File) -> io::Result<Self> {
+        Self::with_hint(file, None)
+    }
 
+    fn with_hint(file: fs::File, hint: Option<Compression>) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+
+        Ok(FileInput {
+            read: B::with_hint(LocalSource::new(LocalReader::File(file)), hint),
+            size: Some(metadata.len()),
+            etag: file_etag(&metadata),
+        })
+    }
+
+    /// Reads from stdin. The content length is unknown in this case.
+    pub fn stdin() -> Self {
         FileInput {
-            read: B::new(file),
-            size,
+            read: B::new(LocalSource::new(LocalReader::Stdin(io::stdin()))),
+            size: None,
+            etag: None,
+        }
+    }
+
+    /// Opens the file at the given path and continues reading at the given position.
+    pub fn resume<P: AsRef<Path>>(path: P, position: &ResumePosition) -> io::Result<Self> {
+        let mut file = fs::File::open(path)?;
+        let metadata = file.metadata()?;
+        file.seek(SeekFrom::Start(position.byte_offset()))?;
+
+        Ok(FileInput {
+            read: B::resume(LocalSource::new(LocalReader::File(file)), position)?,
+            size: Some(metadata.len()),
+            etag: file_etag(&metadata),
+        })
+    }
+
+    /// Opens the file at the given path, or stdin if the path is `-`.
+    ///
+    /// The file extension is used as a hint for the compression format.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path == Path::new("-") {
+            Ok(Self::stdin())
+        } else {
+            let hint = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(Compression::from_extension);
+            Self::with_hint(fs::File::open(path)?, hint)
         }
     }
 }
 
 impl<B> DataInput for FileInput<B>
 where
-    B: DecompressingReader<fs::File>,
+    B: DecompressingReader<LocalSource>,
 {
     type Error = io::Error;
 
@@ -43,6 +124,27 @@ where
     }
 
     fn content_length(&self) -> Option<u64> {
-        Some(self.size)
+        self.size
+    }
+
+    fn resume_position(&self, out_offset: u64) -> Option<ResumePosition> {
+        self.read.resume_position(out_offset)
     }
+
+    fn etag(&self) -> Option<String> {
+        self.etag.clone()
+    }
+
+    fn set_hash(&mut self, hash: RollingHash) {
+        self.read.inner_mut().hash = Some(hash);
+    }
+
+    fn take_hash(&mut self) -> Option<RollingHash> {
+        self.read.inner_mut().hash.take()
+    }
+}
+
+fn file_etag(metadata: &fs::Metadata) -> Option<String> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!("{}-{}", metadata.len(), modified.as_secs()))
 }
@@ -1,50 +1,197 @@
-use crate::input::compression::{DecompressingReader, ParBzDecoder};
-use crate::input::DataInput;
+use crate::input::cache::CacheFile;
+use crate::input::checksum::RollingHash;
+use crate::input::compression::{AutoDecoder, Compression, DecompressingReader};
+use crate::input::par_bz2::ParBzDecoder;
+use crate::input::{DataInput, ResumePosition};
 use bzip2::read::BzDecoder;
 use reqwest::blocking::Response;
 use reqwest::header::{self, HeaderMap, HeaderValue};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::io::{self, Read};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 pub const USER_AGENT: &str = "AKSO geo-db (+https://akso.org)";
-const MAX_OPEN_TRIES: usize = 32;
-const OPEN_RETRY_INTERVAL_SECS: u64 = 8;
+/// Number of times opening a URL may fail before failing over to the next mirror.
+const OPEN_TRIES_PER_URL: usize = 3;
+
+/// How to retry when opening a connection fails.
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    /// Time to wait after the first failure.
+    pub initial_interval: Duration,
+    /// Maximum time to wait between tries.
+    pub max_interval: Duration,
+    /// Factor the interval grows by after each failure.
+    pub multiplier: f64,
+    /// Fraction by which each interval is randomly shortened or lengthened.
+    pub jitter: f64,
+    /// Time after which to give up.
+    pub budget: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            initial_interval: Duration::from_secs(2),
+            max_interval: Duration::from_secs(300),
+            multiplier: 2.,
+            jitter: 0.25,
+            budget: Duration::from_secs(1800),
+        }
+    }
+}
+
+impl RetryConfig {
+    fn jittered(&self, interval: Duration) -> Duration {
+        // a random number in 0..1, so we don't need a dependency just for this
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        interval.mul_f64(1. + self.jitter * (2. * random - 1.))
+    }
+}
 
 /// Reentrant HTTP data input. If interrupted, will attempt to re-establish connection and seek
 /// to the appropriate location.
+///
+/// If mirrors are given, the input fails over to them when a URL keeps failing. Since mirrors
+/// have different etags, only the length of the data is checked when switching, so the data
+/// should be checked against a checksum at the end.
 pub struct HttpDataInput<B> {
-    src_url: String,
+    /// The URL and its mirrors.
+    urls: Vec<String>,
+    /// Index of the URL currently in use.
+    url_index: usize,
+    retry: RetryConfig,
+    /// Compression format guessed from the URL.
+    compression: Option<Compression>,
+    /// Where to continue an earlier download, if resuming one.
+    resume: Option<ResumeFrom>,
+    /// Path to save the compressed data to.
+    cache_path: Option<PathBuf>,
+    /// Hash to pass on to the source once it exists.
+    hash: Option<RollingHash>,
     state: Option<HttpDataInputState<B>>,
 }
 
-pub type HttpBz2DataInput = HttpDataInput<BzDecoder<Response>>;
+pub type HttpAutoDataInput = HttpDataInput<AutoDecoder<HttpSource>>;
+#[allow(dead_code)]
+pub type HttpBz2DataInput = HttpDataInput<BzDecoder<HttpSource>>;
 #[allow(dead_code)]
-pub type HttpParBz2DataInput = HttpDataInput<ParBzDecoder<Response>>;
+pub type HttpParBz2DataInput = HttpDataInput<ParBzDecoder<HttpSource>>;
+
+/// The response body, which is also written to the cache file if there is one.
+pub struct HttpSource {
+    response: Response,
+    cache: Option<CacheFile>,
+    hash: Option<RollingHash>,
+}
+
+impl Read for HttpSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.response.read(buf)?;
+        if let Some(hash) = &mut self.hash {
+            hash.update(&buf[..read]);
+        }
+        if let Some(cache) = &mut self.cache {
+            if let Err(err) = cache.write(&buf[..read]) {
+                warn!("no longer saving the dump because writing failed: {}", err);
+                self.cache = None;
+            }
+        }
+        Ok(read)
+    }
+}
 
 /// HttpDataInput state. Exists during download.
 struct HttpDataInputState<B> {
     read: B,
 
-    /// The etag of the data.
+    /// The URL the data is currently read from.
+    url_index: usize,
+
+    /// The etag of the data at the current URL.
     /// Wikidata supplies etags, so we use this to check that we are still downloading the
     /// same file if the connection was interrupted.
     etag: String,
 
     // content length
     len: Option<u64>,
+
+    /// The date from the Last-Modified header of the first response (YYYYMMDD).
+    modified: Option<String>,
+}
+
+/// An earlier download to continue.
+struct ResumeFrom {
+    /// The etag of the data at the first URL, if it had one.
+    etag: Option<String>,
+    /// The length of the data, which is all that can be compared for mirrors.
+    len: Option<u64>,
+    position: ResumePosition,
+}
+
+/// What reopened data must match to be considered the same data.
+enum ExpectedData {
+    Etag(String),
+    /// When switching to a different URL, etags can't be compared.
+    Len(Option<u64>),
 }
 
 impl<B> HttpDataInput<B>
 where
-    B: DecompressingReader<Response>,
+    B: DecompressingReader<HttpSource>,
 {
     pub fn new(src_url: String) -> Self {
         HttpDataInput {
-            src_url,
+            compression: Compression::from_extension(&src_url),
+            urls: vec![src_url],
+            url_index: 0,
+            retry: RetryConfig::default(),
+            resume: None,
+            cache_path: None,
+            hash: None,
             state: None,
         }
     }
 
+    /// Saves the compressed data to the given path while reading. The file is only created once
+    /// all data was read; until then it has a `.part` suffix.
+    pub fn with_cache(mut self, path: PathBuf) -> Self {
+        self.cache_path = Some(path);
+        self
+    }
+
+    /// Adds mirrors to fail over to.
+    pub fn with_mirrors(mut self, mirrors: Vec<String>) -> Self {
+        self.urls.extend(mirrors);
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Continues an earlier download at the given position. Fails to open if the etag of the
+    /// data at `src_url` no longer matches, or, for mirrors or if there was no etag, if the length
+    /// of the data doesn't.
+    pub fn resume(
+        src_url: String,
+        etag: Option<String>,
+        len: Option<u64>,
+        position: ResumePosition,
+    ) -> Self {
+        let mut input = Self::new(src_url);
+        input.resume = Some(ResumeFrom {
+            etag,
+            len,
+            position,
+        });
+        input
+    }
+
     pub fn open(&mut self) -> Result<(), HttpError> {
         let client = reqwest::blocking::Client::builder()
             .user_agent(USER_AGENT)
@@ -52,20 +199,41 @@ where
 
         debug!("opening new connection");
 
+        let url_index = self.url_index;
+        let url = &self.urls[url_index];
+
+        // the offset we need to continue at and what the data must look like
+        let cursor = match (&self.state, &self.resume) {
+            (Some(state), _) if state.url_index == url_index => Some((
+                state.read.total_in(),
+                ExpectedData::Etag(state.etag.clone()),
+            )),
+            (Some(state), _) => Some((state.read.total_in(), ExpectedData::Len(state.len))),
+            (None, Some(resume)) => Some((
+                resume.position.byte_offset(),
+                match &resume.etag {
+                    // mirrors don't share etags
+                    Some(etag) if url_index == 0 => ExpectedData::Etag(etag.clone()),
+                    _ => ExpectedData::Len(resume.len),
+                },
+            )),
+            (None, None) => None,
+        };
+
         let mut headers = HeaderMap::new();
-        if let Some(state) = &self.state {
+        if let Some((offset, _)) = &cursor {
             debug!(
                 "setting HTTP range header because we already read some data (cursor: {})",
-                state.read.total_in()
+                offset
             );
             headers.append(
                 header::RANGE,
-                HeaderValue::from_str(&format!("bytes={}-", state.read.total_in()))
+                HeaderValue::from_str(&format!("bytes={}-", offset))
                     .expect("failed to create range header"),
             );
         }
 
-        let mut response = client.get(&self.src_url).headers(headers).send()?;
+        let mut response = client.get(url).headers(headers).send()?;
 
         if !response.status().is_success() {
             let status = response.status();
@@ -77,48 +245,48 @@ where
             .headers()
             .get(header::ETAG)
             .map(|s| s.to_str().unwrap_or(""))
-            .unwrap_or("");
-        let res_offset = if let Some(state) = &self.state {
-            // we sent a partial request, so we need to check the etag & range
-
-            if state.etag != etag {
-                return Err(HttpError::EtagMismatch);
-            }
+            .unwrap_or("")
+            .to_string();
+        let mut total_len = response.content_length();
+        let modified = response
+            .headers()
+            .get(header::LAST_MODIFIED)
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok())
+            .map(|date| date.format("%Y%m%d").to_string());
 
+        if let Some((offset, expected)) = &cursor {
+            // we sent a partial request, so we need to check the range & that the data is the same
             let mut res_offset: u64 = 0;
             if let Some(content_range) = response.headers().get(header::CONTENT_RANGE) {
                 let content_range = content_range
                     .to_str()
                     .map_err(|_| HttpError::UnexpectedContentRange)?;
-                let mut parts = content_range.split(" ");
-                if parts.next() != Some("bytes") {
-                    return Err(HttpError::UnexpectedContentRange);
+                let (start, total) = parse_content_range(content_range)?;
+                debug!("parsed content-range to get start offset {}", start);
+                res_offset = start;
+                total_len = total;
+            }
+
+            match expected {
+                ExpectedData::Etag(expected_etag) => {
+                    if *expected_etag != etag {
+                        return Err(HttpError::EtagMismatch);
+                    }
                 }
-                if let Some(range) = parts.next() {
-                    // remove /size part and split by -
-                    let mut range = range.split("/").next().unwrap().split("-");
-                    res_offset = range
-                        .next()
-                        .expect("first item should exist")
-                        .parse()
-                        .map_err(|_| HttpError::UnexpectedContentRange)?;
-                    debug!("parsed content-range to get start offset {}", res_offset);
-                } else {
-                    return Err(HttpError::UnexpectedContentRange);
+                ExpectedData::Len(len) => {
+                    if len.is_none() || total_len != *len {
+                        return Err(HttpError::LengthMismatch);
+                    }
                 }
             }
 
-            if res_offset > state.read.total_in() {
+            if res_offset > *offset {
                 return Err(HttpError::ContentRangeTooSmall);
             }
-            res_offset
-        } else {
-            0
-        };
 
-        if let Some(state) = &mut self.state {
             // seek until offset matches
-            let diff = (state.read.total_in() - res_offset) as usize;
+            let diff = (offset - res_offset) as usize;
             if diff > 0 {
                 let mut buf = [0; 1024];
                 for _ in 0..(diff / 1024) {
@@ -131,20 +299,52 @@ where
 
                 debug!(
                     "response seeked from offset {} to offset {}",
-                    res_offset,
-                    state.read.total_in()
+                    res_offset, offset
                 );
             }
+        }
 
-            *state.read.inner_mut() = response;
+        if let Some(state) = &mut self.state {
+            let source = state.read.inner_mut();
+            source.response = response;
+            if state.url_index != url_index {
+                info!("Continuing download from {}", url);
+                if let Some(cache) = &mut source.cache {
+                    if let Err(err) = cache.set_etag(&etag) {
+                        warn!("no longer saving the dump because writing failed: {}", err);
+                        source.cache = None;
+                    }
+                }
+                state.url_index = url_index;
+                state.etag = etag;
+            }
         } else {
-            let etag = etag.to_string();
-            let len = response.content_length();
             // no state exists; create
+            let cache = self.cache_path.as_ref().and_then(|path| {
+                let offset = cursor.as_ref().map_or(0, |(offset, _)| *offset);
+                match CacheFile::open(path, &etag, offset) {
+                    Ok(cache) => Some(cache),
+                    Err(err) => {
+                        warn!("not saving the dump to {}: {}", path.display(), err);
+                        None
+                    }
+                }
+            });
+            let source = HttpSource {
+                response,
+                cache,
+                hash: self.hash.take(),
+            };
+            let read = match &self.resume {
+                Some(resume) => B::resume(source, &resume.position)?,
+                None => B::with_hint(source, self.compression),
+            };
             self.state = Some(HttpDataInputState {
-                read: B::new(response),
+                read,
+                url_index,
                 etag,
-                len,
+                len: total_len,
+                modified,
             });
         }
 
@@ -152,25 +352,46 @@ where
     }
 
     fn try_open(&mut self) -> Result<(), HttpError> {
-        let mut try_count = 1;
+        let start = Instant::now();
+        let mut interval = self.retry.initial_interval;
+        let mut url_tries = 0;
         loop {
-            let is_last_try = try_count == MAX_OPEN_TRIES;
-
             match self.open() {
                 Ok(()) => break Ok(()),
                 Err(err) => {
-                    if is_last_try {
+                    let elapsed = start.elapsed();
+                    if elapsed >= self.retry.budget {
                         break Err(err);
-                    } else {
-                        std::thread::sleep(std::time::Duration::from_secs(
-                            OPEN_RETRY_INTERVAL_SECS,
-                        ));
-                        try_count += 1;
-                        debug!(
-                            "retrying connection because it failed (try {}/{}): {}",
-                            try_count, MAX_OPEN_TRIES, err
+                    }
+
+                    url_tries += 1;
+                    let data_changed =
+                        matches!(err, HttpError::EtagMismatch | HttpError::LengthMismatch);
+                    if self.urls.len() > 1 && (data_changed || url_tries >= OPEN_TRIES_PER_URL) {
+                        self.url_index = (self.url_index + 1) % self.urls.len();
+                        url_tries = 0;
+                        warn!(
+                            "failing over to {} because opening failed: {}",
+                            self.urls[self.url_index], err
                         );
+                    } else if data_changed {
+                        // there is no point in trying again
+                        break Err(err);
                     }
+
+                    let sleep = self
+                        .retry
+                        .jittered(interval)
+                        .min(self.retry.budget - elapsed);
+                    debug!(
+                        "retrying connection in {:.1}s because it failed: {}",
+                        sleep.as_secs_f64(),
+                        err
+                    );
+                    std::thread::sleep(sleep);
+                    interval = interval
+                        .mul_f64(self.retry.multiplier)
+                        .min(self.retry.max_interval);
                 }
             }
         }
@@ -179,19 +400,37 @@ where
     fn read_raw(&mut self, buf: &mut [u8]) -> Result<usize, HttpError> {
         match &mut self.state {
             Some(state) => Ok(state.read.read(buf)?),
-            None => return Err(HttpError::NoConnection),
+            None => Err(HttpError::NoConnection),
+        }
+    }
+
+    /// Completes the cache file once all data was read.
+    fn finish_cache(&mut self) {
+        if let Some(state) = &mut self.state {
+            if let Some(cache) = state.read.inner_mut().cache.take() {
+                let path = self.cache_path.as_ref().expect("no cache path");
+                match cache.finish(&state.etag, state.len) {
+                    Ok(true) => info!("Saved the dump to {}", path.display()),
+                    Ok(false) => (),
+                    Err(err) => warn!("failed to save the dump to {}: {}", path.display(), err),
+                }
+            }
         }
     }
 }
 
 impl<B> DataInput for HttpDataInput<B>
 where
-    B: DecompressingReader<Response>,
+    B: DecompressingReader<HttpSource>,
 {
     type Error = HttpError;
 
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, HttpError> {
         match self.read_raw(buf) {
+            Ok(0) if !buf.is_empty() => {
+                self.finish_cache();
+                Ok(0)
+            }
             Ok(bytes) => Ok(bytes),
             Err(err) => match err.retry_policy() {
                 RetryPolicy::Retry => {
@@ -213,8 +452,62 @@ where
     }
 
     fn content_length(&self) -> Option<u64> {
-        self.state.as_ref().map_or(None, |s| s.len)
+        self.state.as_ref().and_then(|s| s.len)
+    }
+
+    fn resume_position(&self, out_offset: u64) -> Option<ResumePosition> {
+        self.state
+            .as_ref()
+            .and_then(|s| s.read.resume_position(out_offset))
+    }
+
+    /// Returns the etag of the data at the first URL. The etags of mirrors are not returned,
+    /// since they can't be compared to those of the first URL.
+    fn etag(&self) -> Option<String> {
+        self.state
+            .as_ref()
+            .filter(|s| s.url_index == 0)
+            .map(|s| s.etag.clone())
+            .filter(|etag| !etag.is_empty())
+    }
+
+    fn modified(&self) -> Option<String> {
+        self.state.as_ref().and_then(|s| s.modified.clone())
+    }
+
+    fn set_hash(&mut self, hash: RollingHash) {
+        match &mut self.state {
+            Some(state) => state.read.inner_mut().hash = Some(hash),
+            None => self.hash = Some(hash),
+        }
     }
+
+    fn take_hash(&mut self) -> Option<RollingHash> {
+        match &mut self.state {
+            Some(state) => state.read.inner_mut().hash.take(),
+            None => self.hash.take(),
+        }
+    }
+}
+
+/// Parses a `Content-Range` header value into the start offset and the total length, if known.
+fn parse_content_range(content_range: &str) -> Result<(u64, Option<u64>), HttpError> {
+    let mut parts = content_range.split(' ');
+    if parts.next() != Some("bytes") {
+        return Err(HttpError::UnexpectedContentRange);
+    }
+    let range = parts.next().ok_or(HttpError::UnexpectedContentRange)?;
+    let mut range = range.split('/');
+    let start = range
+        .next()
+        .expect("first item should exist")
+        .split('-')
+        .next()
+        .expect("first item should exist")
+        .parse()
+        .map_err(|_| HttpError::UnexpectedContentRange)?;
+    let total = range.next().and_then(|total| total.parse().ok());
+    Ok((start, total))
 }
 
 enum RetryPolicy {
@@ -229,6 +522,8 @@ pub enum HttpError {
     NoConnection,
     #[error("connection was reopened but the etag no longer matches")]
     EtagMismatch,
+    #[error("mirror has a different content length")]
+    LengthMismatch,
     #[error("server returned unexpected status code {0} ({1:?})")]
     Status(reqwest::StatusCode, String),
     #[error("server returned unexpected content range header")]
@@ -277,3 +572,322 @@ impl HttpError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::test_server::{Fault, TestServer};
+    use crate::input::{InputLineIter, LineIterError};
+    use bzip2::write::BzEncoder;
+    use std::io::Write;
+
+    /// Returns a bzip2 file with several blocks and its lines.
+    fn fixture() -> (Vec<u8>, Vec<String>) {
+        let mut lines = Vec::new();
+        let mut x: u64 = 1;
+        for i in 0..40_000 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            lines.push(format!("{{\"id\":\"Q{}\",\"x\":\"{:x}\"}}", i, x));
+        }
+
+        let mut encoder = BzEncoder::new(Vec::new(), bzip2::Compression::new(1));
+        for line in &lines {
+            writeln!(encoder, "{}", line).unwrap();
+        }
+        (encoder.finish().unwrap(), lines)
+    }
+
+    fn fast_retry() -> RetryConfig {
+        RetryConfig {
+            initial_interval: Duration::from_millis(1),
+            max_interval: Duration::from_millis(10),
+            budget: Duration::from_millis(500),
+            ..Default::default()
+        }
+    }
+
+    fn read_lines<I>(input: I) -> Result<Vec<String>, LineIterError<I::Error>>
+    where
+        I: DataInput + Send + 'static,
+        I::Error: Send + 'static,
+    {
+        let mut lines = InputLineIter::new(input);
+        let mut out = Vec::new();
+        loop {
+            match lines.next() {
+                Ok(line) => out.push(line),
+                Err(LineIterError::Eof) => break Ok(out),
+                Err(err) => break Err(err),
+            }
+        }
+    }
+
+    fn read_with_faults(faults: Vec<Fault>, then: Fault) -> Result<Vec<String>, HttpError> {
+        let (data, _) = fixture();
+        let server = TestServer::start(data, faults, then);
+        let input = HttpAutoDataInput::new(server.url.clone()).with_retry(fast_retry());
+        read_lines(input).map_err(|err| match err {
+            LineIterError::Input(err) => err,
+            err => panic!("unexpected error: {}", err),
+        })
+    }
+
+    #[test]
+    fn uninterrupted() {
+        let (_, lines) = fixture();
+        assert_eq!(read_with_faults(vec![], Fault::None).unwrap(), lines);
+    }
+
+    #[test]
+    fn dropped_connections() {
+        let (data, lines) = fixture();
+        let server = TestServer::start(
+            data,
+            vec![Fault::DropAfter(20_000), Fault::DropAfter(30_000)],
+            Fault::None,
+        );
+        let input = HttpAutoDataInput::new(server.url.clone()).with_retry(fast_retry());
+        assert_eq!(read_lines(input).unwrap(), lines);
+        // each reconnection must continue where the last connection stopped
+        assert_eq!(server.requests(), vec![None, Some(20_000), Some(50_000)]);
+    }
+
+    #[test]
+    fn dropped_connection_with_bz_decoder() {
+        // the decoder buffers its input, which must not affect the offset to continue at
+        let (data, lines) = fixture();
+        let server = TestServer::start(data, vec![Fault::DropAfter(20_000)], Fault::None);
+        let input = HttpBz2DataInput::new(server.url.clone()).with_retry(fast_retry());
+        assert_eq!(read_lines(input).unwrap(), lines);
+        assert_eq!(server.requests(), vec![None, Some(20_000)]);
+    }
+
+    #[test]
+    fn range_ignored() {
+        let (_, lines) = fixture();
+        let faults = vec![Fault::DropAfter(20_000), Fault::IgnoreRange];
+        assert_eq!(read_with_faults(faults, Fault::None).unwrap(), lines);
+    }
+
+    #[test]
+    fn range_starts_earlier() {
+        let (_, lines) = fixture();
+        let faults = vec![Fault::DropAfter(20_000), Fault::EarlierRange(5_000)];
+        assert_eq!(read_with_faults(faults, Fault::None).unwrap(), lines);
+    }
+
+    #[test]
+    fn range_starts_later() {
+        let faults = vec![Fault::DropAfter(20_000)];
+        let err = read_with_faults(faults, Fault::LaterRange(5_000)).unwrap_err();
+        assert!(matches!(err, HttpError::ContentRangeTooSmall), "{}", err);
+    }
+
+    #[test]
+    fn etag_changed() {
+        let faults = vec![Fault::DropAfter(20_000)];
+        let err = read_with_faults(faults, Fault::ChangeEtag).unwrap_err();
+        assert!(matches!(err, HttpError::EtagMismatch), "{}", err);
+    }
+
+    #[test]
+    fn server_errors() {
+        let (_, lines) = fixture();
+        let faults = vec![
+            Fault::Status(503),
+            Fault::DropAfter(20_000),
+            Fault::Status(500),
+        ];
+        assert_eq!(read_with_faults(faults, Fault::None).unwrap(), lines);
+
+        let err = read_with_faults(vec![], Fault::Status(503)).unwrap_err();
+        assert!(matches!(err, HttpError::Status(..)), "{}", err);
+    }
+
+    #[test]
+    fn resume() {
+        let (data, lines) = fixture();
+        let server = TestServer::start(data, vec![], Fault::None);
+
+        let mut first = InputLineIter::new(HttpAutoDataInput::new(server.url.clone()));
+        for _ in 0..25_000 {
+            first.next().unwrap();
+        }
+        let position = first.resume_position().unwrap();
+        let etag = first.etag().unwrap();
+        let line_offset = first.line_offset;
+        assert!(position.out_offset > 0);
+
+        let len = first.content_length();
+        let input = HttpAutoDataInput::resume(server.url.clone(), Some(etag), len, position);
+        let mut resumed = InputLineIter::resume(input, &position, line_offset).unwrap();
+        let mut rest = Vec::new();
+        while let Ok(line) = resumed.next() {
+            rest.push(line);
+        }
+        assert_eq!(rest, lines[25_000..]);
+        assert_eq!(
+            server.requests().last(),
+            Some(&Some(position.byte_offset()))
+        );
+
+        let input =
+            HttpAutoDataInput::resume(server.url.clone(), Some("\"other\"".into()), len, position)
+                .with_retry(fast_retry());
+        match InputLineIter::resume(input, &position, line_offset) {
+            Err(LineIterError::Input(HttpError::EtagMismatch)) => (),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("resumed with a different etag"),
+        }
+    }
+
+    #[test]
+    fn resume_from_mirror() {
+        let (data, lines) = fixture();
+        let server = TestServer::start(data.clone(), vec![], Fault::None);
+        let mut first = InputLineIter::new(HttpAutoDataInput::new(server.url.clone()));
+        for _ in 0..25_000 {
+            first.next().unwrap();
+        }
+        let position = first.resume_position().unwrap();
+        let etag = first.etag();
+        let len = first.content_length();
+        let line_offset = first.line_offset;
+        assert!(etag.is_some());
+
+        // the mirror has a different etag, so only the length can be checked
+        let mirror = TestServer::start(data, vec![], Fault::ChangeEtag);
+        // nothing is listening on port 9
+        let input = HttpAutoDataInput::resume("http://127.0.0.1:9/".into(), etag, len, position)
+            .with_mirrors(vec![mirror.url.clone()])
+            .with_retry(fast_retry());
+        let mut resumed = InputLineIter::resume(input, &position, line_offset).unwrap();
+        let mut rest = Vec::new();
+        while let Ok(line) = resumed.next() {
+            rest.push(line);
+        }
+        assert_eq!(rest, lines[25_000..]);
+        assert_eq!(mirror.requests(), vec![Some(position.byte_offset())]);
+    }
+
+    /// Reads all decompressed data from the input.
+    fn read_all<I: DataInput>(input: &mut I) -> Result<Vec<u8>, I::Error> {
+        let mut out = Vec::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match input.read(&mut buf)? {
+                0 => break Ok(out),
+                read => out.extend_from_slice(&buf[..read]),
+            }
+        }
+    }
+
+    /// Returns the path of a cache file for the given test, with no leftovers of earlier runs.
+    fn cache_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "geo-db-http-test-{}-{}.json.bz2",
+            name,
+            std::process::id()
+        ));
+        for path in [path.clone(), CacheFile::part_path(&path), etag_path(&path)] {
+            let _ = std::fs::remove_file(path);
+        }
+        path
+    }
+
+    fn etag_path(path: &std::path::Path) -> PathBuf {
+        let mut path = CacheFile::part_path(path).into_os_string();
+        path.push(".etag");
+        path.into()
+    }
+
+    fn lines_data(lines: &[String]) -> Vec<u8> {
+        lines
+            .iter()
+            .flat_map(|line| format!("{}\n", line).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn caches_downloads() {
+        let (data, lines) = fixture();
+        let path = cache_path("cache");
+        // left over from a different version of the dump
+        std::fs::write(CacheFile::part_path(&path), b"stale").unwrap();
+        std::fs::write(etag_path(&path), "\"other\"").unwrap();
+
+        let server = TestServer::start(data.clone(), vec![Fault::DropAfter(20_000)], Fault::None);
+        let mut input = HttpAutoDataInput::new(server.url.clone())
+            .with_cache(path.clone())
+            .with_retry(fast_retry());
+        assert_eq!(read_all(&mut input).unwrap(), lines_data(&lines));
+        assert_eq!(input.modified().as_deref(), Some("20210102"));
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert!(!CacheFile::part_path(&path).exists());
+        assert!(!etag_path(&path).exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn caches_resumed_downloads() {
+        let (data, lines) = fixture();
+        let all = lines_data(&lines);
+        let server = TestServer::start(data.clone(), vec![], Fault::None);
+        let path = cache_path("resume");
+
+        // interrupt the download halfway through
+        let mut first = HttpAutoDataInput::new(server.url.clone()).with_cache(path.clone());
+        let mut buf = vec![0; 64 * 1024];
+        let mut out_offset = 0;
+        while out_offset < all.len() as u64 / 2 {
+            out_offset += first.read(&mut buf).unwrap() as u64;
+        }
+        let position = first.resume_position(out_offset).unwrap();
+        let etag = first.etag();
+        let len = first.content_length();
+        assert!(position.out_offset > 0);
+        drop(first);
+        assert!(!path.exists());
+        assert_eq!(
+            std::fs::read_to_string(etag_path(&path)).unwrap(),
+            etag.clone().unwrap()
+        );
+
+        let mut resumed =
+            HttpAutoDataInput::resume(server.url.clone(), etag.clone(), len, position)
+                .with_cache(path.clone());
+        let rest = read_all(&mut resumed).unwrap();
+        assert_eq!(rest, all[position.out_offset as usize..]);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert!(!CacheFile::part_path(&path).exists());
+        assert!(!etag_path(&path).exists());
+        std::fs::remove_file(&path).unwrap();
+
+        // a partial file of a different version of the dump isn't continued
+        std::fs::write(
+            CacheFile::part_path(&path),
+            &data[..position.byte_offset() as usize],
+        )
+        .unwrap();
+        std::fs::write(etag_path(&path), "\"other\"").unwrap();
+        let mut resumed = HttpAutoDataInput::resume(server.url.clone(), etag, len, position)
+            .with_cache(path.clone());
+        assert_eq!(read_all(&mut resumed).unwrap(), rest);
+        assert!(!path.exists());
+        let _ = std::fs::remove_file(CacheFile::part_path(&path));
+        let _ = std::fs::remove_file(etag_path(&path));
+    }
+
+    #[test]
+    fn content_range() {
+        assert_eq!(
+            parse_content_range("bytes 100-199/1000").unwrap(),
+            (100, Some(1000))
+        );
+        assert_eq!(parse_content_range("bytes 100-199/*").unwrap(), (100, None));
+        assert!(parse_content_range("items 100-199/1000").is_err());
+        assert!(parse_content_range("bytes").is_err());
+    }
+}
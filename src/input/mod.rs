@@ -1,87 +1,381 @@
-use std::collections::VecDeque;
-use std::mem;
-use std::string::FromUtf8Error;
+use std::str::Utf8Error;
+use std::thread::{self, JoinHandle};
 
+use crate::input::checksum::RollingHash;
+use crossbeam::channel::{self, Receiver, Sender};
+use memchr::memchr;
 use thiserror::Error;
 
+pub mod cache;
+pub mod checksum;
 mod compression;
 pub mod file;
 pub mod http;
+pub mod par_bz2;
+// also used by the integration tests, which can't see items that are only compiled for tests
+#[doc(hidden)]
+pub mod test_server;
 
 pub trait DataInput {
     type Error;
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
     fn bytes_read(&self) -> u64;
     fn content_length(&self) -> Option<u64>;
+
+    /// Returns the last position at or before the given decompressed offset that reading can be
+    /// resumed from, if the input supports resuming.
+    fn resume_position(&self, _out_offset: u64) -> Option<ResumePosition> {
+        None
+    }
+
+    /// Returns a string that changes if the data changes (e.g. the HTTP ETag).
+    fn etag(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the date the data was last modified (YYYYMMDD), if known (e.g. from the HTTP
+    /// Last-Modified header).
+    fn modified(&self) -> Option<String> {
+        None
+    }
+
+    /// Starts updating the given hash with the compressed data. Must be called before the first
+    /// read, or, if the input was resumed, with a hash of the data before the resume position.
+    fn set_hash(&mut self, _hash: RollingHash) {}
+
+    /// Returns the hash set with `set_hash`, updated with all data read since.
+    fn take_hash(&mut self) -> Option<RollingHash> {
+        None
+    }
+}
+
+/// A point in the compressed data where decoding can be restarted, i.e. the start of a bzip2
+/// block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumePosition {
+    /// Bit offset of the block in the compressed data.
+    pub bit_offset: u64,
+    /// bzip2 block size level of the stream containing the block.
+    pub level: u8,
+    /// Offset of the block in the decompressed data.
+    pub out_offset: u64,
+}
+
+impl ResumePosition {
+    /// Offset of the byte in the compressed data that contains the start of the block.
+    pub fn byte_offset(&self) -> u64 {
+        self.bit_offset / 8
+    }
 }
 
-pub struct InputLineIter<I> {
-    pub input: I,
+/// Splits the decompressed data into lines.
+///
+/// The input is read on a separate thread, which sends large chunks of data along with the state
+/// of the input at the time, so that decompression can run in parallel with everything else.
+pub struct InputLineIter<I: DataInput> {
+    chunks: Receiver<Result<Chunk, I::Error>>,
+    /// Returns chunk buffers to the reader thread for reuse.
+    empty_chunks: Sender<Vec<u8>>,
+    reader: Option<JoinHandle<I>>,
+    chunk: Chunk,
+    /// Offset of the current chunk in the decompressed data.
+    chunk_offset: u64,
+    /// Position of the next line in the current chunk.
+    chunk_pos: usize,
+    /// Start of a line that continues in the next chunk.
+    partial_line: Vec<u8>,
+    /// Lines that can be reused.
+    lines: (Sender<String>, Receiver<String>),
+    /// Number of decompressed bytes read.
     pub bytes_read: u64,
-    line_buf: Vec<u8>,
-    pending_lines: VecDeque<String>,
+    /// Offset in the decompressed data of the line that will be returned next.
+    pub line_offset: u64,
+    /// A position to resume from before the line that will be returned next.
+    line_resume_position: Option<ResumePosition>,
 }
 
-const ESTIMATED_LINE_SIZE: usize = 65536;
+/// A piece of decompressed data, and the state of the input after reading it.
+struct Chunk {
+    data: Vec<u8>,
+    /// Compressed bytes read from the input.
+    bytes_read: u64,
+    content_length: Option<u64>,
+    etag: Option<String>,
+    modified: Option<String>,
+    /// Positions to resume from, in order: the last one before the start of the chunk, followed
+    /// by any within the chunk.
+    resume_positions: Vec<ResumePosition>,
+    /// Whether this is the last chunk.
+    eof: bool,
+}
+
+const CHUNK_SIZE: usize = 4 << 20;
+/// Number of chunks that may be read ahead.
+const CHUNK_QUEUE_SIZE: usize = 4;
+const RECYCLED_LINES: usize = 1024;
+/// Lines larger than this are not reused, so they don't take up memory forever.
+const MAX_RECYCLED_LINE_CAPACITY: usize = 1 << 20;
 
 impl<I> InputLineIter<I>
 where
-    I: DataInput,
+    I: DataInput + Send + 'static,
+    I::Error: Send + 'static,
 {
     pub fn new(input: I) -> Self {
+        Self::start(input, 0, None)
+    }
+
+    /// Continues reading from an input that was resumed at the given position, skipping ahead to
+    /// the line at the given offset.
+    pub fn resume(
+        input: I,
+        position: &ResumePosition,
+        line_offset: u64,
+    ) -> Result<Self, LineIterError<I::Error>> {
+        let mut lines = Self::start(input, position.out_offset, Some(*position));
+
+        while lines.bytes_read < line_offset {
+            if !lines.next_chunk()? {
+                return Err(LineIterError::Eof);
+            }
+        }
+        lines.chunk_pos = (line_offset - lines.chunk_offset) as usize;
+        lines.line_offset = line_offset;
+        Ok(lines)
+    }
+
+    fn start(input: I, out_offset: u64, resume_position: Option<ResumePosition>) -> Self {
+        let (chunk_send, chunk_recv) = channel::bounded(CHUNK_QUEUE_SIZE);
+        let (empty_send, empty_recv) = channel::bounded(CHUNK_QUEUE_SIZE);
+        let reader = thread::spawn(move || read_chunks(input, out_offset, chunk_send, empty_recv));
+
         InputLineIter {
-            input,
-            bytes_read: 0,
-            line_buf: Vec::with_capacity(ESTIMATED_LINE_SIZE),
-            pending_lines: VecDeque::new(),
+            chunks: chunk_recv,
+            empty_chunks: empty_send,
+            reader: Some(reader),
+            chunk: Chunk {
+                data: Vec::new(),
+                bytes_read: 0,
+                content_length: None,
+                etag: None,
+                modified: None,
+                resume_positions: resume_position.into_iter().collect(),
+                eof: false,
+            },
+            chunk_offset: out_offset,
+            chunk_pos: 0,
+            partial_line: Vec::new(),
+            lines: channel::bounded(RECYCLED_LINES),
+            bytes_read: out_offset,
+            line_offset: out_offset,
+            line_resume_position: resume_position,
+        }
+    }
+
+    /// Moves on to the next chunk. Returns false at the end of the data.
+    fn next_chunk(&mut self) -> Result<bool, LineIterError<I::Error>> {
+        if self.chunk.eof {
+            return Ok(false);
         }
+        let chunk = match self.chunks.recv() {
+            Ok(chunk) => chunk?,
+            Err(_) => return Err(LineIterError::ReaderStopped),
+        };
+
+        let old_data = std::mem::replace(&mut self.chunk, chunk).data;
+        let _ = self.empty_chunks.try_send(old_data);
+        self.chunk_offset = self.bytes_read;
+        self.chunk_pos = 0;
+        self.bytes_read += self.chunk.data.len() as u64;
+        Ok(true)
     }
 
-    fn push_line(&mut self) -> Result<(), LineIterError<I::Error>> {
-        self.pending_lines.push_back(
-            String::from_utf8(mem::replace(
-                &mut self.line_buf,
-                Vec::with_capacity(ESTIMATED_LINE_SIZE),
-            ))
-            .map_err(LineIterError::Utf8)?,
-        );
-        Ok(())
+    fn make_line(&self, data: &[u8]) -> Result<String, LineIterError<I::Error>> {
+        let data = std::str::from_utf8(data).map_err(LineIterError::Utf8)?;
+        let mut line = self.lines.1.try_recv().unwrap_or_default();
+        line.clear();
+        line.push_str(data);
+        Ok(line)
     }
 
+    // not an Iterator, since the end of the input is an error that has to be handled
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Result<String, LineIterError<I::Error>> {
-        let mut buf = [0; ESTIMATED_LINE_SIZE];
-
-        while self.pending_lines.is_empty() {
-            let bytes_read = self.input.read(&mut buf)?;
-            self.bytes_read += bytes_read as u64;
-
-            let mut cursor = 0;
-            for i in 0..bytes_read {
-                let byte = buf[i];
-                if byte == b'\n' {
-                    self.line_buf.extend_from_slice(&buf[cursor..i]);
-                    self.push_line()?;
-                    cursor = i + 1;
-                }
-            }
-            if cursor < bytes_read {
-                self.line_buf.extend_from_slice(&buf[cursor..bytes_read]);
+        loop {
+            let data = &self.chunk.data[self.chunk_pos..];
+            if let Some(len) = memchr(b'\n', data) {
+                let line = if self.partial_line.is_empty() {
+                    self.make_line(&data[..len])?
+                } else {
+                    self.partial_line.extend_from_slice(&data[..len]);
+                    let line = self.make_line(&self.partial_line)?;
+                    self.partial_line.clear();
+                    line
+                };
+                self.chunk_pos += len + 1;
+                self.line_offset = self.chunk_offset + self.chunk_pos as u64;
+                self.update_resume_position();
+                return Ok(line);
             }
-            if bytes_read == 0 {
+
+            self.partial_line.extend_from_slice(data);
+            self.chunk_pos = self.chunk.data.len();
+            if !self.next_chunk()? {
                 // EOF
-                if !self.line_buf.is_empty() {
-                    self.push_line()?;
-                } else {
+                if self.partial_line.is_empty() {
                     // the end of the end
                     return Err(LineIterError::Eof);
                 }
+                let line = self.make_line(&self.partial_line)?;
+                self.partial_line.clear();
+                self.line_offset = self.bytes_read;
+                self.update_resume_position();
+                return Ok(line);
             }
         }
+    }
+
+    fn update_resume_position(&mut self) {
+        let line_offset = self.line_offset;
+        if let Some(position) = self
+            .chunk
+            .resume_positions
+            .iter()
+            .rev()
+            .find(|position| position.out_offset <= line_offset)
+        {
+            self.line_resume_position = Some(*position);
+        }
+    }
+
+    /// Returns a handle that gives lines back to the iterator so their memory can be reused.
+    pub fn recycler(&self) -> LineRecycler {
+        LineRecycler(self.lines.0.clone())
+    }
+
+    /// Compressed bytes read from the input.
+    pub fn input_bytes_read(&self) -> u64 {
+        self.chunk.bytes_read
+    }
 
-        Ok(self.pending_lines.pop_front().unwrap())
+    pub fn content_length(&self) -> Option<u64> {
+        self.chunk.content_length
+    }
+
+    pub fn etag(&self) -> Option<String> {
+        self.chunk.etag.clone()
+    }
+
+    pub fn modified(&self) -> Option<String> {
+        self.chunk.modified.clone()
+    }
+
+    /// Returns a position to resume from before the line that will be returned next.
+    pub fn resume_position(&self) -> Option<ResumePosition> {
+        self.line_resume_position
+    }
+
+    /// Stops reading and returns the input. The iterator will return an error afterwards.
+    pub fn finish(&mut self) -> I {
+        // unblocks the reader thread if it's waiting for chunks to be taken, and makes `next`
+        // fail instead of waiting for chunks that never arrive
+        self.chunks = channel::bounded(0).1;
+        self.chunk.data.clear();
+        self.chunk.eof = false;
+        self.chunk_pos = 0;
+        self.partial_line.clear();
+        self.reader
+            .take()
+            .expect("already finished")
+            .join()
+            .expect("reader thread panicked")
+    }
+}
+
+/// Gives lines back to an `InputLineIter` so their memory can be reused.
+#[derive(Clone)]
+pub struct LineRecycler(Sender<String>);
+
+impl LineRecycler {
+    pub fn recycle(&self, line: String) {
+        if line.capacity() <= MAX_RECYCLED_LINE_CAPACITY {
+            let _ = self.0.try_send(line);
+        }
     }
 }
 
+/// Reads chunks from the input until the end of the data, an error, or until the receiver is
+/// dropped.
+fn read_chunks<I: DataInput>(
+    mut input: I,
+    mut out_offset: u64,
+    chunks: Sender<Result<Chunk, I::Error>>,
+    empty_chunks: Receiver<Vec<u8>>,
+) -> I {
+    loop {
+        let mut data = empty_chunks.try_recv().unwrap_or_default();
+        data.resize(CHUNK_SIZE, 0);
+
+        let mut len = 0;
+        let mut eof = false;
+        let mut error = None;
+        while len < data.len() {
+            match input.read(&mut data[len..]) {
+                Ok(0) => {
+                    eof = true;
+                    break;
+                }
+                Ok(read) => len += read,
+                Err(err) => {
+                    error = Some(err);
+                    break;
+                }
+            }
+        }
+        data.truncate(len);
+
+        let end_offset = out_offset + len as u64;
+        let chunk = Chunk {
+            data,
+            bytes_read: input.bytes_read(),
+            content_length: input.content_length(),
+            etag: input.etag(),
+            modified: input.modified(),
+            resume_positions: resume_positions(&input, out_offset, end_offset),
+            eof,
+        };
+        out_offset = end_offset;
+
+        if chunks.send(Ok(chunk)).is_err() {
+            break;
+        }
+        if let Some(err) = error {
+            let _ = chunks.send(Err(err));
+            break;
+        }
+        if eof {
+            break;
+        }
+    }
+    input
+}
+
+/// Collects the resume positions between two decompressed offsets, along with the last one
+/// before them.
+fn resume_positions<I: DataInput>(input: &I, start: u64, end: u64) -> Vec<ResumePosition> {
+    let mut positions = Vec::new();
+    let mut offset = end;
+    while let Some(position) = input.resume_position(offset) {
+        positions.push(position);
+        if position.out_offset <= start || position.out_offset == 0 {
+            break;
+        }
+        offset = position.out_offset - 1;
+    }
+    positions.reverse();
+    positions
+}
+
 #[derive(Debug, Error)]
 pub enum LineIterError<I> {
     #[error("eof")]
@@ -89,5 +383,137 @@ pub enum LineIterError<I> {
     #[error("{0}")]
     Input(#[from] I),
     #[error("utf8 error: {0}")]
-    Utf8(FromUtf8Error),
+    Utf8(Utf8Error),
+    #[error("input reader stopped")]
+    ReaderStopped,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Uncompressed data that is read in pieces of a fixed size, with a resume position every
+    /// `block` bytes.
+    struct TestInput {
+        data: Vec<u8>,
+        /// Offset of `data` in the whole input.
+        start: usize,
+        pos: usize,
+        read_size: usize,
+        block: u64,
+    }
+
+    impl TestInput {
+        fn new(data: &[u8], read_size: usize) -> Self {
+            Self::resume(data, read_size, 0)
+        }
+
+        fn resume(data: &[u8], read_size: usize, start: usize) -> Self {
+            TestInput {
+                data: data.to_vec(),
+                start,
+                pos: start,
+                read_size,
+                block: 100_000,
+            }
+        }
+
+        fn position(&self, out_offset: u64) -> ResumePosition {
+            let out_offset = out_offset / self.block * self.block;
+            ResumePosition {
+                bit_offset: out_offset * 8,
+                level: 9,
+                out_offset,
+            }
+        }
+    }
+
+    impl DataInput for TestInput {
+        type Error = std::io::Error;
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let len = self
+                .read_size
+                .min(buf.len())
+                .min(self.data.len() - self.pos);
+            buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+        fn bytes_read(&self) -> u64 {
+            (self.pos - self.start) as u64
+        }
+        fn content_length(&self) -> Option<u64> {
+            Some(self.data.len() as u64)
+        }
+        fn resume_position(&self, out_offset: u64) -> Option<ResumePosition> {
+            Some(self.position(out_offset))
+        }
+    }
+
+    /// Returns lines of various lengths, including empty ones and some longer than a chunk, and
+    /// the data they were taken from, which doesn't end with a newline.
+    fn lines() -> (Vec<String>, Vec<u8>) {
+        let mut lines = Vec::new();
+        for i in 0..20_000 {
+            lines.push(format!("{{\"id\":\"Q{}\"}}{}", i, "x".repeat(i % 300)));
+            if i % 5000 == 0 {
+                lines.push(String::new());
+                lines.push("y".repeat(CHUNK_SIZE + i));
+            }
+        }
+        lines.push("no newline".into());
+        (lines.clone(), lines.join("\n").into_bytes())
+    }
+
+    /// Reads the remaining lines, checking the offsets and resume positions along the way.
+    fn read_lines(lines: &mut InputLineIter<TestInput>, expected: &[String], offset: u64) {
+        let recycler = lines.recycler();
+        let mut offset = offset;
+        for expected in expected {
+            assert_eq!(lines.line_offset, offset);
+            let line = lines.next().unwrap();
+            assert_eq!(&line, expected);
+            recycler.recycle(line);
+
+            offset = (offset + expected.len() as u64 + 1).min(lines.bytes_read);
+            let position = lines.resume_position().unwrap();
+            assert_eq!(position.out_offset, offset / 100_000 * 100_000);
+            assert_eq!(position.bit_offset, position.out_offset * 8);
+        }
+        assert!(matches!(lines.next(), Err(LineIterError::Eof)));
+    }
+
+    #[test]
+    fn splits_lines() {
+        let (expected, data) = lines();
+        for &read_size in &[1, 4097, CHUNK_SIZE, CHUNK_SIZE + 1] {
+            let mut lines = InputLineIter::new(TestInput::new(&data, read_size));
+            read_lines(&mut lines, &expected, 0);
+            assert_eq!(lines.bytes_read, data.len() as u64);
+            assert_eq!(lines.input_bytes_read(), data.len() as u64);
+            assert_eq!(lines.finish().pos, data.len());
+        }
+    }
+
+    #[test]
+    fn resumes_at_lines() {
+        let (expected, data) = lines();
+        let mut lines = InputLineIter::new(TestInput::new(&data, CHUNK_SIZE));
+        for _ in 0..12_345 {
+            lines.next().unwrap();
+        }
+        let position = lines.resume_position().unwrap();
+        let line_offset = lines.line_offset;
+        assert!(position.out_offset > 0 && position.out_offset < line_offset);
+        let input = lines.finish();
+        assert!(matches!(lines.next(), Err(LineIterError::ReaderStopped)));
+
+        for &read_size in &[1, CHUNK_SIZE] {
+            let input = TestInput::resume(&input.data, read_size, position.out_offset as usize);
+            let mut lines = InputLineIter::resume(input, &position, line_offset).unwrap();
+            assert_eq!(lines.resume_position(), Some(position));
+            read_lines(&mut lines, &expected[12_345..], line_offset);
+        }
+    }
 }
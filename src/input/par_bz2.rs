@@ -0,0 +1,795 @@
+//! Parallel bzip2 decoding.
+//!
+//! A bzip2 file consists of one or more streams, each of which consists of a header, any number
+//! of independently compressed blocks, and an end-of-stream marker with a combined CRC.
+//! Neither blocks nor end-of-stream markers are byte-aligned, but both start with a 48-bit magic
+//! number, which we search for bit by bit.
+//!
+//! Each block is then wrapped into a single-block bzip2 stream of its own and decoded by libbzip2
+//! on the rayon thread pool. libbzip2 checks the block CRC, and we check the combined CRC of each
+//! stream once all of its blocks have been decoded.
+//!
+//! The magic numbers may occasionally also appear inside compressed block data. Such a false
+//! block boundary results in two invalid blocks, so if a block fails to decode, it is retried
+//! merged with the next block. False end-of-stream markers are rejected up front because they
+//! have to be followed by either the end of the file or the header of another stream.
+
+use crate::input::compression::DecompressingReader;
+use crate::input::ResumePosition;
+use bzip2::read::{BzDecoder, MultiBzDecoder};
+use crossbeam::channel::{self, Receiver};
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::{fs, mem};
+
+const BLOCK_MAGIC: u64 = 0x3141_5926_5359;
+const EOS_MAGIC: u64 = 0x1772_4538_5090;
+const MAGIC_MASK: u64 = 0xffff_ffff_ffff;
+const MAGIC_BITS: u64 = 48;
+const CRC_BITS: u64 = 32;
+const STREAM_HEADER_LEN: u64 = 4;
+
+/// How many bytes to read from the inner reader at once.
+const READ_CHUNK_SIZE: usize = 1 << 20;
+
+/// How many of the most recently decoded blocks to remember as resume positions.
+const MAX_RESUME_POSITIONS: usize = 4096;
+
+/// How often a block that fails to decode is merged with the next block before giving up.
+/// False block boundaries are rare enough that a block never contains more than a few.
+const MAX_MERGES: usize = 3;
+
+/// A compressed block, as found in the input.
+struct RawBlock {
+    /// Absolute bit offset of the block magic in the input.
+    bit_offset: u64,
+    /// Block size level of the stream this block belongs to (1–9).
+    level: u8,
+    /// Bytes containing the block. The block starts at bit `bit_offset % 8` in the first byte.
+    data: Vec<u8>,
+    /// Length of the block in bits, including the block magic.
+    bit_len: u64,
+    /// The CRC stored in the block header.
+    crc: u32,
+    /// If this is the last block of its stream: the combined CRC stored in the stream footer.
+    stream_crc: Option<u32>,
+}
+
+impl RawBlock {
+    fn start_shift(&self) -> u64 {
+        self.bit_offset % 8
+    }
+
+    /// Merges this block with the one immediately following it.
+    fn merge(&self, next: &RawBlock) -> Option<RawBlock> {
+        if self.stream_crc.is_some() || self.bit_offset + self.bit_len != next.bit_offset {
+            return None;
+        }
+
+        // if the next block does not start on a byte boundary, both blocks contain that byte
+        let overlap = if next.start_shift() == 0 { 0 } else { 1 };
+        let mut data = Vec::with_capacity(self.data.len() + next.data.len());
+        data.extend_from_slice(&self.data[..self.data.len() - overlap]);
+        data.extend_from_slice(&next.data);
+
+        Some(RawBlock {
+            bit_offset: self.bit_offset,
+            level: self.level,
+            data,
+            bit_len: self.bit_len + next.bit_len,
+            crc: self.crc,
+            stream_crc: next.stream_crc,
+        })
+    }
+
+    /// Decodes the block by wrapping it in a single-block bzip2 stream.
+    fn decode(&self) -> io::Result<Vec<u8>> {
+        let mut stream = BitWriter::with_capacity(self.data.len() + 16);
+        stream.write_bytes(b"BZh");
+        stream.write_bytes(&[b'0' + self.level]);
+        stream.copy_bits(&self.data, self.start_shift(), self.bit_len);
+        stream.write_bits(EOS_MAGIC, MAGIC_BITS as u32);
+        // the combined CRC of a stream with a single block is just the block CRC
+        stream.write_bits(self.crc as u64, CRC_BITS as u32);
+        let stream = stream.finish();
+
+        let mut out = Vec::with_capacity(self.level as usize * 100_000);
+        BzDecoder::new(&stream[..]).read_to_end(&mut out)?;
+        Ok(out)
+    }
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    acc_bits: u32,
+}
+
+impl BitWriter {
+    fn with_capacity(capacity: usize) -> Self {
+        BitWriter {
+            out: Vec::with_capacity(capacity),
+            acc: 0,
+            acc_bits: 0,
+        }
+    }
+
+    /// Writes the lowest `bits` bits of the value. At most 56 bits can be written at once.
+    fn write_bits(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 56);
+        self.acc = (self.acc << bits) | (value & ((1 << bits) - 1));
+        self.acc_bits += bits;
+        while self.acc_bits >= 8 {
+            self.acc_bits -= 8;
+            self.out.push((self.acc >> self.acc_bits) as u8);
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        if self.acc_bits == 0 {
+            self.out.extend_from_slice(bytes);
+        } else {
+            for byte in bytes {
+                self.write_bits(*byte as u64, 8);
+            }
+        }
+    }
+
+    /// Copies `bit_len` bits, starting at bit `shift` of the first byte of `data`.
+    fn copy_bits(&mut self, data: &[u8], shift: u64, bit_len: u64) {
+        let full_bytes = (bit_len / 8) as usize;
+        let shift = shift as u32;
+        if shift == 0 {
+            self.write_bytes(&data[..full_bytes]);
+        } else {
+            for i in 0..full_bytes {
+                let byte = (data[i] << shift) | (data[i + 1] >> (8 - shift));
+                self.write_bits(byte as u64, 8);
+            }
+        }
+
+        let rest = (bit_len % 8) as u32;
+        if rest > 0 {
+            let bits = read_bits(data, full_bytes as u64 * 8 + shift as u64, rest);
+            self.write_bits(bits, rest);
+        }
+    }
+
+    /// Pads the last byte with zeroes.
+    fn finish(mut self) -> Vec<u8> {
+        if self.acc_bits > 0 {
+            let padding = 8 - self.acc_bits;
+            self.write_bits(0, padding);
+        }
+        self.out
+    }
+}
+
+/// Reads up to 57 bits at the given bit offset. The bits must be in bounds.
+fn read_bits(data: &[u8], bit_offset: u64, bits: u32) -> u64 {
+    let start = (bit_offset / 8) as usize;
+    let shift = (bit_offset % 8) as u32;
+    let mut value: u64 = 0;
+    let bytes = (shift + bits).div_ceil(8) as usize;
+    for byte in &data[start..start + bytes] {
+        value = (value << 8) | *byte as u64;
+    }
+    let total_bits = bytes as u32 * 8;
+    (value >> (total_bits - shift - bits)) & ((1 << bits) - 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScanState {
+    /// Expecting a stream header at the given absolute byte offset.
+    Header(u64),
+    /// Expecting a block or the end of the stream at the given absolute bit offset.
+    Marker(u64),
+    Eof,
+}
+
+/// Splits a bzip2 file into blocks.
+struct BlockScanner<R> {
+    inner: R,
+    /// Bytes read from the inner reader that have not been handed out in a block yet.
+    buf: Vec<u8>,
+    /// Absolute byte offset of the first byte in the buffer.
+    buf_offset: u64,
+    inner_eof: bool,
+    state: ScanState,
+    /// Block size level of the current stream.
+    level: u8,
+}
+
+impl<R> BlockScanner<R>
+where
+    R: Read,
+{
+    fn new(inner: R) -> Self {
+        BlockScanner {
+            inner,
+            buf: Vec::new(),
+            buf_offset: 0,
+            inner_eof: false,
+            state: ScanState::Header(0),
+            level: 9,
+        }
+    }
+
+    /// Creates a scanner that starts at a block in the middle of a stream. The inner reader must
+    /// start at the byte containing the start of the block.
+    fn resume(inner: R, position: &ResumePosition) -> Self {
+        BlockScanner {
+            inner,
+            buf: Vec::new(),
+            buf_offset: position.byte_offset(),
+            inner_eof: false,
+            state: ScanState::Marker(position.bit_offset),
+            level: position.level,
+        }
+    }
+
+    /// Total number of bytes read from the inner reader.
+    fn total_in(&self) -> u64 {
+        self.buf_offset + self.buf.len() as u64
+    }
+
+    /// Absolute bit offset one past the last buffered bit.
+    fn buf_end_bit(&self) -> u64 {
+        self.total_in() * 8
+    }
+
+    /// Reads more data. Returns false at EOF.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.inner_eof {
+            return Ok(false);
+        }
+        let len = self.buf.len();
+        self.buf.resize(len + READ_CHUNK_SIZE, 0);
+        let read = loop {
+            match self.inner.read(&mut self.buf[len..]) {
+                Ok(read) => break read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    self.buf.truncate(len);
+                    return Err(err);
+                }
+            }
+        };
+        self.buf.truncate(len + read);
+        self.inner_eof = read == 0;
+        Ok(!self.inner_eof)
+    }
+
+    /// Makes sure the given absolute bit offset is buffered. Returns false at EOF.
+    fn fill_to_bit(&mut self, bit: u64) -> io::Result<bool> {
+        while bit > self.buf_end_bit() {
+            if !self.fill()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_bits(&self, bit: u64, bits: u32) -> u64 {
+        read_bits(&self.buf, bit - self.buf_offset * 8, bits)
+    }
+
+    fn truncated() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "unexpected EOF while reading compressed data",
+        )
+    }
+
+    /// Drops buffered bytes before the given absolute byte offset.
+    fn discard_before(&mut self, offset: u64) {
+        let len = (offset - self.buf_offset) as usize;
+        self.buf.drain(..len);
+        self.buf_offset = offset;
+    }
+
+    /// Reads the next block from the input.
+    fn next_block(&mut self) -> io::Result<Option<RawBlock>> {
+        loop {
+            match self.state {
+                ScanState::Eof => return Ok(None),
+                ScanState::Header(offset) => {
+                    if !self.fill_to_bit((offset + STREAM_HEADER_LEN) * 8)? {
+                        if self.total_in() == offset {
+                            self.state = ScanState::Eof;
+                            continue;
+                        }
+                        return Err(Self::truncated());
+                    }
+                    let start = (offset - self.buf_offset) as usize;
+                    self.level = parse_stream_header(&self.buf[start..start + 4])?;
+                    self.state = ScanState::Marker((offset + STREAM_HEADER_LEN) * 8);
+                }
+                ScanState::Marker(bit) => {
+                    self.discard_before(bit / 8);
+                    if !self.fill_to_bit(bit + MAGIC_BITS + CRC_BITS)? {
+                        return Err(Self::truncated());
+                    }
+                    match self.read_bits(bit, MAGIC_BITS as u32) {
+                        BLOCK_MAGIC => return self.scan_block(bit).map(Some),
+                        EOS_MAGIC => {
+                            // empty stream
+                            if self.read_bits(bit + MAGIC_BITS, CRC_BITS as u32) != 0 {
+                                return Err(stream_crc_mismatch());
+                            }
+                            self.state =
+                                ScanState::Header(align_to_byte(bit + MAGIC_BITS + CRC_BITS) / 8);
+                        }
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "bzip2 block magic not found",
+                            ))
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Scans for the end of the block starting at the given bit.
+    fn scan_block(&mut self, start: u64) -> io::Result<RawBlock> {
+        let crc = self.read_bits(start + MAGIC_BITS, CRC_BITS as u32) as u32;
+
+        // the block header is followed by at least some data
+        let mut search_bit = start + MAGIC_BITS + CRC_BITS;
+        let (end, stream_crc) = loop {
+            match self.find_marker(search_bit) {
+                Some((bit, BLOCK_MAGIC)) => {
+                    self.state = ScanState::Marker(bit);
+                    break (bit, None);
+                }
+                Some((bit, _)) => match self.check_end_of_stream(bit)? {
+                    Some(next_stream) => {
+                        let stream_crc = self.read_bits(bit + MAGIC_BITS, CRC_BITS as u32);
+                        self.state = next_stream;
+                        break (bit, Some(stream_crc as u32));
+                    }
+                    None => search_bit = bit + 1,
+                },
+                None => {
+                    // keep the last bits around in case a marker straddles the buffer end
+                    search_bit = self
+                        .buf_end_bit()
+                        .saturating_sub(MAGIC_BITS - 1)
+                        .max(search_bit);
+                    if !self.fill()? {
+                        return Err(Self::truncated());
+                    }
+                }
+            }
+        };
+
+        let data_start = (start / 8 - self.buf_offset) as usize;
+        let data_end = (align_to_byte(end) / 8 - self.buf_offset) as usize;
+        Ok(RawBlock {
+            bit_offset: start,
+            level: self.level,
+            data: self.buf[data_start..data_end].to_vec(),
+            bit_len: end - start,
+            crc,
+            stream_crc,
+        })
+    }
+
+    /// Finds the next block or end-of-stream magic number in the buffer, starting at the given
+    /// absolute bit offset.
+    fn find_marker(&self, from_bit: u64) -> Option<(u64, u64)> {
+        let end_bit = self.buf_end_bit();
+        if from_bit + MAGIC_BITS > end_bit {
+            return None;
+        }
+
+        let first_byte = (from_bit / 8 - self.buf_offset) as usize;
+        let mut window: u64 = 0;
+        for (i, byte) in self.buf[first_byte..].iter().enumerate() {
+            window = (window << 8) | *byte as u64;
+            // bit offset right after this byte
+            let window_end = (self.buf_offset + (first_byte + i) as u64 + 1) * 8;
+            if window_end < from_bit + MAGIC_BITS {
+                continue;
+            }
+            // check all magic numbers ending in this byte, earliest first
+            for shift in (0..8).rev() {
+                let bit = window_end - shift - MAGIC_BITS;
+                if bit < from_bit {
+                    continue;
+                }
+                let candidate = (window >> shift) & MAGIC_MASK;
+                if candidate == BLOCK_MAGIC || candidate == EOS_MAGIC {
+                    return Some((bit, candidate));
+                }
+            }
+        }
+        None
+    }
+
+    /// Checks whether an end-of-stream magic number at the given bit is really the end of a
+    /// stream, by checking what follows it. Returns the state after the stream if so.
+    fn check_end_of_stream(&mut self, bit: u64) -> io::Result<Option<ScanState>> {
+        let next_stream = align_to_byte(bit + MAGIC_BITS + CRC_BITS) / 8;
+        let next_marker = (next_stream + STREAM_HEADER_LEN) * 8;
+        if !self.fill_to_bit(next_marker + MAGIC_BITS)? {
+            // the file has to end right after the end-of-stream marker
+            return Ok(if self.total_in() == next_stream {
+                Some(ScanState::Eof)
+            } else {
+                None
+            });
+        }
+
+        let start = (next_stream - self.buf_offset) as usize;
+        if parse_stream_header(&self.buf[start..start + 4]).is_err() {
+            return Ok(None);
+        }
+        match self.read_bits(next_marker, MAGIC_BITS as u32) {
+            BLOCK_MAGIC | EOS_MAGIC => Ok(Some(ScanState::Header(next_stream))),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn align_to_byte(bit: u64) -> u64 {
+    bit.div_ceil(8) * 8
+}
+
+fn parse_stream_header(header: &[u8]) -> io::Result<u8> {
+    match header {
+        [b'B', b'Z', b'h', level @ b'1'..=b'9'] => Ok(level - b'0'),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid bzip2 stream header",
+        )),
+    }
+}
+
+fn stream_crc_mismatch() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "bzip2 stream CRC mismatch")
+}
+
+/// A block that is being decoded on the thread pool.
+struct PendingBlock {
+    block: Arc<RawBlock>,
+    result: Receiver<io::Result<Vec<u8>>>,
+}
+
+impl PendingBlock {
+    fn spawn(block: RawBlock) -> Self {
+        let block = Arc::new(block);
+        let (send, recv) = channel::bounded(1);
+        let block2 = Arc::clone(&block);
+        rayon_core::spawn(move || {
+            let _ = send.send(block2.decode());
+        });
+        PendingBlock {
+            block,
+            result: recv,
+        }
+    }
+
+    fn wait(&self) -> io::Result<Vec<u8>> {
+        self.result
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::other("bzip2 block decoder panicked")))
+    }
+}
+
+/// Multistream-aware bzip2 decoder that decodes blocks in parallel on the rayon thread pool.
+pub struct ParBzDecoder<R> {
+    scanner: BlockScanner<R>,
+    pending: VecDeque<PendingBlock>,
+    max_pending: usize,
+    scanner_done: bool,
+    output: Vec<u8>,
+    output_pos: usize,
+    /// Total number of bytes output before the current output buffer.
+    out_offset: u64,
+    /// Combined CRC of the blocks of the current stream that have been output so far.
+    stream_crc: u32,
+    /// False if decoding started in the middle of the current stream.
+    stream_crc_valid: bool,
+    /// Start positions of the most recently output blocks.
+    block_starts: VecDeque<ResumePosition>,
+}
+
+impl<R> ParBzDecoder<R>
+where
+    R: Read,
+{
+    fn with_scanner(scanner: BlockScanner<R>, out_offset: u64) -> Self {
+        ParBzDecoder {
+            scanner,
+            pending: VecDeque::new(),
+            max_pending: rayon_core::current_num_threads() * 2,
+            scanner_done: false,
+            output: Vec::new(),
+            output_pos: 0,
+            out_offset,
+            stream_crc: 0,
+            stream_crc_valid: true,
+            block_starts: VecDeque::new(),
+        }
+    }
+
+    fn fill_pending(&mut self) -> io::Result<()> {
+        while !self.scanner_done && self.pending.len() < self.max_pending {
+            match self.scanner.next_block()? {
+                Some(block) => self.pending.push_back(PendingBlock::spawn(block)),
+                None => self.scanner_done = true,
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the next block into the output buffer. Returns false at EOF.
+    fn next_block(&mut self) -> io::Result<bool> {
+        self.fill_pending()?;
+        let pending = match self.pending.pop_front() {
+            Some(pending) => pending,
+            None => return Ok(false),
+        };
+
+        let mut result = pending.wait();
+        let mut block = pending.block;
+        let mut merges = 0;
+        while result.is_err() {
+            if merges == MAX_MERGES {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "bzip2 block at bit {} could not be decoded, even merged with the next {} blocks",
+                        block.bit_offset, MAX_MERGES
+                    ),
+                ));
+            }
+            // this might have been a false block boundary
+            if self.pending.is_empty() {
+                self.fill_pending()?;
+            }
+            let merged = match self.pending.front() {
+                Some(next) => block.merge(&next.block),
+                None => None,
+            };
+            match merged {
+                Some(merged) => {
+                    debug!(
+                        "retrying bzip2 block at bit {} merged with the next block",
+                        merged.bit_offset
+                    );
+                    self.pending.pop_front();
+                    merges += 1;
+                    result = merged.decode();
+                    block = Arc::new(merged);
+                }
+                None => break,
+            }
+        }
+        self.out_offset += self.output.len() as u64;
+        self.output = result?;
+        self.output_pos = 0;
+
+        if self.block_starts.len() == MAX_RESUME_POSITIONS {
+            self.block_starts.pop_front();
+        }
+        self.block_starts.push_back(ResumePosition {
+            bit_offset: block.bit_offset,
+            level: block.level,
+            out_offset: self.out_offset,
+        });
+
+        self.stream_crc = self.stream_crc.rotate_left(1) ^ block.crc;
+        if let Some(stream_crc) = block.stream_crc {
+            let valid = mem::replace(&mut self.stream_crc_valid, true);
+            if mem::replace(&mut self.stream_crc, 0) != stream_crc && valid {
+                return Err(stream_crc_mismatch());
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<R> DecompressingReader<R> for ParBzDecoder<R>
+where
+    R: Read,
+{
+    fn new(r: R) -> Self {
+        ParBzDecoder::with_scanner(BlockScanner::new(r), 0)
+    }
+    fn resume(r: R, position: &ResumePosition) -> io::Result<Self> {
+        let mut decoder =
+            ParBzDecoder::with_scanner(BlockScanner::resume(r, position), position.out_offset);
+        decoder.stream_crc_valid = false;
+        Ok(decoder)
+    }
+    fn inner(&self) -> &R {
+        &self.scanner.inner
+    }
+    fn inner_mut(&mut self) -> &mut R {
+        &mut self.scanner.inner
+    }
+    fn total_in(&self) -> u64 {
+        self.scanner.total_in()
+    }
+    fn resume_position(&self, out_offset: u64) -> Option<ResumePosition> {
+        self.block_starts
+            .iter()
+            .rev()
+            .find(|position| position.out_offset <= out_offset)
+            .copied()
+    }
+}
+
+impl<R> Read for ParBzDecoder<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.output_pos == self.output.len() {
+            if !self.next_block()? {
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.output.len() - self.output_pos);
+        buf[..len].copy_from_slice(&self.output[self.output_pos..self.output_pos + len]);
+        self.output_pos += len;
+        Ok(len)
+    }
+}
+
+/// Decodes the given file with both the parallel decoder and libbzip2, and checks that the
+/// output is identical.
+pub fn verify_decoders(path: &str) -> io::Result<bool> {
+    let mut reference = MultiBzDecoder::new(fs::File::open(path)?);
+    let mut parallel: ParBzDecoder<_> = DecompressingReader::new(fs::File::open(path)?);
+
+    const CHUNK_SIZE: usize = 1 << 20;
+    let mut ref_buf = vec![0; CHUNK_SIZE];
+    let mut par_buf = vec![0; CHUNK_SIZE];
+    let mut offset: u64 = 0;
+    let start_time = std::time::Instant::now();
+    let mut last_time = std::time::Instant::now();
+
+    loop {
+        let ref_len = read_full(&mut reference, &mut ref_buf)?;
+        let par_len = read_full(&mut parallel, &mut par_buf)?;
+
+        let common_len = ref_len.min(par_len);
+        if let Some(i) = (0..common_len).find(|i| ref_buf[*i] != par_buf[*i]) {
+            error!(
+                "Output differs at decompressed offset {}",
+                offset + i as u64
+            );
+            return Ok(false);
+        }
+        if ref_len != par_len {
+            error!(
+                "Output length differs: parallel decoder ended at {} and libbzip2 at {}",
+                offset + par_len as u64,
+                offset + ref_len as u64,
+            );
+            return Ok(false);
+        }
+        if ref_len == 0 {
+            break;
+        }
+        offset += ref_len as u64;
+
+        if last_time.elapsed().as_secs() >= 10 {
+            info!(
+                "{:.2} MB of {:.2} MB read, {:.2} MB decompressed output identical",
+                parallel.total_in() as f64 / 1_000_000.,
+                fs::metadata(path)?.len() as f64 / 1_000_000.,
+                offset as f64 / 1_000_000.,
+            );
+            last_time = std::time::Instant::now();
+        }
+    }
+
+    info!(
+        "{:.2} MB of decompressed output identical ({:.1}s)",
+        offset as f64 / 1_000_000.,
+        start_time.elapsed().as_secs_f64()
+    );
+    Ok(true)
+}
+
+/// Reads until the buffer is full or EOF is reached.
+fn read_full(read: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match read.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bzip2::write::BzEncoder;
+    use bzip2::Compression;
+    use std::io::Write;
+
+    fn compress(data: &[u8], level: u32) -> Vec<u8> {
+        let mut encoder = BzEncoder::new(Vec::new(), Compression::new(level));
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decode(compressed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoder: ParBzDecoder<_> = DecompressingReader::new(compressed);
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    /// Returns data that takes several blocks at level 1.
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut x: u64 = 1;
+        (0..len)
+            .map(|_| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (x >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merges_blocks_at_false_magic() {
+        // Right after the block CRC come a randomization bit, the 24-bit index of the original
+        // data in the sorted rotations, a 16-bit bitmap of the used byte ranges and a 16-bit
+        // bitmap of the used bytes in each of those. With the first byte unique and 201749
+        // bytes smaller than it, and the used bytes chosen to match the remaining bits, this
+        // spells out the block magic starting at the fifth bit of the index.
+        let small = [0x02, 0x03, 0x05, 0x07, 0x08, 0x0b, 0x30];
+        let large = [0x62, 0x90, 0xa0, 0xd0, 0xf0];
+        let mut data = vec![0x61];
+        data.extend(small.iter().cycle().take(201_749));
+        data.extend(large.iter().cycle().take(1000));
+        let compressed = compress(&data, 9);
+
+        let false_magic = 32 + MAGIC_BITS + CRC_BITS + 1 + 4;
+        assert_eq!(
+            read_bits(&compressed, false_magic, MAGIC_BITS as u32),
+            BLOCK_MAGIC
+        );
+        assert_eq!(decode(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn decodes_multiple_streams() {
+        let first = random_data(250_000);
+        let second = b"second stream\n".repeat(1000);
+        let mut compressed = compress(&first, 1);
+        compressed.extend(compress(&[], 9));
+        compressed.extend(compress(&second, 9));
+
+        let mut expected = first;
+        expected.extend(second);
+        assert_eq!(decode(&compressed).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_crc_mismatches() {
+        let mut compressed = compress(&random_data(600_000), 1);
+        // the CRC of the first block follows the stream header and the block magic
+        compressed[10] ^= 1;
+
+        let err = decode(&compressed).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("could not be decoded"), "{}", err);
+    }
+}
@@ -0,0 +1,239 @@
+//! A minimal HTTP server for testing `HttpDataInput`, which can misbehave in various ways.
+//!
+//! It can also stand in for other HTTP APIs by serving fixed responses for some paths.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const ETAG: &str = "\"fixture\"";
+const LAST_MODIFIED: &str = "Sat, 02 Jan 2021 12:00:00 GMT";
+/// Where the data is served, which is where the latest dump is in a dumps directory.
+const DATA_PATH: &str = "/latest-all.json.bz2";
+
+/// How the server responds to a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Responds normally.
+    None,
+    /// Closes the connection after sending this many bytes of the body.
+    DropAfter(usize),
+    /// Sends all data with status 200, as if the Range header wasn't supported.
+    IgnoreRange,
+    /// Starts the response this many bytes before the requested offset.
+    EarlierRange(u64),
+    /// Starts the response this many bytes after the requested offset.
+    LaterRange(u64),
+    /// Responds with a different etag.
+    ChangeEtag,
+    /// Responds with the given status code and no data.
+    Status(u16),
+}
+
+pub struct TestServer {
+    pub url: String,
+    state: Arc<Mutex<ServerState>>,
+}
+
+struct ServerState {
+    data: Vec<u8>,
+    faults: Vec<Fault>,
+    then: Fault,
+    /// The requested start offset of each request.
+    requests: Vec<Option<u64>>,
+    /// Responses to requests for each path (without the query), in order. The last response
+    /// is repeated. Other paths than that of the data are not found if there are any routes.
+    routes: HashMap<String, Vec<Vec<u8>>>,
+    /// The request target (path and query) of each request.
+    targets: Vec<String>,
+}
+
+impl TestServer {
+    /// Serves the data, responding to the n-th request with the n-th fault, and with `then` once
+    /// they run out.
+    pub fn start(data: Vec<u8>, faults: Vec<Fault>, then: Fault) -> Self {
+        Self::start_with_routes(data, faults, then, HashMap::new())
+    }
+
+    /// Serves the given responses for each path, and responds to other paths with 404.
+    pub fn routes(routes: HashMap<String, Vec<Vec<u8>>>) -> Self {
+        Self::start_with_routes(Vec::new(), Vec::new(), Fault::None, routes)
+    }
+
+    /// Serves the data like [`TestServer::start`], and the given responses for other paths like
+    /// [`TestServer::routes`].
+    pub fn start_with_routes(
+        data: Vec<u8>,
+        faults: Vec<Fault>,
+        then: Fault,
+        routes: HashMap<String, Vec<Vec<u8>>>,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind test server");
+        let url = format!(
+            "http://{}{}",
+            listener.local_addr().expect("no local address"),
+            DATA_PATH
+        );
+        let state = Arc::new(Mutex::new(ServerState {
+            data,
+            faults,
+            then,
+            requests: Vec::new(),
+            routes,
+            targets: Vec::new(),
+        }));
+
+        let state2 = Arc::clone(&state);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let state = Arc::clone(&state2);
+                // the client may not read a response to the end, so don't block on it
+                thread::spawn(move || {
+                    let _ = handle(stream, &state);
+                });
+            }
+        });
+
+        TestServer { url, state }
+    }
+
+    /// Returns the requested start offset of each request so far.
+    pub fn requests(&self) -> Vec<Option<u64>> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Returns the request target (path and query) of each request so far.
+    pub fn targets(&self) -> Vec<String> {
+        self.state.lock().unwrap().targets.clone()
+    }
+
+    /// Returns the URL of a path on the server.
+    pub fn url_of(&self, path: &str) -> String {
+        let base = self.url.trim_end_matches(DATA_PATH);
+        format!("{}{}", base, path)
+    }
+}
+
+fn handle(mut stream: TcpStream, state: &Mutex<ServerState>) -> std::io::Result<()> {
+    let mut range = None;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let target = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_string();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let line = line.trim().to_ascii_lowercase();
+        if let Some(value) = line.strip_prefix("range: bytes=") {
+            range = value.trim_end_matches('-').parse::<u64>().ok();
+        }
+    }
+
+    let route = {
+        let mut state = state.lock().unwrap();
+        state.targets.push(target.clone());
+        let path = target.split('?').next().unwrap_or_default();
+        if state.routes.is_empty() || path == DATA_PATH {
+            None
+        } else {
+            Some(state.routes.get_mut(path).map(|responses| {
+                if responses.len() > 1 {
+                    responses.remove(0)
+                } else {
+                    responses[0].clone()
+                }
+            }))
+        }
+    };
+    match route {
+        Some(Some(body)) => {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )?;
+            return stream.write_all(&body);
+        }
+        Some(None) => {
+            write!(
+                stream,
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )?;
+            return Ok(());
+        }
+        None => (),
+    }
+
+    let (data, fault) = {
+        let mut state = state.lock().unwrap();
+        let fault = state
+            .faults
+            .get(state.requests.len())
+            .copied()
+            .unwrap_or(state.then);
+        state.requests.push(range);
+        (state.data.clone(), fault)
+    };
+    let total = data.len() as u64;
+
+    let start = match (range, fault) {
+        (_, Fault::Status(status)) => {
+            write!(
+                stream,
+                "HTTP/1.1 {} Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )?;
+            return Ok(());
+        }
+        (None, _) | (_, Fault::IgnoreRange) => None,
+        (Some(start), Fault::EarlierRange(diff)) => Some(start.saturating_sub(diff)),
+        (Some(start), Fault::LaterRange(diff)) => Some((start + diff).min(total)),
+        (Some(start), _) => Some(start),
+    };
+    let etag = if fault == Fault::ChangeEtag {
+        "\"changed\""
+    } else {
+        ETAG
+    };
+
+    let body = &data[start.unwrap_or(0) as usize..];
+    match start {
+        Some(start) => write!(
+            stream,
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+            start,
+            total.saturating_sub(1),
+            total
+        )?,
+        None => write!(stream, "HTTP/1.1 200 OK\r\n")?,
+    }
+    write!(
+        stream,
+        "ETag: {}\r\nLast-Modified: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        etag,
+        LAST_MODIFIED,
+        body.len()
+    )?;
+
+    match fault {
+        Fault::DropAfter(len) => {
+            stream.write_all(&body[..len.min(body.len())])?;
+            stream.flush()?;
+            stream.shutdown(Shutdown::Both)?;
+        }
+        _ => stream.write_all(body)?,
+    }
+    Ok(())
+}
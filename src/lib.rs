@@ -0,0 +1,22 @@
+//! Extracts a database of cities from a Wikidata dump.
+
+#[macro_use]
+extern crate log;
+
+pub mod database;
+pub mod index;
+pub mod input;
+pub mod places;
+pub mod post;
+pub mod prefilter;
+pub mod rules;
+#[cfg(test)]
+mod test_util;
+pub mod update;
+pub mod wiki_data_line;
+pub mod wiki_entity;
+pub mod wiki_quantity;
+pub mod wiki_sparql;
+pub mod wiki_time;
+pub mod wiki_triples;
+pub mod wikibase;
@@ -1,18 +1,55 @@
 #[macro_use]
 extern crate log;
 
-use crate::input::DataInput;
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgGroup, ArgMatches, SubCommand};
+use crossbeam::channel::{Receiver, Sender};
+use crossbeam::sync::WaitGroup;
+use geo_db::database::{self, Checkpoint, DataEntry};
+use geo_db::input::cache::CacheFile;
+use geo_db::input::checksum::{self, ExpectedChecksum, RollingHash};
+use geo_db::input::{self, DataInput, InputLineIter, LineRecycler, ResumePosition};
+use geo_db::places::{PlaceGraph, ReachablePlaces};
+use geo_db::prefilter::Prefilter;
+use geo_db::rules::Rules;
+use geo_db::wiki_data_line::{self, EntityFormat};
+use geo_db::wiki_sparql::Classes;
+use geo_db::wikibase::Wikibase;
+use geo_db::{index, post, update, wiki_sparql, wiki_triples};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-mod database;
-mod input;
-mod json;
-mod post;
-mod wiki_data_line;
-mod wiki_sparql;
-mod wiki_time;
+/// Parses a numeric argument.
+fn number_arg<T>(matches: &ArgMatches, name: &str) -> T
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = matches.value_of(name).expect("missing default value");
+    match value.parse() {
+        Ok(value) => value,
+        Err(e) => {
+            error!(
+                "Invalid value {} for --{}: {}",
+                value,
+                name.replace('_', "-"),
+                e
+            );
+            exit(-1);
+        }
+    }
+}
+
+/// Parses an argument that is a number of seconds.
+fn secs_arg(matches: &ArgMatches, name: &str) -> Duration {
+    Duration::from_secs(number_arg(matches, name))
+}
 
 fn main() {
     let matches = App::new("geo-db")
@@ -25,6 +62,149 @@ fn main() {
                 .takes_value(true)
                 .default_value("geo.db"),
         )
+        .arg(
+            Arg::with_name("input")
+                .short("i")
+                .long("input")
+                .help("Reads the dump from a local file instead of downloading it (- for stdin)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dump_date")
+                .long("dump-date")
+                .help("Downloads the dump from the given date (YYYYMMDD) instead of the latest one")
+                .takes_value(true)
+                .conflicts_with("input"),
+        )
+        .arg(
+            Arg::with_name("dump_url")
+                .long("dump-url")
+                .help("Downloads the dump from the given URL")
+                .takes_value(true)
+                .conflicts_with_all(&["input", "dump_date"]),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .help("Sets the format of the dump: the full JSON dump, or the much smaller truthy N-Triples dump, which has no qualifiers (detected from the file name by default)")
+                .takes_value(true)
+                .possible_values(&["json", "nt"]),
+        )
+        .arg(
+            Arg::with_name("mirror")
+                .long("mirror")
+                .help("Adds a mirror of the dump to fail over to if downloading fails")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with("input")
+                .requires("checksum"),
+        )
+        .arg(
+            Arg::with_name("retry_interval")
+                .long("retry-interval")
+                .help("Sets the number of seconds to wait before reconnecting for the first time")
+                .takes_value(true)
+                .default_value("2"),
+        )
+        .arg(
+            Arg::with_name("retry_max_interval")
+                .long("retry-max-interval")
+                .help("Sets the maximum number of seconds to wait between reconnection attempts")
+                .takes_value(true)
+                .default_value("300"),
+        )
+        .arg(
+            Arg::with_name("retry_multiplier")
+                .long("retry-multiplier")
+                .help("Sets the factor the time between reconnection attempts grows by")
+                .takes_value(true)
+                .default_value("2"),
+        )
+        .arg(
+            Arg::with_name("retry_jitter")
+                .long("retry-jitter")
+                .help("Sets the fraction (0 to 1) by which the time between reconnection attempts is randomly varied")
+                .takes_value(true)
+                .default_value("0.25"),
+        )
+        .arg(
+            Arg::with_name("retry_budget")
+                .long("retry-budget")
+                .help("Sets the number of seconds to keep trying to reconnect before giving up")
+                .takes_value(true)
+                .default_value("1800"),
+        )
+        .arg(
+            Arg::with_name("cache")
+                .long("cache")
+                .help("Saves the downloaded dump to a file, which can later be used with --input")
+                .takes_value(true)
+                .conflicts_with("input"),
+        )
+        .arg(
+            Arg::with_name("checksum")
+                .long("checksum")
+                .help("Verifies the dump against an md5sums or sha1sums file (path or URL)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("Continues an interrupted build from the checkpoint in the output file"),
+        )
+        .arg(
+            Arg::with_name("checkpoint_interval")
+                .long("checkpoint-interval")
+                .help("Sets the number of seconds between checkpoints")
+                .takes_value(true)
+                .default_value("300"),
+        )
+        .arg(
+            Arg::with_name("parsers")
+                .long("parsers")
+                .help("Sets the number of threads parsing lines (defaults to the number of CPUs)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("line_queue")
+                .long("line-queue")
+                .help("Sets the number of lines that may wait to be parsed")
+                .takes_value(true)
+                .default_value("4096"),
+        )
+        .arg(
+            Arg::with_name("entry_queue")
+                .long("entry-queue")
+                .help("Sets the number of entries that may wait to be written to the database")
+                .takes_value(true)
+                .default_value("65536"),
+        )
+        .arg(
+            Arg::with_name("no_prefilter")
+                .long("no-prefilter")
+                .help("Fully parses every entity of the JSON dump instead of first skipping those that can’t be extracted"),
+        )
+        .arg(
+            Arg::with_name("two_pass")
+                .long("two-pass")
+                .help("Reads the dump twice to only write the territorial entities that cities are in, which keeps the database much smaller")
+                .requires("input"),
+        )
+        .arg(
+            Arg::with_name("wikibase")
+                .long("wikibase")
+                .help("Builds from another Wikibase instance, configured in the given JSON file")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("rules")
+                .long("rules")
+                .help("Uses the extraction rules in the given JSON file instead of the default rules")
+                .takes_value(true)
+                .global(true),
+        )
         .arg(
             Arg::with_name("verbose")
                 .short("v")
@@ -41,6 +221,49 @@ fn main() {
                         .takes_value(true)
                         .multiple(true)
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("from_dump")
+                        .help("reads the entities from a local dump indexed with the index command instead of fetching them from wikidata.org")
+                        .long("from-dump")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("index")
+                        .help("the index file of the dump (defaults to the dump path with .index appended)")
+                        .long("index")
+                        .takes_value(true)
+                        .requires("from_dump"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("index")
+                .about("records the position of every entity in a local bzip2 dump, for entity --from-dump")
+                .arg(
+                    Arg::with_name("dump")
+                        .help("the dump file")
+                        .index(1)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("index")
+                        .help("the index file (defaults to the dump path with .index appended)")
+                        .long("index")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-bz2")
+                .about(
+                    "checks that the parallel bzip2 decoder produces the same output as libbzip2",
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .help("the .bz2 file")
+                        .index(1)
+                        .takes_value(true)
+                        .required(true),
                 ),
         )
         .subcommand(
@@ -64,6 +287,35 @@ fn main() {
                         .long("no-cleanup"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("update")
+                .about("replaces the data of changed entities in a database that has not been cleaned up")
+                .arg(
+                    Arg::with_name("database")
+                        .help("the database file")
+                        .index(1)
+                        .takes_value(true)
+                        .default_value("geo.db"),
+                )
+                .arg(
+                    Arg::with_name("ids")
+                        .help("a file with the IDs of changed entities, one per line (- for stdin)")
+                        .long("ids")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("since")
+                        .help("updates the items that have changed since this time (e.g. 2021-01-01T00:00:00Z), according to the recent changes of the wiki; items that are not in the database yet, e.g. new cities, are skipped")
+                        .long("since")
+                        .takes_value(true),
+                )
+                .group(
+                    ArgGroup::with_name("changes")
+                        .args(&["ids", "since"])
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
         .get_matches();
 
     let colors = fern::colors::ColoredLevelConfig::new();
@@ -87,14 +339,70 @@ fn main() {
         .apply()
         .unwrap();
 
+    let wikibase = match matches.value_of("wikibase") {
+        Some(path) => match Wikibase::load(path) {
+            Ok(wikibase) => wikibase,
+            Err(e) => {
+                error!("Failed to load {}: {}", path, e);
+                exit(-1);
+            }
+        },
+        None => Wikibase::default(),
+    };
+    let rules = match matches.value_of("rules") {
+        Some(path) => match Rules::load(path) {
+            Ok(rules) => rules,
+            Err(e) => {
+                error!("Failed to load {}: {}", path, e);
+                exit(-1);
+            }
+        },
+        None => Rules::default(),
+    };
+
     match matches.subcommand() {
         ("entity", Some(args)) => {
             let ids = args.values_of("entity").expect("no entity id");
-            match debug_entities(ids) {
+            let dump_index = match args.value_of("from_dump") {
+                Some(dump) => {
+                    let index_file = index_path(dump, args.value_of("index"));
+                    match index::DumpIndex::open(dump, &index_file) {
+                        Ok(dump_index) => Some(dump_index),
+                        Err(e) => {
+                            error!("Could not open index {}: {}", index_file, e);
+                            exit(-1);
+                        }
+                    }
+                }
+                None => None,
+            };
+            match debug_entities(ids, &wikibase, &rules, dump_index.as_ref()) {
                 Ok(()) => {}
                 Err(e) => error!("{}", e),
             }
         }
+        ("index", Some(args)) => {
+            let dump = args.value_of("dump").expect("no dump");
+            let index_file = index_path(dump, args.value_of("index"));
+            match index::build(dump, &index_file) {
+                Ok(()) => info!("Done!"),
+                Err(e) => {
+                    error!("{}", e);
+                    exit(-1);
+                }
+            }
+        }
+        ("verify-bz2", Some(args)) => {
+            let file = args.value_of("file").expect("no file");
+            match input::par_bz2::verify_decoders(file) {
+                Ok(true) => info!("Done!"),
+                Ok(false) => exit(-1),
+                Err(e) => {
+                    error!("{}", e);
+                    exit(-1);
+                }
+            }
+        }
         ("post", Some(args)) => {
             let db_file = args.value_of("database").expect("no database file");
             let only_cleanup = args.is_present("only_cleanup");
@@ -108,54 +416,526 @@ fn main() {
                 (false, true) => (true, false),
                 (false, false) => (true, true),
             };
+            if !Path::new(db_file).exists() {
+                error!("{} does not exist", db_file);
+                exit(-1);
+            }
+            if do_post {
+                // the table is dropped during cleanup, so it's fine if it doesn't exist
+                if let Ok(Some(checkpoint)) = database::load_checkpoint(db_file) {
+                    if !checkpoint.complete {
+                        error!("{} is from an incomplete or failed build", db_file);
+                        exit(-1);
+                    }
+                }
+            }
             match post::run(db_file, do_post, do_cleanup) {
                 Ok(()) => {}
                 Err(e) => error!("{}", e),
             }
         }
+        ("update", Some(args)) => {
+            let db_file = args.value_of("database").expect("no database file");
+            let mut ids = Vec::new();
+            if let Some(path) = args.value_of("ids") {
+                match update::read_ids(path) {
+                    Ok(file_ids) => ids.extend(file_ids),
+                    Err(e) => {
+                        error!("Failed to read {}: {}", path, e);
+                        exit(-1);
+                    }
+                }
+            }
+            // the recent changes include every item, so only update those in the database
+            let only_known = if let Some(since) = args.value_of("since") {
+                info!("Loading recent changes");
+                let changes = update::client()
+                    .map_err(update::UpdateError::from)
+                    .and_then(|client| update::recent_changes(&client, &wikibase, since));
+                match changes {
+                    Ok(changes) => {
+                        let new: Vec<_> =
+                            changes.into_iter().filter(|id| !ids.contains(id)).collect();
+                        ids.extend(new);
+                        args.value_of("ids").is_none()
+                    }
+                    Err(e) => {
+                        error!("Failed to load recent changes: {}", e);
+                        exit(-1);
+                    }
+                }
+            } else {
+                false
+            };
+
+            info!("Loading classes");
+            let classes = match wiki_sparql::Classes::new_from_http(&wikibase, &rules) {
+                Ok(classes) => classes,
+                Err(e) => {
+                    error!("Failed to load classes: {}", e);
+                    exit(-1);
+                }
+            };
+            match update::run(db_file, &ids, only_known, &wikibase, &rules, &classes) {
+                Ok(stats) => {
+                    info!(
+                        "Updated {} entities, deleted {}",
+                        stats.updated, stats.deleted
+                    );
+                    if !stats.failed.is_empty() {
+                        error!(
+                            "Failed to fetch {} entities: {}",
+                            stats.failed.len(),
+                            stats.failed.join(" ")
+                        );
+                        exit(-1);
+                    }
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    exit(-1);
+                }
+            }
+        }
         _ => {
             let out_file = matches.value_of("out").expect("no output file");
-            run(out_file.into());
+            let checkpoint_interval = secs_arg(&matches, "checkpoint_interval");
+            let parsers = match matches.value_of("parsers") {
+                Some(_) => number_arg(&matches, "parsers"),
+                None => std::thread::available_parallelism().map_or(4, |n| n.get()),
+            };
+            let line_queue = number_arg(&matches, "line_queue");
+            let entry_queue = number_arg(&matches, "entry_queue");
+            let retry = input::http::RetryConfig {
+                initial_interval: secs_arg(&matches, "retry_interval"),
+                max_interval: secs_arg(&matches, "retry_max_interval"),
+                multiplier: number_arg(&matches, "retry_multiplier"),
+                jitter: number_arg(&matches, "retry_jitter"),
+                budget: secs_arg(&matches, "retry_budget"),
+            };
+            if !(1. ..).contains(&retry.multiplier) || !(0. ..=1.).contains(&retry.jitter) {
+                error!("--retry-multiplier must be at least 1 and --retry-jitter between 0 and 1");
+                exit(-1);
+            }
+            let format = match matches.value_of("format") {
+                Some("nt") => EntityFormat::Truthy,
+                Some(_) => EntityFormat::Json,
+                None => matches
+                    .value_of("input")
+                    .or_else(|| matches.value_of("dump_url"))
+                    .map_or(EntityFormat::Json, EntityFormat::from_file_name),
+            };
+            // only the latest truthy dump is named without -BETA
+            let (latest_name, dated_name) = match format {
+                EntityFormat::Json => ("all.json.bz2", "all.json.bz2"),
+                EntityFormat::Truthy => ("truthy.nt.bz2", "truthy-BETA.nt.bz2"),
+            };
+            let dump_url = match (
+                matches.value_of("dump_url"),
+                matches.value_of("dump_date"),
+                &wikibase.dumps_url,
+            ) {
+                (Some(url), _, _) => url.to_string(),
+                (None, Some(date), Some(dumps_url)) => {
+                    if date.len() != 8 || chrono::NaiveDate::parse_from_str(date, "%Y%m%d").is_err()
+                    {
+                        error!("Invalid dump date {} (expected YYYYMMDD)", date);
+                        exit(-1);
+                    }
+                    format!(
+                        "{0}/{1}/{2}-{1}-{3}",
+                        dumps_url, date, wikibase.dump_name, dated_name
+                    )
+                }
+                (None, None, Some(dumps_url)) => format!("{}/latest-{}", dumps_url, latest_name),
+                (None, _, None) => String::new(),
+            };
+            let source = match matches.value_of("input") {
+                Some(path) => path,
+                None if dump_url.is_empty() => {
+                    error!("The Wikibase config has no dumps_url; pass --input or --dump-url");
+                    exit(-1);
+                }
+                None => &dump_url,
+            };
+
+            let checkpoint = if matches.is_present("resume") {
+                let checkpoint = match database::load_checkpoint(out_file) {
+                    Ok(Some(checkpoint)) => checkpoint,
+                    Ok(None) => {
+                        error!("No checkpoint found in {}", out_file);
+                        exit(-1);
+                    }
+                    Err(e) => {
+                        error!("Failed to load checkpoint from {}: {}", out_file, e);
+                        exit(-1);
+                    }
+                };
+                if checkpoint.complete {
+                    error!("The build in {} is already complete", out_file);
+                    exit(-1);
+                }
+                if checkpoint.source != source {
+                    error!(
+                        "The build in {} was started from {}, not {}",
+                        out_file, checkpoint.source, source
+                    );
+                    exit(-1);
+                }
+                Some(checkpoint)
+            } else {
+                if Path::new(out_file).exists() {
+                    error!(
+                        "{} already exists; use --resume to continue an interrupted build",
+                        out_file
+                    );
+                    exit(-1);
+                }
+                None
+            };
+
+            let checksum = matches.value_of("checksum").map(|location| {
+                let name = checksum::listed_name(
+                    source,
+                    location,
+                    &wikibase.dump_name,
+                    latest_name,
+                    dated_name,
+                );
+                match ExpectedChecksum::load(location, &name) {
+                    Ok(checksum) => checksum,
+                    Err(e) => {
+                        error!("Failed to load checksum: {}", e);
+                        exit(-1);
+                    }
+                }
+            });
+            let hash = checksum.as_ref().map(|checksum| {
+                let mut hash = RollingHash::new(checksum.kind);
+                if let Some(checkpoint) = &checkpoint {
+                    // the hash also has to cover the data before the checkpoint
+                    let prefix_path = match (matches.value_of("input"), matches.value_of("cache")) {
+                        (Some(path), _) => PathBuf::from(path),
+                        (None, Some(path)) => CacheFile::part_path(Path::new(path)),
+                        (None, None) => {
+                            error!(
+                                "Can’t verify the checksum of a resumed download without --cache"
+                            );
+                            exit(-1);
+                        }
+                    };
+                    let len = checkpoint.position.byte_offset();
+                    match File::open(&prefix_path).and_then(|file| hash.update_from(file.take(len)))
+                    {
+                        Ok(read) if read == len => (),
+                        Ok(_) => {
+                            error!("{} is missing data", prefix_path.display());
+                            exit(-1);
+                        }
+                        Err(e) => {
+                            error!("Failed to read {}: {}", prefix_path.display(), e);
+                            exit(-1);
+                        }
+                    }
+                }
+                hash
+            });
+
+            info!("Loading classes");
+            let classes = match wiki_sparql::Classes::new_from_http(&wikibase, &rules) {
+                Ok(classes) => classes,
+                Err(e) => {
+                    error!("Failed to fetch classes: {}", e);
+                    exit(-1);
+                }
+            };
+            let prefilter = match format {
+                EntityFormat::Json if !matches.is_present("no_prefilter") => {
+                    Some(Arc::new(Prefilter::new(&wikibase, &rules, &classes)))
+                }
+                _ => None,
+            };
+
+            let mut options = BuildOptions {
+                out_file: out_file.into(),
+                checkpoint,
+                checkpoint_interval,
+                checksum,
+                cache_path: matches.value_of("cache").map(PathBuf::from),
+                parsing: Parsing {
+                    format,
+                    wikibase: Arc::new(wikibase),
+                    rules: Arc::new(rules),
+                    classes: Arc::new(classes),
+                    prefilter,
+                },
+                parsers,
+                line_queue,
+                entry_queue,
+                places: None,
+            };
+
+            match matches.value_of("input") {
+                Some(path) => {
+                    if matches.is_present("two_pass") {
+                        if path == "-" {
+                            error!(
+                                "Can’t read stdin twice; save the dump to a file to use --two-pass"
+                            );
+                            exit(-1);
+                        }
+                        options.places = Some(collect_places(path, &options));
+                    }
+                    let data_input = match &options.checkpoint {
+                        Some(_) if path == "-" => {
+                            error!("Can’t resume reading from stdin");
+                            exit(-1);
+                        }
+                        Some(checkpoint) => {
+                            input::file::AutoFileInput::resume(path, &checkpoint.position)
+                        }
+                        None => input::file::AutoFileInput::open(path),
+                    };
+                    let mut data_input = match data_input {
+                        Ok(data_input) => data_input,
+                        Err(e) => {
+                            error!("Failed to open {}: {}", path, e);
+                            exit(-1);
+                        }
+                    };
+                    if let Some(checkpoint) = &options.checkpoint {
+                        if data_input.etag() != checkpoint.etag {
+                            error!("{} has changed since the checkpoint", path);
+                            exit(-1);
+                        }
+                    }
+                    if let Some(hash) = hash {
+                        data_input.set_hash(hash);
+                    }
+                    run(data_input, source, options);
+                }
+                None => {
+                    let data_input = match &options.checkpoint {
+                        Some(checkpoint) => input::http::HttpAutoDataInput::resume(
+                            dump_url.clone(),
+                            checkpoint.etag.clone(),
+                            checkpoint.length,
+                            checkpoint.position,
+                        ),
+                        None => input::http::HttpAutoDataInput::new(dump_url.clone()),
+                    };
+                    let mirrors = matches
+                        .values_of("mirror")
+                        .map(|mirrors| mirrors.map(String::from).collect())
+                        .unwrap_or_default();
+                    let data_input = data_input.with_mirrors(mirrors).with_retry(retry);
+                    let mut data_input = match matches.value_of("cache") {
+                        Some(path) => data_input.with_cache(path.into()),
+                        None => data_input,
+                    };
+                    if let Some(hash) = hash {
+                        data_input.set_hash(hash);
+                    }
+                    run(data_input, source, options);
+                }
+            }
         }
     }
 }
 
-fn run(out_file: String) {
-    let url = "https://dumps.wikimedia.org/wikidatawiki/entities/latest-all.json.bz2";
-    let db_writer = {
-        let data_input = input::http::HttpBz2DataInput::new(url.into());
-        // let data_input = input::file::Bz2FileInput::new(std::fs::File::open(file).unwrap());
-        let mut lines = input::InputLineIter::new(data_input);
+struct BuildOptions {
+    out_file: String,
+    /// Checkpoint to resume from.
+    checkpoint: Option<Checkpoint>,
+    checkpoint_interval: Duration,
+    /// Checksum to verify the dump against. The input must have been given a hash.
+    checksum: Option<ExpectedChecksum>,
+    /// The file the dump was saved to, which is removed if it does not match the checksum.
+    cache_path: Option<PathBuf>,
+    parsing: Parsing,
+    /// Number of parser threads.
+    parsers: usize,
+    /// Capacity of the queue between the line reader and the parsers.
+    line_queue: usize,
+    /// Capacity of the queue between the parsers and the DB writer.
+    entry_queue: usize,
+    /// The places found in the first pass of a two-pass build, whose entries are the only ones
+    /// written.
+    places: Option<ReachablePlaces>,
+}
 
-        info!("Loading classes");
-        let classes = Arc::new(match wiki_sparql::Classes::new_from_http() {
-            Ok(classes) => classes,
-            Err(e) => {
-                error!("Failed to fetch classes: {}", e);
-                exit(-1);
+/// What the parser threads need to handle lines.
+struct Parsing {
+    format: EntityFormat,
+    wikibase: Arc<Wikibase>,
+    rules: Arc<Rules>,
+    classes: Arc<Classes>,
+    /// Skips entities of the JSON dump that can’t produce entries before parsing them.
+    prefilter: Option<Arc<Prefilter>>,
+}
+
+/// A line (or the triples of an entity) waiting to be parsed.
+struct LineJob {
+    line: String,
+    number: u64,
+    offset: u64,
+    /// Dropped once the line has been handled.
+    _in_flight: WaitGroup,
+}
+
+impl LineJob {
+    fn new(line: String, start: LineStart, in_flight: &WaitGroup) -> Self {
+        LineJob {
+            line,
+            number: start.number + 1,
+            offset: start.offset,
+            _in_flight: in_flight.clone(),
+        }
+    }
+}
+
+/// The point before a line, from which reading can be resumed.
+#[derive(Debug, Clone, Copy)]
+struct LineStart {
+    position: Option<ResumePosition>,
+    offset: u64,
+    /// Number of lines before it.
+    number: u64,
+}
+
+fn line_start<I>(lines: &InputLineIter<I>, line_number: u64) -> LineStart
+where
+    I: DataInput + Send + 'static,
+    I::Error: Send + 'static,
+{
+    LineStart {
+        position: lines.resume_position(),
+        offset: lines.line_offset,
+        number: line_number,
+    }
+}
+
+/// Collects the triples of an entity in the truthy dump until the subject changes.
+#[derive(Default)]
+struct EntityTriples {
+    subject: String,
+    triples: String,
+    /// Start of the first triple, if there are any.
+    start: Option<LineStart>,
+}
+
+impl EntityTriples {
+    /// Adds a triple. Returns the previous entity if the triple starts a new one.
+    fn push(
+        &mut self,
+        line: &str,
+        start: LineStart,
+        wikibase: &Wikibase,
+    ) -> Option<(String, LineStart)> {
+        let mut previous = None;
+        if let Some(subject) = wiki_triples::entity_subject(line, wikibase) {
+            if subject != self.subject {
+                previous = self.take();
+                self.subject.clear();
+                self.subject.push_str(subject);
+                self.start = Some(start);
             }
-        });
+        }
+        // triples about other subjects (e.g. sitelinks) are kept with the entity they appear in
+        if self.start.is_some() {
+            self.triples.push_str(line);
+            self.triples.push('\n');
+        }
+        previous
+    }
 
-        info!("Streaming data from {} to {}", url, out_file);
+    fn take(&mut self) -> Option<(String, LineStart)> {
+        let start = self.start.take()?;
+        Some((std::mem::take(&mut self.triples), start))
+    }
+}
 
-        let (send, recv) = crossbeam::channel::unbounded();
+fn run<I>(data_input: I, source: &str, options: BuildOptions)
+where
+    I: DataInput + Send + 'static,
+    I::Error: fmt::Display + Send + 'static,
+{
+    let BuildOptions {
+        out_file,
+        checkpoint,
+        checkpoint_interval,
+        checksum,
+        cache_path,
+        parsing,
+        parsers,
+        line_queue,
+        entry_queue,
+        places,
+    } = options;
+    let resume = checkpoint.is_some();
+    let format = parsing.format;
+    let wikibase = Arc::clone(&parsing.wikibase);
+    let mut complete = false;
+    let mut failed = false;
+    // whether the dump did not match its checksum
+    let mut corrupted = false;
+    let mut can_checkpoint = true;
 
-        let db_writer = std::thread::spawn(move || match database::db_writer(&out_file, recv) {
-            Ok(()) => (),
-            Err(e) => {
-                error!("database writer exited with error: {}", e);
-                exit(-1);
+    let db_writer = {
+        let (mut lines, mut line_number) = match checkpoint {
+            Some(checkpoint) => {
+                match InputLineIter::resume(
+                    data_input,
+                    &checkpoint.position,
+                    checkpoint.line_offset,
+                ) {
+                    Ok(lines) => {
+                        info!("Resuming at line {}", checkpoint.line_number);
+                        (lines, checkpoint.line_number)
+                    }
+                    Err(e) => {
+                        error!("Failed to resume: {}", e);
+                        exit(-1);
+                    }
+                }
+            }
+            None => (InputLineIter::new(data_input), 0),
+        };
+
+        info!("Streaming data from {} to {}", source, out_file);
+
+        let (send, recv) = crossbeam::channel::bounded(entry_queue);
+        let (line_send, line_recv) = crossbeam::channel::bounded::<LineJob>(line_queue);
+
+        let db_writer = std::thread::spawn({
+            let out_file = out_file.clone();
+            move || match database::db_writer(&out_file, resume, places.as_ref(), recv) {
+                Ok(()) => (),
+                Err(e) => {
+                    error!("database writer exited with error: {}", e);
+                    exit(-1);
+                }
             }
         });
 
+        let parsers = spawn_parsers(&parsing, parsers, &line_recv, &lines.recycler(), &send);
+        drop(line_recv);
+
         let (cancel_send, cancel_recv) = crossbeam::channel::bounded(3);
         ctrlc::set_handler(move || cancel_send.send(()).unwrap())
             .expect("could not set interrupt handler");
 
-        let mut last_time = std::time::Instant::now();
-        let mut last_bytes = 0;
-        let mut last_dec_bytes = 0;
-        let mut line_number = 0;
+        // lines that are still being handled
+        let mut in_flight = WaitGroup::new();
+        // the truthy dump is handled one entity at a time
+        let mut entity = EntityTriples::default();
+        let recycler = lines.recycler();
+
+        let mut last_time = Instant::now();
+        let mut last_checkpoint = Instant::now();
+        let mut last_bytes = lines.input_bytes_read();
+        let mut last_dec_bytes = lines.bytes_read;
+        let mut sent_dump_info = false;
         loop {
             match cancel_recv.try_recv() {
                 Ok(()) => {
@@ -166,97 +946,463 @@ fn run(out_file: String) {
                 Err(e) => panic!("unexpected error {}", e),
             }
 
-            let line_offset = lines.bytes_read;
-            line_number += 1;
+            let start = line_start(&lines, line_number);
             let line = match lines.next() {
                 Ok(line) => line,
-                Err(input::LineIterError::Eof) => break,
+                Err(input::LineIterError::Eof) => {
+                    complete = true;
+                    break;
+                }
                 Err(e) => {
                     error!("line iterator error: {}", e);
-                    exit(-1);
+                    failed = true;
+                    break;
                 }
             };
+            line_number += 1;
 
-            let sink = send.clone();
-            let classes2 = Arc::clone(&classes);
-            rayon_core::spawn(move || {
-                match wiki_data_line::handle_line(&line, &classes2, &sink, false) {
-                    Ok(()) => (),
-                    Err(e) => error!(
-                        "error handling line {} at offset {}:{}\n\n",
-                        line_number, line_offset, e
-                    ),
-                }
-            });
+            if !sent_dump_info {
+                // the etag is only known once the input has been read from
+                send.send(DataEntry::DumpInfo {
+                    source: source.into(),
+                    etag: lines.etag(),
+                    // e.g. for latest-all.json.bz2
+                    date: dump_date(source, &wikibase.dump_name).or_else(|| lines.modified()),
+                })
+                .expect("DB writer disconnected");
+                sent_dump_info = true;
+            }
+
+            if let Some((line, start)) =
+                entity_job(line, start, format, &mut entity, &wikibase, &recycler)
+            {
+                line_send
+                    .send(LineJob::new(line, start, &in_flight))
+                    .expect("parsers disconnected");
+            }
+
+            if can_checkpoint && last_checkpoint.elapsed() >= checkpoint_interval {
+                let handled = std::mem::replace(&mut in_flight, WaitGroup::new());
+                // lines of an incomplete entity will be read again
+                let start = entity
+                    .start
+                    .unwrap_or_else(|| line_start(&lines, line_number));
+                can_checkpoint = send_checkpoint(&lines, start, handled, source, false, &send);
+                last_checkpoint = Instant::now();
+            }
 
             let elapsed = last_time.elapsed();
             if elapsed.as_secs() > 10 {
                 let bytes_read =
-                    (lines.input.bytes_read() - last_bytes) as f64 / elapsed.as_secs_f64();
+                    (lines.input_bytes_read() - last_bytes) as f64 / elapsed.as_secs_f64();
                 let dec_bytes_read =
                     (lines.bytes_read - last_dec_bytes) as f64 / elapsed.as_secs_f64();
-                let total_bytes = lines.input.content_length().unwrap_or(0);
-                let percent_complete = lines.input.bytes_read() as f64 / total_bytes as f64;
-                let mut eta = (total_bytes - lines.input.bytes_read()) as f64 / bytes_read / 60.;
-                let mut eta_unit = "m";
-                if eta > 60. {
-                    eta /= 60.;
-                    eta_unit = "h";
-
-                    if eta > 24. {
-                        eta /= 24.;
-                        eta_unit = "d 😔";
+
+                if let Some(total_bytes) = lines.content_length() {
+                    let percent_complete = lines.input_bytes_read() as f64 / total_bytes as f64;
+                    let mut eta = total_bytes.saturating_sub(lines.input_bytes_read()) as f64
+                        / bytes_read
+                        / 60.;
+                    let mut eta_unit = "m";
+                    if eta > 60. {
+                        eta /= 60.;
+                        eta_unit = "h";
+
+                        if eta > 24. {
+                            eta /= 24.;
+                            eta_unit = "d 😔";
+                        }
                     }
-                }
 
-                info!(
-                    "{:02.2}% (ETA: {:.1}{}) | {:.2} MB of {:.2} MB at {:.2} MB/s ({:.2} MB/s data)",
-                    percent_complete * 100.,
-                    eta,
-                    eta_unit,
-                    lines.input.bytes_read() as f64 / 1000_000.,
-                    total_bytes as f64 / 1000_000.,
-                    bytes_read / 1000_000.,
-                    dec_bytes_read / 1000_000.,
-                );
-                last_bytes = lines.input.bytes_read();
+                    info!(
+                        "{:02.2}% (ETA: {:.1}{}) | {:.2} MB of {:.2} MB at {:.2} MB/s ({:.2} MB/s data) | queued: {} lines, {} entries",
+                        percent_complete * 100.,
+                        eta,
+                        eta_unit,
+                        lines.input_bytes_read() as f64 / 1_000_000.,
+                        total_bytes as f64 / 1_000_000.,
+                        bytes_read / 1_000_000.,
+                        dec_bytes_read / 1_000_000.,
+                        line_send.len(),
+                        send.len(),
+                    );
+                } else {
+                    info!(
+                        "{:.2} MB at {:.2} MB/s ({:.2} MB/s data) | queued: {} lines, {} entries",
+                        lines.input_bytes_read() as f64 / 1_000_000.,
+                        bytes_read / 1_000_000.,
+                        dec_bytes_read / 1_000_000.,
+                        line_send.len(),
+                        send.len(),
+                    );
+                }
+                last_bytes = lines.input_bytes_read();
                 last_dec_bytes = lines.bytes_read;
-                last_time = std::time::Instant::now();
+                last_time = Instant::now();
+            }
+        }
+
+        if complete {
+            if let Some((line, start)) = entity.take() {
+                line_send
+                    .send(LineJob::new(line, start, &in_flight))
+                    .expect("parsers disconnected");
             }
         }
 
+        // also makes sure that everything is flushed
+        let mut input = lines.finish();
+
+        if complete {
+            if let Some(checksum) = &checksum {
+                let hash = input.take_hash().expect("no hash").finish();
+                if hash == checksum.hex {
+                    info!("Checksum matches");
+                } else {
+                    error!(
+                        "The dump does not match its checksum (expected {}, got {}); the database must not be used",
+                        checksum.hex, hash
+                    );
+                    complete = false;
+                    failed = true;
+                    corrupted = true;
+                    // resuming won't help
+                    can_checkpoint = false;
+
+                    if let Some(path) = &cache_path {
+                        match std::fs::remove_file(path) {
+                            Ok(()) => warn!("Removed {} because it is corrupted", path.display()),
+                            Err(e) => error!("Failed to remove {}: {}", path.display(), e),
+                        }
+                    }
+                }
+            }
+        }
+
+        if can_checkpoint {
+            let start = entity
+                .start
+                .unwrap_or_else(|| line_start(&lines, line_number));
+            can_checkpoint = send_checkpoint(&lines, start, in_flight, source, complete, &send);
+        }
+
+        drop(line_send);
+        for parser in parsers {
+            parser.join().unwrap();
+        }
+
         db_writer
     };
 
     debug!("Waiting for DB writer to join");
     db_writer.join().unwrap();
+
+    if corrupted {
+        // so that it can't be post-processed by accident
+        match std::fs::remove_file(&out_file) {
+            Ok(()) => warn!(
+                "Removed {} because it was built from a corrupted dump",
+                out_file
+            ),
+            Err(e) => error!("Failed to remove {}: {}", out_file, e),
+        }
+    }
+
+    if !complete {
+        if can_checkpoint {
+            info!("Stopped; run again with --resume to continue");
+        }
+        if failed {
+            exit(-1);
+        }
+        return;
+    }
     info!("Done!");
 }
 
-fn debug_entities<'a>(ids: impl Iterator<Item = &'a str>) -> reqwest::Result<()> {
-    info!("Loading classes");
-    let classes = wiki_sparql::Classes::new_from_http()?;
+/// Starts the threads that handle the lines of `jobs` and send their entries to `sink`.
+fn spawn_parsers(
+    parsing: &Parsing,
+    count: usize,
+    jobs: &Receiver<LineJob>,
+    recycler: &LineRecycler,
+    sink: &Sender<DataEntry>,
+) -> Vec<JoinHandle<()>> {
+    (0..count)
+        .map(|_| {
+            let jobs = jobs.clone();
+            let recycler = recycler.clone();
+            let sink = sink.clone();
+            let format = parsing.format;
+            let classes = Arc::clone(&parsing.classes);
+            let wikibase = Arc::clone(&parsing.wikibase);
+            let rules = Arc::clone(&parsing.rules);
+            let prefilter = parsing.prefilter.clone();
+            std::thread::spawn(move || {
+                for mut job in jobs {
+                    if let Some(prefilter) = &prefilter {
+                        if !prefilter.is_relevant(&job.line) {
+                            recycler.recycle(job.line);
+                            continue;
+                        }
+                    }
+                    match wiki_data_line::handle_line_mut(
+                        &mut job.line,
+                        format,
+                        &wikibase,
+                        &rules,
+                        &classes,
+                        &sink,
+                        false,
+                    ) {
+                        Ok(()) => (),
+                        Err(e) => error!(
+                            "error handling line {} at offset {}:{}\n\n",
+                            job.number, job.offset, e
+                        ),
+                    }
+                    recycler.recycle(job.line);
+                }
+            })
+        })
+        .collect()
+}
 
-    for id in ids {
-        let url = format!("https://wikidata.org/wiki/Special:EntityData/{}.json", id);
-        let json: serde_json::Value = match reqwest::blocking::get(url).and_then(|res| res.json()) {
-            Ok(json) => json,
+/// Returns the line to send to the parsers, which for the truthy dump is the previous entity
+/// once a triple of the next one has been read.
+fn entity_job(
+    line: String,
+    start: LineStart,
+    format: EntityFormat,
+    entity: &mut EntityTriples,
+    wikibase: &Wikibase,
+    recycler: &LineRecycler,
+) -> Option<(String, LineStart)> {
+    match format {
+        EntityFormat::Json => Some((line, start)),
+        EntityFormat::Truthy => {
+            let job = entity.push(&line, start, wikibase);
+            recycler.recycle(line);
+            job
+        }
+    }
+}
+
+/// The first pass of a two-pass build: reads a local dump to find the cities and countries, and
+/// the places they are in.
+fn collect_places(path: &str, options: &BuildOptions) -> ReachablePlaces {
+    info!("First pass: finding the places that cities are in");
+    let data_input = match input::file::AutoFileInput::open(path) {
+        Ok(data_input) => data_input,
+        Err(e) => {
+            error!("Failed to open {}: {}", path, e);
+            exit(-1);
+        }
+    };
+    let mut lines = InputLineIter::new(data_input);
+
+    let (send, recv) = crossbeam::channel::bounded(options.entry_queue);
+    let (line_send, line_recv) = crossbeam::channel::bounded::<LineJob>(options.line_queue);
+    let collector = std::thread::spawn(move || PlaceGraph::collect(recv));
+    let parsers = spawn_parsers(
+        &options.parsing,
+        options.parsers,
+        &line_recv,
+        &lines.recycler(),
+        &send,
+    );
+    drop(line_recv);
+    drop(send);
+
+    let in_flight = WaitGroup::new();
+    let mut entity = EntityTriples::default();
+    let recycler = lines.recycler();
+    let mut line_number = 0;
+    let mut last_time = Instant::now();
+    loop {
+        let start = line_start(&lines, line_number);
+        let line = match lines.next() {
+            Ok(line) => line,
+            Err(input::LineIterError::Eof) => break,
+            Err(e) => {
+                error!("line iterator error: {}", e);
+                exit(-1);
+            }
+        };
+        line_number += 1;
+
+        let format = options.parsing.format;
+        if let Some((line, start)) = entity_job(
+            line,
+            start,
+            format,
+            &mut entity,
+            &options.parsing.wikibase,
+            &recycler,
+        ) {
+            line_send
+                .send(LineJob::new(line, start, &in_flight))
+                .expect("parsers disconnected");
+        }
+
+        if last_time.elapsed().as_secs() > 10 {
+            match lines.content_length() {
+                Some(total_bytes) => info!(
+                    "First pass: {:02.2}% | queued: {} lines",
+                    lines.input_bytes_read() as f64 / total_bytes as f64 * 100.,
+                    line_send.len(),
+                ),
+                None => info!(
+                    "First pass: {:.2} MB | queued: {} lines",
+                    lines.input_bytes_read() as f64 / 1_000_000.,
+                    line_send.len(),
+                ),
+            }
+            last_time = Instant::now();
+        }
+    }
+    if let Some((line, start)) = entity.take() {
+        line_send
+            .send(LineJob::new(line, start, &in_flight))
+            .expect("parsers disconnected");
+    }
+    lines.finish();
+
+    drop(line_send);
+    for parser in parsers {
+        parser.join().unwrap();
+    }
+    let places = collector.join().unwrap().reachable();
+    info!(
+        "Found {} cities, countries and places they are in; starting the second pass",
+        places.len()
+    );
+    places
+}
+
+/// Returns the date of a dump from its file name, e.g. `wikidata-20210101-all.json.bz2`.
+fn dump_date(source: &str, dump_name: &str) -> Option<String> {
+    let date = checksum::file_name(source)
+        .strip_prefix(dump_name)?
+        .strip_prefix('-')?
+        .get(..8)?;
+    if date.chars().all(|c| c.is_ascii_digit()) {
+        Some(date.into())
+    } else {
+        None
+    }
+}
+
+/// Waits for all lines sent to the parsers so far to be handled, then sends a checkpoint at
+/// `start` to the DB writer, which will write it after their entries.
+///
+/// Returns false if the input does not support resuming.
+fn send_checkpoint<I>(
+    lines: &InputLineIter<I>,
+    start: LineStart,
+    in_flight: WaitGroup,
+    source: &str,
+    complete: bool,
+    sink: &Sender<DataEntry>,
+) -> bool
+where
+    I: DataInput + Send + 'static,
+    I::Error: Send + 'static,
+{
+    let position = match start.position {
+        Some(position) => position,
+        None => {
+            warn!("Not writing checkpoints because the input can’t be resumed");
+            return false;
+        }
+    };
+
+    debug!("writing checkpoint at line {}", start.number);
+    in_flight.wait();
+    sink.send(DataEntry::Checkpoint(Checkpoint {
+        source: source.into(),
+        etag: lines.etag(),
+        length: lines.content_length(),
+        position,
+        line_offset: start.offset,
+        line_number: start.number,
+        complete,
+    }))
+    .expect("DB writer disconnected");
+    true
+}
+
+/// Returns the path of the index file of a dump.
+fn index_path(dump: &str, index_file: Option<&str>) -> String {
+    match index_file {
+        Some(index_file) => index_file.into(),
+        None => format!("{}.index", dump),
+    }
+}
+
+/// Loads an entity, either from the dump if an index is given or from the Wikibase instance.
+fn load_entity(
+    id: &str,
+    wikibase: &Wikibase,
+    dump_index: Option<&index::DumpIndex>,
+) -> Option<String> {
+    if let Some(dump_index) = dump_index {
+        return match dump_index.entity(id) {
+            Ok(Some(entity)) => Some(entity),
+            Ok(None) => {
+                error!("Entity {} is not in the dump", id);
+                None
+            }
             Err(e) => {
-                error!("Failed to fetch entity {}: {}", id, e);
-                continue;
+                error!("Failed to read entity {} from the dump: {}", id, e);
+                None
             }
         };
-        if let Some(entity) = json
-            .as_object()
-            .and_then(|root| root.get("entities"))
-            .and_then(|entities| entities.as_object())
-            .and_then(|entities| entities.get(id))
-            .and_then(|entity| serde_json::to_string(entity).ok())
-        {
+    }
+
+    let client = match update::client() {
+        Ok(client) => client,
+        Err(e) => {
+            error!("{}", e);
+            return None;
+        }
+    };
+    match update::fetch_entity(&client, wikibase, id) {
+        Ok(Some(entity)) => Some(entity),
+        Ok(None) => {
+            error!("Entity {} does not exist", id);
+            None
+        }
+        Err(e) => {
+            error!("Failed to fetch entity {}: {}", id, e);
+            None
+        }
+    }
+}
+
+fn debug_entities<'a>(
+    ids: impl Iterator<Item = &'a str>,
+    wikibase: &Wikibase,
+    rules: &Rules,
+    dump_index: Option<&index::DumpIndex>,
+) -> reqwest::Result<()> {
+    info!("Loading classes");
+    let classes = wiki_sparql::Classes::new_from_http(wikibase, rules)?;
+
+    for id in ids {
+        if let Some(entity) = load_entity(id, wikibase, dump_index) {
             info!("Entity {}", id);
 
             let (send, recv) = crossbeam::channel::unbounded();
-            match wiki_data_line::handle_line(&entity, &classes, &send, false) {
+            match wiki_data_line::handle_line(
+                &entity,
+                EntityFormat::Json,
+                wikibase,
+                rules,
+                &classes,
+                &send,
+                false,
+            ) {
                 Ok(()) => {}
                 Err(e) => {
                     error!("{}", e);
@@ -266,7 +1412,7 @@ fn debug_entities<'a>(ids: impl Iterator<Item = &'a str>) -> reqwest::Result<()>
             let mut was_empty = true;
             while let Ok(entry) = recv.try_recv() {
                 was_empty = false;
-                if let database::DataEntry::ObjectLabel { .. } = &entry {
+                if let DataEntry::ObjectLabel { .. } = &entry {
                     info!("{}: {:?}", id, entry);
                 } else {
                     info!("{}: {:#?}", id, entry);
@@ -275,15 +1421,71 @@ fn debug_entities<'a>(ids: impl Iterator<Item = &'a str>) -> reqwest::Result<()>
 
             if was_empty {
                 info!("empty output - debug:");
-                if let Err(e) = wiki_data_line::handle_line(&entity, &classes, &send, true) {
+                if let Err(e) = wiki_data_line::handle_line(
+                    &entity,
+                    EntityFormat::Json,
+                    wikibase,
+                    rules,
+                    &classes,
+                    &send,
+                    true,
+                ) {
                     error!("{}", e);
                 }
             }
-        } else {
-            error!("Entity {}: invalid data", id);
         }
     }
 
     info!("Done!");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_triples_by_entity() {
+        let wikibase = Wikibase::default();
+        let lines = [
+            "<http://www.wikidata.org/entity/Q1> <http://www.wikidata.org/prop/direct/P31> <http://www.wikidata.org/entity/Q515> .",
+            "<http://www.wikidata.org/entity/Q1> <http://www.w3.org/2000/01/rdf-schema#label> \"One\"@en .",
+            "<https://en.wikipedia.org/wiki/One> <http://schema.org/about> <http://www.wikidata.org/entity/Q1> .",
+            "<http://www.wikidata.org/entity/Q2> <http://www.w3.org/2000/01/rdf-schema#label> \"Two\"@en .",
+            "<http://www.wikidata.org/entity/Q2> <http://www.wikidata.org/prop/direct/P31> <http://www.wikidata.org/entity/Q515> .",
+        ];
+
+        let mut entity = EntityTriples::default();
+        let mut entities = Vec::new();
+        let mut offset = 0;
+        for (number, line) in lines.iter().enumerate() {
+            let start = LineStart {
+                position: None,
+                offset,
+                number: number as u64,
+            };
+            entities.extend(entity.push(line, start, &wikibase));
+            offset += line.len() as u64 + 1;
+        }
+        entities.extend(entity.take());
+
+        let entities: Vec<_> = entities
+            .iter()
+            .map(|(triples, start)| (triples.as_str(), start.number, start.offset))
+            .collect();
+        let first = lines[..3]
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect::<String>();
+        let second = lines[3..]
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect::<String>();
+        let second_offset = lines[..3].iter().map(|line| line.len() as u64 + 1).sum();
+        assert_eq!(
+            entities,
+            [(first.as_str(), 0, 0), (second.as_str(), 3, second_offset)]
+        );
+        assert!(entity.take().is_none());
+    }
+}
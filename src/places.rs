@@ -0,0 +1,257 @@
+//! The places that cities are in, for builds in two passes.
+//!
+//! Most territorial entities are not a parent of any city, but are only needed if they are. The
+//! first pass collects just the parent relations of places and which of them are cities or
+//! countries. The second pass then writes only the territorial entities, labels, languages and
+//! time zones of those and of the places they are in, which are all that post-processing looks at.
+
+use crate::database::DataEntry;
+use crossbeam::channel::Receiver;
+use std::collections::{HashMap, HashSet};
+
+/// Parent relations between places, with entity IDs numbered in the order they were seen.
+#[derive(Debug, Default)]
+pub struct PlaceGraph {
+    ids: HashMap<Box<str>, u32>,
+    parents: Vec<Vec<u32>>,
+    /// Cities and countries.
+    roots: Vec<u32>,
+}
+
+impl PlaceGraph {
+    /// Collects the entries of the first pass until the channel is disconnected.
+    pub fn collect(recv: Receiver<DataEntry>) -> Self {
+        let mut graph = PlaceGraph::default();
+        for entry in recv {
+            graph.add(&entry);
+        }
+        graph
+    }
+
+    pub fn add(&mut self, entry: &DataEntry) {
+        match entry {
+            DataEntry::City { id, .. } | DataEntry::Country { id, .. } => {
+                let id = self.intern(id);
+                self.roots.push(id);
+            }
+            DataEntry::TerritorialEntityParent { id, parent } => {
+                let id = self.intern(id);
+                let parent = self.intern(parent);
+                self.parents[id as usize].push(parent);
+            }
+            _ => (),
+        }
+    }
+
+    fn intern(&mut self, id: &str) -> u32 {
+        if let Some(&index) = self.ids.get(id) {
+            return index;
+        }
+        let index = self.parents.len() as u32;
+        self.ids.insert(id.into(), index);
+        self.parents.push(Vec::new());
+        index
+    }
+
+    /// Returns the cities and countries, and all places they are in.
+    pub fn reachable(self) -> ReachablePlaces {
+        let mut reachable = vec![false; self.parents.len()];
+        let mut stack = self.roots;
+        while let Some(id) = stack.pop() {
+            if !std::mem::replace(&mut reachable[id as usize], true) {
+                stack.extend(&self.parents[id as usize]);
+            }
+        }
+
+        ReachablePlaces(
+            self.ids
+                .into_iter()
+                .filter(|(_, index)| reachable[*index as usize])
+                .map(|(id, _)| id)
+                .collect(),
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReachablePlaces(HashSet<Box<str>>);
+
+impl ReachablePlaces {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns whether an entry of the second pass should be written.
+    pub fn keeps(&self, entry: &DataEntry) -> bool {
+        match entry {
+            DataEntry::TerritorialEntity { id, .. }
+            | DataEntry::TerritorialEntityParent { id, .. }
+            | DataEntry::ObjectLanguage { id, .. }
+            | DataEntry::ObjectTimeZone { id, .. }
+            | DataEntry::ObjectLabel { id, .. } => self.0.contains(id.as_str()),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database;
+    use crate::post;
+    use crate::rules::Rules;
+    use crate::test_util::{self, classes, statement};
+    use crate::wiki_data_line::{handle_line, EntityFormat};
+    use crate::wikibase::Wikibase;
+    use rusqlite::Connection;
+    use serde_json::{json, Value};
+
+    fn item(id: &str, class: &str, claims: Value) -> String {
+        let mut claims = claims;
+        claims["P31"] = json!([statement(json!({ "id": class }))]);
+        let (de, eo) = (format!("{} de", id), format!("{} eo", id));
+        test_util::item(id, &[("de", &de), ("eo", &eo)], claims).to_string()
+    }
+
+    fn entities() -> Vec<String> {
+        let parent = |id: &str| json!([statement(json!({ "id": id }))]);
+        vec![
+            item(
+                "Q100",
+                "Q34770",
+                json!({ "P424": [statement(json!("de"))] }),
+            ),
+            item(
+                "Q1",
+                "Q6256",
+                json!({ "P297": [statement(json!("DE"))], "P37": parent("Q100") }),
+            ),
+            item(
+                "Q2",
+                "Q10864048",
+                json!({ "P131": parent("Q1"), "P421": parent("Q20") }),
+            ),
+            item("Q3", "Q56061", json!({ "P131": parent("Q2") })),
+            // territorial entities that no city is in
+            item(
+                "Q4",
+                "Q10864048",
+                json!({ "P131": parent("Q1"), "P421": parent("Q21") }),
+            ),
+            item("Q5", "Q56061", json!({ "P131": parent("Q4") })),
+            item(
+                "Q20",
+                "Q12143",
+                json!({ "P6687": [statement(json!("Europe/Berlin"))] }),
+            ),
+            item(
+                "Q10",
+                "Q515",
+                json!({
+                    "P17": parent("Q1"),
+                    "P131": parent("Q3"),
+                    // with a leading zero
+                    "P374": [statement(json!("01053"))],
+                }),
+            ),
+            // a city in a city
+            item(
+                "Q11",
+                "Q515",
+                json!({ "P17": parent("Q1"), "P131": parent("Q10") }),
+            ),
+        ]
+    }
+
+    fn build(name: &str, places: Option<&ReachablePlaces>) -> Connection {
+        let path = std::env::temp_dir().join(format!(
+            "geo-db-places-test-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let (send, recv) = crossbeam::channel::unbounded();
+        for line in entities() {
+            handle_line(
+                &line,
+                EntityFormat::Json,
+                &Wikibase::default(),
+                &Rules::default(),
+                &classes(),
+                &send,
+                false,
+            )
+            .unwrap();
+        }
+        drop(send);
+        database::db_writer(path.to_str().unwrap(), false, places, recv).unwrap();
+        post::run(path.to_str().unwrap(), true, true).unwrap();
+        let conn = Connection::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        conn
+    }
+
+    fn rows(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn
+            .prepare(&format!("select * from {} order by 1, 2", table))
+            .unwrap();
+        let columns = stmt.column_count();
+        let rows = stmt
+            .query_map([], |row| {
+                (0..columns)
+                    .map(|i| Ok(format!("{:?}", row.get::<_, rusqlite::types::Value>(i)?)))
+                    .collect::<rusqlite::Result<Vec<_>>>()
+                    .map(|values| values.join(" "))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        rows
+    }
+
+    #[test]
+    fn keeps_only_reachable_places() {
+        let (send, recv) = crossbeam::channel::unbounded();
+        for line in entities() {
+            handle_line(
+                &line,
+                EntityFormat::Json,
+                &Wikibase::default(),
+                &Rules::default(),
+                &classes(),
+                &send,
+                false,
+            )
+            .unwrap();
+        }
+        drop(send);
+        let places = PlaceGraph::collect(recv).reachable();
+        let mut ids: Vec<_> = places.0.iter().map(|id| id.to_string()).collect();
+        ids.sort();
+        assert_eq!(ids, ["Q1", "Q10", "Q11", "Q2", "Q3"]);
+
+        let all = build("all", None);
+        let reachable = build("reachable", Some(&places));
+        for table in [
+            "cities",
+            "cities_labels",
+            "cities_languages",
+            "cities_external_ids",
+        ] {
+            assert_eq!(rows(&all, table), rows(&reachable, table), "{}", table);
+        }
+        assert!(!rows(&all, "cities").is_empty());
+        assert_eq!(
+            rows(&reachable, "cities_external_ids"),
+            [r#"Text("Q10") Text("P374") Text("01053")"#]
+        );
+        // inherited from Q2
+        assert!(rows(&reachable, "cities")
+            .iter()
+            .all(|row| row.contains("Europe/Berlin")));
+    }
+}
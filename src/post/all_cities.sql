@@ -0,0 +1,3 @@
+-- the post-processing scripts only change cities in affected_cities, which are all of them here
+DROP VIEW IF EXISTS temp.affected_cities;
+CREATE TEMP VIEW affected_cities AS SELECT id FROM cities;
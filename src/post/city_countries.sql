@@ -1,10 +1,12 @@
 -- Remove city countries that do not exist in geo-db (fallen countries usually)
 DELETE FROM cities_countries
-WHERE NOT EXISTS(
-	SELECT 1
-	FROM countries
-	WHERE countries.id = cities_countries.country
-);
+WHERE
+  city IN affected_cities
+  AND NOT EXISTS(
+    SELECT 1
+    FROM countries
+    WHERE countries.id = cities_countries.country
+  );
 
 -- Pick one value for cities.country from cities_countries;
 UPDATE cities
@@ -15,6 +17,7 @@ FROM (
 	(
 		SELECT city, MIN(priority) as prio
 		FROM cities_countries
+		WHERE city IN affected_cities
 		GROUP BY city
 	) cc
 	ON cc.city = cities_countries.city
@@ -1,7 +1,3 @@
-ALTER TABLE cities ADD COLUMN native_label string;
-
-CREATE INDEX cities_native_label_index ON cities (native_label);
-
 UPDATE cities
 SET native_label = labels.full_label
 FROM (
@@ -18,6 +14,7 @@ FROM (
     WHERE
       native_order NOT NULL
       AND native_order <= 1
+      AND c.id IN affected_cities
   ) AS labels_inner
   GROUP BY labels_inner.id
 ) AS labels
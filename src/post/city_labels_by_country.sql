@@ -57,6 +57,7 @@ FROM (
 
   WHERE
     cities.native_label IS NULL
+    AND cities.id IN affected_cities
 ) AS labels
 
 WHERE cities.id = labels.id;
@@ -1,2 +1,5 @@
 DROP TABLE languages;
 DROP TABLE missing_p17;
+DROP TABLE build_checkpoint;
+DROP TABLE object_time_zones;
+DROP TABLE time_zones;
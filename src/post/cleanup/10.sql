@@ -0,0 +1,6 @@
+DELETE FROM population_history
+WHERE NOT EXISTS(
+  SELECT 1
+  FROM cities
+  WHERE cities.id = population_history.id
+);
@@ -0,0 +1,6 @@
+DELETE FROM cities_external_ids
+WHERE NOT EXISTS(
+  SELECT 1
+  FROM cities
+  WHERE cities.id = cities_external_ids.id
+);
@@ -0,0 +1,26 @@
+-- columns filled in by post-processing, which updates expect to exist already
+ALTER TABLE cities ADD COLUMN "2nd_id" string;
+CREATE INDEX cities_2nd_id_index ON cities ("2nd_id");
+
+ALTER TABLE cities ADD COLUMN time_zone string;
+CREATE INDEX cities_time_zone_index ON cities (time_zone);
+
+ALTER TABLE cities ADD COLUMN native_label string;
+
+CREATE INDEX cities_native_label_index ON cities (native_label);
+
+ALTER TABLE cities ADD COLUMN eo_label string;
+
+CREATE INDEX cities_eo_label_index ON cities (eo_label);
+
+ALTER TABLE cities ADD COLUMN "2nd_native_label" string;
+
+CREATE INDEX cities_2nd_native_label_index ON cities ("2nd_native_label");
+
+ALTER TABLE cities ADD COLUMN "2nd_eo_label" string;
+
+CREATE INDEX cities_2nd_eo_label_index ON cities ("2nd_eo_label");
+
+ALTER TABLE cities ADD COLUMN "2nd_iso";
+
+CREATE INDEX "cities_2nd_iso_index" ON cities ("2nd_iso");
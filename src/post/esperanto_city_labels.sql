@@ -1,7 +1,3 @@
-ALTER TABLE cities ADD COLUMN eo_label string;
-
-CREATE INDEX cities_eo_label_index ON cities (eo_label);
-
 UPDATE cities
 SET eo_label = labels.label
 FROM (
@@ -17,5 +13,6 @@ FROM (
 	    LIMIT 1
     ) AS label
   FROM cities
+  WHERE cities.id IN affected_cities
 ) AS labels
 WHERE cities.id = labels.id;
@@ -1,7 +1,3 @@
-ALTER TABLE cities ADD COLUMN "2nd_eo_label" string;
-
-CREATE INDEX cities_2nd_eo_label_index ON cities ("2nd_eo_label");
-
 UPDATE cities
 SET "2nd_eo_label" = labels.label
 FROM (
@@ -17,5 +13,8 @@ FROM (
 	    LIMIT 1
     ) AS label
   FROM cities
+  WHERE cities.id IN affected_cities
 ) AS labels
-WHERE cities."2nd_id" = labels."2nd_id";
+WHERE
+  cities."2nd_id" = labels."2nd_id"
+  AND cities.id IN affected_cities;
@@ -1,6 +1,3 @@
-ALTER TABLE cities ADD COLUMN "2nd_id" string;
-CREATE INDEX cities_2nd_id_index ON cities ("2nd_id");
-
 UPDATE cities
 SET "2nd_id" = data.parent
 FROM (
@@ -31,5 +28,6 @@ FROM (
       ORDER BY step DESC
     ) AS parent
   FROM cities
+  WHERE cities.id IN affected_cities
 ) data
 WHERE cities.id = data.id;
@@ -1,158 +1,119 @@
 use rusqlite::{params, Connection};
 use std::time::Instant;
 
-pub fn run(db_file: &str, do_post: bool, do_cleanup: bool) -> rusqlite::Result<()> {
-    info!(
-        "Opening database at {} (SQLite {})",
-        db_file,
-        rusqlite::version()
-    );
-    let conn = Connection::open(db_file)?;
-
-    conn.execute_batch("PRAGMA cache_size = 100000;")?;
-
-    if do_post {
-        fn run_iter_labels(
-            conn: &Connection,
-            count_query: &str,
-            iter_query: &str,
-            per_row: &str,
-        ) -> rusqlite::Result<()> {
-            let unlabeled_city_count: u64 = conn.query_row(count_query, [], |row| row.get(0))?;
-            let status = {
-                let (send, recv) = crossbeam::channel::unbounded();
-                let status = std::thread::spawn(move || {
-                    let start_time = Instant::now();
-                    let mut last_time = Instant::now();
-                    let mut rows_processed = 0;
-                    let mut rows_processed_since_last = 0;
-                    let mut item = String::from("?");
-                    loop {
-                        item = match recv.recv_timeout(std::time::Duration::from_secs(5)) {
-                            Ok(item) => {
-                                rows_processed += 1;
-                                rows_processed_since_last += 1;
-                                item
-                            }
-                            Err(crossbeam::channel::RecvTimeoutError::Timeout) => item,
-                            Err(crossbeam::channel::RecvTimeoutError::Disconnected) => break,
-                        };
-
-                        if last_time.elapsed().as_secs() >= 10 {
-                            if rows_processed_since_last == 0 {
-                                error!("SQL got stuck on item {}", item);
-                                std::process::exit(-1);
-                            }
-
-                            let progress = rows_processed as f64 / unlabeled_city_count as f64;
-
-                            let rps = rows_processed_since_last as f64
-                                / last_time.elapsed().as_secs_f64();
-
-                            let elapsed_secs = start_time.elapsed().as_secs();
-                            let secs = elapsed_secs % 60;
-                            let mins = (elapsed_secs / 60) % 60;
-                            let hours = elapsed_secs / 3600;
-
-                            let time_elapsed = if hours > 0 {
-                                format!("{}h {:02}m {:02}s", hours, mins, secs)
-                            } else if mins > 0 {
-                                format!("{:02}m {:02}s", mins, secs)
-                            } else {
-                                format!("{}s", secs)
-                            };
-
-                            let mut eta =
-                                (unlabeled_city_count - rows_processed) as f64 / rps / 60.;
-                            let mut eta_unit = "m";
-                            if eta > 60. {
-                                eta /= 60.;
-                                eta_unit = "h";
-
-                                if eta > 24. {
-                                    eta /= 24.;
-                                    eta_unit = "d 😔";
-                                }
-                            }
-
-                            info!(
-                                "{:.2}% (ETA: {:.1}{}) | {}/{} rows in {} | {:.1} rows/s (at: {})",
-                                progress * 100.,
-                                eta,
-                                eta_unit,
-                                rows_processed,
-                                unlabeled_city_count,
-                                time_elapsed,
-                                rps,
-                                item,
-                            );
-                            last_time = Instant::now();
-                            rows_processed_since_last = 0;
-                        }
+/// Runs `per_row` for each ID returned by `iter_query`, logging the progress.
+fn run_iter_labels(
+    conn: &Connection,
+    count_query: &str,
+    iter_query: &str,
+    per_row: &str,
+) -> rusqlite::Result<()> {
+    let unlabeled_city_count: u64 = conn.query_row(count_query, [], |row| row.get(0))?;
+    let status = {
+        let (send, recv) = crossbeam::channel::unbounded();
+        let status = std::thread::spawn(move || {
+            let start_time = Instant::now();
+            let mut last_time = Instant::now();
+            let mut rows_processed = 0;
+            let mut rows_processed_since_last = 0;
+            let mut item = String::from("?");
+            loop {
+                item = match recv.recv_timeout(std::time::Duration::from_secs(5)) {
+                    Ok(item) => {
+                        rows_processed += 1;
+                        rows_processed_since_last += 1;
+                        item
+                    }
+                    Err(crossbeam::channel::RecvTimeoutError::Timeout) => item,
+                    Err(crossbeam::channel::RecvTimeoutError::Disconnected) => break,
+                };
+
+                if last_time.elapsed().as_secs() >= 10 {
+                    if rows_processed_since_last == 0 {
+                        error!("SQL got stuck on item {}", item);
+                        std::process::exit(-1);
                     }
-                    info!("Done!");
-                });
-
-                let mut row_stmt = conn.prepare(iter_query)?;
-                let mut update_row = conn.prepare(per_row)?;
 
-                let mut rows = row_stmt.query([])?;
-                while let Some(row) = rows.next()? {
-                    let id = row.get_ref(0)?;
-                    let id_str = id.as_str()?;
-                    send.send(id_str.to_string())
-                        .expect("failed to send status");
+                    let progress = rows_processed as f64 / unlabeled_city_count as f64;
 
-                    update_row.execute(params![id_str])?;
-                }
-                status
-            };
-            status.join().expect("status thread join failed");
-            Ok(())
-        }
+                    let rps = rows_processed_since_last as f64 / last_time.elapsed().as_secs_f64();
 
-        info!("Picking most relevant countries");
-        conn.execute_batch(include_str!("city_countries.sql"))?;
+                    let elapsed_secs = start_time.elapsed().as_secs();
+                    let secs = elapsed_secs % 60;
+                    let mins = (elapsed_secs / 60) % 60;
+                    let hours = elapsed_secs / 3600;
 
-        info!("Finding subdivisions");
-        conn.execute_batch(include_str!("find_subdivision.sql"))?;
+                    let time_elapsed = if hours > 0 {
+                        format!("{}h {:02}m {:02}s", hours, mins, secs)
+                    } else if mins > 0 {
+                        format!("{:02}m {:02}s", mins, secs)
+                    } else {
+                        format!("{}s", secs)
+                    };
 
-        info!("Updating city labels");
-        conn.execute_batch(include_str!("city_labels.sql"))?;
+                    let mut eta = (unlabeled_city_count - rows_processed) as f64 / rps / 60.;
+                    let mut eta_unit = "m";
+                    if eta > 60. {
+                        eta /= 60.;
+                        eta_unit = "h";
 
-        info!("Updating city labels recursively");
-        run_iter_labels(
-            &conn,
-            "SELECT COUNT(1) as count FROM cities WHERE native_label IS NULL",
-            "SELECT id FROM cities WHERE native_label IS NULL",
-            include_str!("per_city.sql"),
-        )?;
+                        if eta > 24. {
+                            eta /= 24.;
+                            eta_unit = "d 😔";
+                        }
+                    }
 
-        info!("Updating city labels by country");
-        conn.execute_batch(include_str!("city_labels_by_country.sql"))?;
+                    info!(
+                        "{:.2}% (ETA: {:.1}{}) | {}/{} rows in {} | {:.1} rows/s (at: {})",
+                        progress * 100.,
+                        eta,
+                        eta_unit,
+                        rows_processed,
+                        unlabeled_city_count,
+                        time_elapsed,
+                        rps,
+                        item,
+                    );
+                    last_time = Instant::now();
+                    rows_processed_since_last = 0;
+                }
+            }
+            info!("Done!");
+        });
 
-        info!("Updating Esperanto city labels");
-        conn.execute_batch(include_str!("esperanto_city_labels.sql"))?;
+        let mut row_stmt = conn.prepare(iter_query)?;
+        let mut update_row = conn.prepare(per_row)?;
 
-        info!("Updating subdivision labels");
-        conn.execute_batch(include_str!("subdivision_labels.sql"))?;
+        let mut rows = row_stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let id = row.get_ref(0)?;
+            let id_str = id.as_str()?;
+            send.send(id_str.to_string())
+                .expect("failed to send status");
 
-        info!("Updating subdivision labels recursively");
-        run_iter_labels(
-            &conn,
-            r#"SELECT count(DISTINCT "2nd_id") as count FROM cities WHERE "2nd_native_label" IS NULL AND "2nd_id" IS NOT NULL"#,
-            r#"SELECT DISTINCT "2nd_id" FROM cities WHERE "2nd_native_label" IS NULL AND "2nd_id" IS NOT NULL"#,
-            include_str!("per_subdivision.sql"),
-        )?;
+            update_row.execute(params![id_str])?;
+        }
+        status
+    };
+    status.join().expect("status thread join failed");
+    Ok(())
+}
 
-        info!("Updating subdivision labels by country");
-        conn.execute_batch(include_str!("subdivision_labels_by_country.sql"))?;
+pub fn run(db_file: &str, do_post: bool, do_cleanup: bool) -> rusqlite::Result<()> {
+    info!(
+        "Opening database at {} (SQLite {})",
+        db_file,
+        rusqlite::version()
+    );
+    let conn = Connection::open(db_file)?;
 
-        info!("Updating Esperanto subdivision labels");
-        conn.execute_batch(include_str!("esperanto_subdivision_labels.sql"))?;
+    conn.execute_batch("PRAGMA cache_size = 100000;")?;
 
-        info!("Updating cities by subdivision ISO");
-        conn.execute_batch(include_str!("subdivision_iso.sql"))?;
+    if do_post {
+        conn.execute_batch(include_str!("columns.sql"))?;
+        conn.execute_batch(include_str!("all_cities.sql"))?;
+        process_affected(&conn)?;
+        conn.execute_batch("DROP VIEW affected_cities;")?;
     }
 
     if do_cleanup {
@@ -181,6 +142,14 @@ pub fn run(db_file: &str, do_post: bool, do_cleanup: bool) -> rusqlite::Result<(
                 "deleting unused object languages",
             ),
             (include_str!("cleanup/09.sql"), "renaming tables"),
+            (
+                include_str!("cleanup/10.sql"),
+                "deleting unused population history",
+            ),
+            (
+                include_str!("cleanup/11.sql"),
+                "deleting unused external IDs",
+            ),
         ];
 
         for (i, (script, description)) in SCRIPTS.iter().enumerate() {
@@ -196,3 +165,70 @@ pub fn run(db_file: &str, do_post: bool, do_cleanup: bool) -> rusqlite::Result<(
 
     Ok(())
 }
+
+/// Redoes post-processing for the cities affected by the entities in the temporary
+/// `updated_entities` table, which must exist on the connection.
+pub fn update(conn: &Connection) -> rusqlite::Result<()> {
+    info!("Finding affected cities");
+    conn.execute_batch(include_str!("update/affected_cities.sql"))?;
+    let affected: u64 =
+        conn.query_row("SELECT COUNT(1) FROM affected_cities", [], |row| row.get(0))?;
+    info!("{} cities are affected", affected);
+    conn.execute_batch(include_str!("update/reset.sql"))?;
+
+    process_affected(conn)?;
+
+    conn.execute_batch("DROP TABLE affected_cities;")?;
+    Ok(())
+}
+
+/// Fills in the post-processed columns of the cities in `affected_cities`.
+fn process_affected(conn: &Connection) -> rusqlite::Result<()> {
+    info!("Picking most relevant countries");
+    conn.execute_batch(include_str!("city_countries.sql"))?;
+
+    info!("Finding subdivisions");
+    conn.execute_batch(include_str!("find_subdivision.sql"))?;
+
+    info!("Finding time zones");
+    conn.execute_batch(include_str!("time_zones.sql"))?;
+
+    info!("Updating city labels");
+    conn.execute_batch(include_str!("city_labels.sql"))?;
+
+    info!("Updating city labels recursively");
+    run_iter_labels(
+        conn,
+        "SELECT COUNT(1) as count FROM cities WHERE native_label IS NULL AND id IN affected_cities",
+        "SELECT id FROM cities WHERE native_label IS NULL AND id IN affected_cities",
+        include_str!("per_city.sql"),
+    )?;
+
+    info!("Updating city labels by country");
+    conn.execute_batch(include_str!("city_labels_by_country.sql"))?;
+
+    info!("Updating Esperanto city labels");
+    conn.execute_batch(include_str!("esperanto_city_labels.sql"))?;
+
+    info!("Updating subdivision labels");
+    conn.execute_batch(include_str!("subdivision_labels.sql"))?;
+
+    info!("Updating subdivision labels recursively");
+    run_iter_labels(
+        conn,
+        r#"SELECT count(DISTINCT "2nd_id") as count FROM cities WHERE "2nd_native_label" IS NULL AND "2nd_id" IS NOT NULL AND id IN affected_cities"#,
+        r#"SELECT DISTINCT "2nd_id" FROM cities WHERE "2nd_native_label" IS NULL AND "2nd_id" IS NOT NULL AND id IN affected_cities"#,
+        include_str!("per_subdivision.sql"),
+    )?;
+
+    info!("Updating subdivision labels by country");
+    conn.execute_batch(include_str!("subdivision_labels_by_country.sql"))?;
+
+    info!("Updating Esperanto subdivision labels");
+    conn.execute_batch(include_str!("esperanto_subdivision_labels.sql"))?;
+
+    info!("Updating cities by subdivision ISO");
+    conn.execute_batch(include_str!("subdivision_iso.sql"))?;
+
+    Ok(())
+}
@@ -1,7 +1,3 @@
-ALTER TABLE cities ADD COLUMN "2nd_iso";
-
-CREATE INDEX "cities_2nd_iso_index" ON cities ("2nd_iso");
-
 UPDATE cities
 SET "2nd_iso" = subdivisions.iso
 FROM (
@@ -9,5 +5,6 @@ FROM (
 	FROM territorial_entities
 	WHERE is_2nd
 ) AS subdivisions
-WHERE subdivisions.id = cities."2nd_id";
-
+WHERE
+  subdivisions.id = cities."2nd_id"
+  AND cities.id IN affected_cities;
@@ -1,7 +1,3 @@
-ALTER TABLE cities ADD COLUMN "2nd_native_label" string;
-
-CREATE INDEX cities_2nd_native_label_index ON cities ("2nd_native_label");
-
 UPDATE cities
 SET "2nd_native_label" = labels.full_label
 FROM (
@@ -18,8 +14,10 @@ FROM (
     WHERE
       native_order NOT NULL
       AND native_order <= 1
+      AND c.id IN (SELECT "2nd_id" FROM cities WHERE id IN affected_cities)
   ) AS labels_inner
   GROUP BY labels_inner.id
 ) AS labels
 WHERE
-    labels.id = cities."2nd_id";
+    labels.id = cities."2nd_id"
+    AND cities.id IN affected_cities;
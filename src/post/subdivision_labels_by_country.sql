@@ -57,6 +57,9 @@ FROM (
 
   WHERE
     cities."2nd_native_label" IS NULL
+    AND cities.id IN affected_cities
 ) AS labels
 
-WHERE cities."2nd_id" = labels."2nd_id";
+WHERE
+  cities."2nd_id" = labels."2nd_id"
+  AND cities.id IN affected_cities;
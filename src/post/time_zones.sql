@@ -0,0 +1,36 @@
+-- the first time zone of the city, or of the nearest place it is in
+UPDATE cities
+SET time_zone = data.tz
+FROM (
+  SELECT
+    cities.id,
+    (
+      SELECT time_zones.tz
+      FROM (
+        WITH RECURSIVE parents(step, id) AS (
+          VALUES(0, cities.id)
+          UNION ALL
+          SELECT
+          step + 1 as step,
+          parent AS id
+          FROM territorial_entities_parents, parents
+          WHERE
+            territorial_entities_parents.id = parents.id
+            AND step < 100
+        )
+        SELECT
+          step,
+          id
+        FROM parents
+      ) AS parents_outer
+      INNER JOIN object_time_zones
+      ON object_time_zones.id = parents_outer.id
+      INNER JOIN time_zones
+      ON time_zones.id = object_time_zones.time_zone_id
+      ORDER BY step ASC, tz_index ASC
+      LIMIT 1
+    ) AS tz
+  FROM cities
+  WHERE cities.id IN affected_cities
+) data
+WHERE cities.id = data.id;
@@ -0,0 +1,32 @@
+-- cities whose post-processed columns may have changed because of the entities in updated_entities:
+-- the entities themselves, everything within them and within places that use an updated time zone,
+-- and cities in updated countries or in countries that use an updated language
+DROP TABLE IF EXISTS temp.affected_cities;
+CREATE TEMP TABLE affected_cities (id string NOT NULL PRIMARY KEY);
+
+WITH RECURSIVE descendants(id) AS (
+  SELECT id FROM updated_entities
+
+  UNION
+
+  SELECT object_time_zones.id
+  FROM object_time_zones
+  WHERE object_time_zones.time_zone_id IN updated_entities
+
+  UNION
+
+  SELECT territorial_entities_parents.id
+  FROM territorial_entities_parents, descendants
+  WHERE territorial_entities_parents.parent = descendants.id
+)
+INSERT OR IGNORE INTO affected_cities
+SELECT cities.id
+FROM cities
+WHERE
+  cities.id IN descendants
+  OR cities.country IN descendants
+  OR cities.country IN (
+    SELECT object_languages.id
+    FROM object_languages
+    WHERE object_languages.lang_id IN updated_entities
+  );
@@ -0,0 +1,11 @@
+UPDATE cities
+SET
+  country = NULL,
+  "2nd_id" = NULL,
+  native_label = NULL,
+  eo_label = NULL,
+  "2nd_native_label" = NULL,
+  "2nd_eo_label" = NULL,
+  "2nd_iso" = NULL,
+  time_zone = NULL
+WHERE id IN affected_cities;
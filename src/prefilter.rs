@@ -0,0 +1,361 @@
+//! A quick scan of dump lines that skips entities which can't produce any rows, without fully
+//! parsing them.
+//!
+//! Only countries, time zones and instances of the classes of the handlers are extracted, so only
+//! the `P31` (instance of) targets and the presence of `P297` (ISO code) or `P6687` (IANA time zone
+//! ID) in the claims of an entity are looked at. Whenever a line doesn't look as expected, it is
+//! passed on to be parsed fully.
+
+use crate::rules::Rules;
+use crate::wiki_sparql::Classes;
+use crate::wikibase::Wikibase;
+use memchr::{memchr2, memchr3};
+use std::collections::HashSet;
+
+pub struct Prefilter {
+    /// ID of the instance-of property in the Wikibase instance.
+    instance_of: String,
+    /// IDs of the properties that make an entity a country or a time zone.
+    marker_properties: Vec<String>,
+    /// Classes that some handler is interested in.
+    classes: HashSet<String>,
+}
+
+impl Prefilter {
+    pub fn new(wikibase: &Wikibase, rules: &Rules, classes: &Classes) -> Self {
+        let mut handled_classes = HashSet::new();
+        handled_classes.extend(classes.territorial_entities.iter().cloned());
+        handled_classes.extend(classes.human_settlements.iter().cloned());
+        handled_classes.extend(classes.languages.iter().cloned());
+
+        Prefilter {
+            instance_of: wikibase.property(&rules.instance_of.property).into(),
+            marker_properties: rules
+                .country
+                .columns
+                .iso
+                .iter()
+                .chain(&rules.time_zone.columns.tz)
+                .map(|rule| wikibase.property(&rule.property).into())
+                .collect(),
+            classes: handled_classes,
+        }
+    }
+
+    /// Returns whether a line of the JSON dump may contain an entity that produces rows.
+    pub fn is_relevant(&self, line: &str) -> bool {
+        let line = line.strip_suffix(',').unwrap_or(line);
+        let mut scanner = Scanner { line, pos: 0 };
+        scanner.is_relevant(self).unwrap_or(true)
+    }
+}
+
+/// Walks the JSON of an entity. Methods return `None` if the JSON is not as expected.
+struct Scanner<'a> {
+    line: &'a str,
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn is_relevant(&mut self, filter: &Prefilter) -> Option<bool> {
+        let mut relevant = false;
+        self.object(|s, key| {
+            if key != "claims" {
+                s.skip_value()?;
+                return Some(false);
+            }
+            s.object(|s, property| {
+                if filter.marker_properties.iter().any(|p| p == property) {
+                    relevant = true;
+                    return Some(true);
+                }
+                if property != filter.instance_of {
+                    s.skip_value()?;
+                    return Some(false);
+                }
+                s.array(|s| {
+                    if let Some(id) = s.statement_value_id()? {
+                        relevant |= filter.classes.contains(id);
+                    }
+                    Some(())
+                })?;
+                Some(relevant)
+            })?;
+            // the claims are all that matters
+            Some(true)
+        })?;
+        Some(relevant)
+    }
+
+    /// Reads `mainsnak.datavalue.value.id` of a statement.
+    fn statement_value_id(&mut self) -> Option<Option<&'a str>> {
+        let mut id = None;
+        self.field(&["mainsnak", "datavalue", "value", "id"], &mut |s| {
+            id = Some(s.string()?);
+            Some(())
+        })?;
+        Some(id)
+    }
+
+    /// Calls `f` with the value at the path of keys, if there is one, and skips everything else.
+    fn field(&mut self, path: &[&str], f: &mut dyn FnMut(&mut Self) -> Option<()>) -> Option<()> {
+        let (first, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return f(self),
+        };
+        if self.peek()? != b'{' {
+            return self.skip_value();
+        }
+        self.object(|s, key| {
+            if key == *first {
+                s.field(rest, f)?;
+            } else {
+                s.skip_value()?;
+            }
+            Some(false)
+        })
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.line.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let bytes = self.line.as_bytes();
+        while self.pos < bytes.len() && bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        if self.peek()? == byte {
+            self.pos += 1;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// Reads a string without unescaping it. Strings with escapes are only accepted if the caller
+    /// doesn't need their contents, so that they can't be mistaken for keys or IDs.
+    fn raw_string(&mut self) -> Option<(&'a str, bool)> {
+        self.expect(b'"')?;
+        let bytes = self.line.as_bytes();
+        let start = self.pos;
+        let mut escaped = false;
+        loop {
+            let i = self.pos + memchr2(b'"', b'\\', bytes.get(self.pos..)?)?;
+            if bytes[i] == b'\\' {
+                escaped = true;
+                self.pos = i + 2;
+            } else {
+                self.pos = i + 1;
+                return Some((&self.line[start..i], escaped));
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<&'a str> {
+        match self.raw_string()? {
+            (s, false) => Some(s),
+            (_, true) => None,
+        }
+    }
+
+    /// Calls `f` for each key of an object, which has to consume the value and returns whether to
+    /// stop scanning. The rest of the object is not skipped after stopping.
+    fn object(&mut self, mut f: impl FnMut(&mut Self, &'a str) -> Option<bool>) -> Option<()> {
+        self.expect(b'{')?;
+        if self.peek()? == b'}' {
+            self.pos += 1;
+            return Some(());
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(b':')?;
+            if f(self, key)? {
+                return Some(());
+            }
+            match self.peek()? {
+                b',' => self.pos += 1,
+                b'}' => {
+                    self.pos += 1;
+                    return Some(());
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn array(&mut self, mut f: impl FnMut(&mut Self) -> Option<()>) -> Option<()> {
+        self.expect(b'[')?;
+        if self.peek()? == b']' {
+            self.pos += 1;
+            return Some(());
+        }
+        loop {
+            f(self)?;
+            match self.peek()? {
+                b',' => self.pos += 1,
+                b']' => {
+                    self.pos += 1;
+                    return Some(());
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn skip_value(&mut self) -> Option<()> {
+        match self.peek()? {
+            b'"' => {
+                self.raw_string()?;
+            }
+            open @ b'{' | open @ b'[' => {
+                // other kinds of brackets can be ignored, since they are balanced within
+                let close = if open == b'{' { b'}' } else { b']' };
+                let bytes = self.line.as_bytes();
+                let mut depth = 0_usize;
+                loop {
+                    self.pos += memchr3(b'"', open, close, bytes.get(self.pos..)?)?;
+                    match bytes[self.pos] {
+                        b'"' => {
+                            self.raw_string()?;
+                            continue;
+                        }
+                        b if b == open => depth += 1,
+                        _ => {
+                            depth -= 1;
+                            if depth == 0 {
+                                self.pos += 1;
+                                return Some(());
+                            }
+                        }
+                    }
+                    self.pos += 1;
+                }
+            }
+            _ => {
+                // numbers, booleans and null
+                let bytes = self.line.as_bytes();
+                let start = self.pos;
+                while self.pos < bytes.len() && !matches!(bytes[self.pos], b',' | b'}' | b']') {
+                    self.pos += 1;
+                }
+                if self.pos == start {
+                    return None;
+                }
+            }
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::classes;
+    use crate::wiki_data_line::{handle_line, EntityFormat};
+    use serde_json::json;
+
+    fn statement(id: &str) -> serde_json::Value {
+        json!({
+            "mainsnak": {
+                "snaktype": "value",
+                "property": "P31",
+                "datavalue": {
+                    "value": { "entity-type": "item", "numeric-id": 1, "id": id },
+                    "type": "wikibase-entityid"
+                }
+            },
+            "type": "statement",
+            "qualifiers": { "P31": [{ "snaktype": "value", "datavalue": { "value": { "id": "Q515" } } }] },
+            "rank": "normal"
+        })
+    }
+
+    /// Checks the filter against whether the entity produces any entries.
+    fn check(filter: &Prefilter, line: &str, expected: bool) {
+        let (send, recv) = crossbeam::channel::unbounded();
+        handle_line(
+            line,
+            EntityFormat::Json,
+            &Wikibase::default(),
+            &Rules::default(),
+            &classes(),
+            &send,
+            false,
+        )
+        .unwrap();
+        let produces_entries = recv.try_recv().is_ok();
+        assert!(
+            filter.is_relevant(line) || !produces_entries,
+            "skipped an entity with entries: {}",
+            line
+        );
+        assert_eq!(filter.is_relevant(line), expected, "{}", line);
+    }
+
+    #[test]
+    fn skips_only_irrelevant_entities() {
+        let filter = Prefilter::new(&Wikibase::default(), &Rules::default(), &classes());
+        let entity = |claims: serde_json::Value| {
+            json!({
+                "type": "item",
+                "id": "Q1",
+                // keys and IDs in strings must not be mistaken for claims
+                "labels": { "en": { "language": "en", "value": "\"P31\":[{\"id\":\"Q515\"}] \\" } },
+                "claims": claims,
+                "sitelinks": {}
+            })
+            .to_string()
+        };
+
+        check(&filter, &entity(json!({})), false);
+        check(&filter, &entity(json!({ "P31": [statement("Q5")] })), false);
+        check(
+            &filter,
+            &format!(
+                "{},",
+                entity(json!({ "P31": [statement("Q5"), statement("Q515")] }))
+            ),
+            true,
+        );
+        check(
+            &filter,
+            &entity(json!({ "P31": [statement("Q34770")] })),
+            true,
+        );
+        check(
+            &filter,
+            &entity(json!({ "P17": [statement("Q515")], "P31": [], "P297": [] })),
+            true,
+        );
+        check(
+            &filter,
+            &entity(
+                json!({ "P6687": [{ "mainsnak": { "snaktype": "value", "datavalue": { "value": "Europe/Berlin" } } }] }),
+            ),
+            true,
+        );
+        check(
+            &filter,
+            &entity(
+                json!({ "P31": [{ "mainsnak": { "snaktype": "novalue", "property": "P31" } }] }),
+            ),
+            false,
+        );
+        // not as expected, so parsed fully
+        check(
+            &filter,
+            &format!(
+                r#"{{"id":"Q1","claims":{{"P\u0033\u0031":[{}]}}}}"#,
+                statement("Q515")
+            ),
+            true,
+        );
+        check(&filter, "[", true);
+        assert!(filter.is_relevant(r#"{"id":"Q1","labels":{"en":"\"#));
+    }
+}
@@ -0,0 +1,255 @@
+//! Rules for which entities are extracted, and which of their statements end up in which columns.
+//!
+//! The default rules are in `rules.json`. All IDs are Wikidata IDs, which are mapped to the
+//! Wikibase instance (see [`Wikibase`](crate::wikibase::Wikibase)).
+
+use crate::wiki_entity::{Entity, Rank, Statement};
+use crate::wiki_quantity::Units;
+use crate::wiki_time::is_object_active;
+use serde::Deserialize;
+use std::{fs, io};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    /// Entities that have any of these properties are skipped entirely.
+    pub skip: Vec<SkipRule>,
+    /// The statements that give the classes of an entity.
+    pub instance_of: PropertyRule,
+    pub country: CountryRules,
+    pub territorial_entity: TerritorialEntityRules,
+    pub human_settlement: HumanSettlementRules,
+    pub language: LanguageRules,
+    #[serde(default)]
+    pub time_zone: TimeZoneRules,
+    /// Conversions of the units of lengths and areas.
+    #[serde(default)]
+    pub units: Units,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SkipRule {
+    pub property: String,
+    /// The entity is not skipped if any of the statements has one of these qualifiers.
+    #[serde(default)]
+    pub unless_qualifiers: Vec<String>,
+}
+
+/// Which statements of a property to use.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PropertyRule {
+    pub property: String,
+    /// Skips statements that have ended or have not started yet.
+    #[serde(default)]
+    pub only_active: bool,
+    /// Skips statements that have any of these qualifiers, e.g. P518 (applies to part).
+    #[serde(default)]
+    pub disqualifying_qualifiers: Vec<String>,
+    /// The qualifier with the date of a statement, for columns that use the most recent one.
+    #[serde(default)]
+    pub date_qualifier: Option<String>,
+    /// The qualifier with the method by which the value was determined, e.g. a census.
+    #[serde(default)]
+    pub method_qualifier: Option<String>,
+    /// Properties of references that are recorded as the source of a value, in order of
+    /// preference.
+    #[serde(default)]
+    pub reference_properties: Vec<String>,
+}
+
+/// The rules for one column. The first rule whose property the entity has is used, even if all
+/// of its statements are skipped.
+pub type ColumnRules = Vec<PropertyRule>;
+
+/// Entities that have statements for `countries.iso` are countries.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CountryRules {
+    pub columns: CountryColumns,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CountryColumns {
+    /// The first statement is used.
+    #[serde(rename = "countries.iso")]
+    pub iso: ColumnRules,
+    #[serde(rename = "object_languages.lang_id", default)]
+    pub languages: ColumnRules,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TerritorialEntityRules {
+    /// Classes whose instances (including those of their subclasses) are territorial entities.
+    pub classes: Vec<String>,
+    pub excluded_classes: Vec<String>,
+    /// Classes of first-level subdivisions of countries.
+    pub first_level_classes: Vec<String>,
+    pub columns: TerritorialEntityColumns,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TerritorialEntityColumns {
+    /// The first statement is used, for first-level subdivisions only.
+    #[serde(rename = "territorial_entities.iso", default)]
+    pub iso: ColumnRules,
+    #[serde(rename = "territorial_entities_parents.parent", default)]
+    pub parents: ColumnRules,
+    #[serde(rename = "object_languages.lang_id", default)]
+    pub languages: ColumnRules,
+    /// Inherited by the cities in the entity that don't have any.
+    #[serde(rename = "object_time_zones.time_zone_id", default)]
+    pub time_zones: ColumnRules,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HumanSettlementRules {
+    pub classes: Vec<String>,
+    pub excluded_classes: Vec<String>,
+    pub columns: HumanSettlementColumns,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HumanSettlementColumns {
+    /// Settlements without statements for this are recorded in `missing_p17` instead.
+    #[serde(rename = "cities_countries.country")]
+    pub country: ColumnRules,
+    #[serde(rename = "territorial_entities_parents.parent", default)]
+    pub parents: ColumnRules,
+    /// The statement with the most recent date is used, and all of them end up in
+    /// `population_history`.
+    #[serde(rename = "cities.population", default)]
+    pub population: ColumnRules,
+    /// Both `lat` and `lon`, from the first statement.
+    #[serde(rename = "cities.coordinates", default)]
+    pub coordinates: ColumnRules,
+    /// In metres, from the first statement with a known unit.
+    #[serde(rename = "cities.elevation", default)]
+    pub elevation: ColumnRules,
+    /// In square kilometres, from the first statement with a known unit.
+    #[serde(rename = "cities.area", default)]
+    pub area: ColumnRules,
+    /// The first one that is a time zone is used; if there is none, that of the nearest place the
+    /// city is in.
+    #[serde(rename = "object_time_zones.time_zone_id", default)]
+    pub time_zones: ColumnRules,
+    /// Unlike for other columns, the statements of all of the properties are used.
+    #[serde(rename = "cities_external_ids.value", default)]
+    pub external_ids: ColumnRules,
+    /// Labels with a `native_order`.
+    #[serde(rename = "object_labels.native_order", default)]
+    pub native_labels: ColumnRules,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LanguageRules {
+    pub classes: Vec<String>,
+    pub columns: LanguageColumns,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LanguageColumns {
+    /// The first statement is used; languages without one are skipped.
+    #[serde(rename = "languages.code")]
+    pub code: ColumnRules,
+}
+
+/// Entities that have statements for `time_zones.tz` are time zones.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeZoneRules {
+    pub columns: TimeZoneColumns,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeZoneColumns {
+    /// The IANA time zone identifier (e.g. `Europe/Berlin`), from the first statement.
+    #[serde(rename = "time_zones.tz", default)]
+    pub tz: ColumnRules,
+}
+
+#[derive(Debug, Error)]
+pub enum RulesError {
+    #[error("could not read rules: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid rules: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        serde_json::from_str(include_str!("rules.json")).expect("invalid default rules")
+    }
+}
+
+impl Rules {
+    pub fn load(path: &str) -> Result<Self, RulesError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Returns whether the entity should be skipped.
+    pub fn is_skipped(&self, entity: &Entity) -> bool {
+        self.skip.iter().any(|rule| {
+            let statements = match entity.statements(&rule.property) {
+                Some(statements) if !statements.is_empty() => statements,
+                _ => return false,
+            };
+            let mut statements = statements
+                .iter()
+                .filter(|statement| statement.rank != Rank::Deprecated)
+                .peekable();
+            statements.peek().is_some()
+                && !statements.any(|statement| {
+                    rule.unless_qualifiers
+                        .iter()
+                        .any(|qualifier| statement.has_qualifier(qualifier))
+                })
+        })
+    }
+}
+
+impl PropertyRule {
+    /// Returns whether a statement should be used. Deprecated statements never are.
+    pub fn accepts(&self, statement: &Statement) -> bool {
+        if statement.rank == Rank::Deprecated {
+            return false;
+        }
+        if self.only_active && !is_object_active(statement) {
+            return false;
+        }
+        !self
+            .disqualifying_qualifiers
+            .iter()
+            .any(|qualifier| statement.has_qualifier(qualifier))
+    }
+}
+
+/// Returns the rule for a column that applies to the entity, and the statements it accepts, with
+/// preferred statements before normal ones.
+pub fn statements<'a>(
+    entity: &'a Entity,
+    rules: &'a [PropertyRule],
+) -> Option<(&'a PropertyRule, impl Iterator<Item = &'a Statement>)> {
+    rules.iter().find_map(|rule| {
+        let statements = entity.statements(&rule.property)?;
+        let with_rank = move |rank| {
+            statements
+                .iter()
+                .filter(move |statement| statement.rank == rank && rule.accepts(statement))
+        };
+        Some((
+            rule,
+            with_rank(Rank::Preferred).chain(with_rank(Rank::Normal)),
+        ))
+    })
+}
@@ -1,10 +1,3 @@
--- since we are just writing to a blank database,
--- we can tune sqlite for speed at the expense of safety
-PRAGMA synchronous = OFF;
-PRAGMA cache_size = 100000;
-PRAGMA journal_mode = MEMORY;
-PRAGMA temp_store = MEMORY;
-
 create table countries (
         id string not null primary key,
         iso char(2) not null
@@ -24,6 +17,18 @@ create table languages (
         code string not null);
 create index languages_code_index on languages (code);
 
+create table object_time_zones (
+        id string not null,
+        time_zone_id string not null,
+        tz_index integer not null,
+        primary key (id, time_zone_id)
+);
+create index object_time_zones_time_zone_id_index on object_time_zones (time_zone_id);
+
+create table time_zones (
+        id string not null primary key,
+        tz string not null);
+
 create table territorial_entities (
     id string not null primary key,
     is_2nd boolean not null,
@@ -42,8 +47,12 @@ create table cities (
     id string not null primary key,
     country string,
     population integer,
+    -- text, since years alone would be stored as integers in string (numeric) columns
+    population_date text,
     lat decimal(5, 3),
-    lon decimal(6, 3)
+    lon decimal(6, 3),
+    elevation real,
+    area real
 );
 create index cities_country_index on cities (country);
 create index cities_population_index on cities (population);
@@ -61,6 +70,27 @@ create index cities_countries_country_index on cities_countries (country);
 create unique index cities_countries_city_country_index on cities_countries (city, country);
 create unique index cities_countries_city_priority_index on cities_countries (city, priority);
 
+create table population_history (
+    id string not null,
+    population integer not null,
+    date text,
+    method string,
+    reference string
+);
+-- one row per population and date, so that rows that are inserted again are noticed
+create unique index population_history_id_population_date_index
+    on population_history (id, population, coalesce(date, ''));
+
+create table cities_external_ids (
+    id string not null,
+    property string not null,
+    -- text rather than string, which has numeric affinity and would drop leading zeros
+    value text not null,
+    primary key (id, property, value)
+);
+create index cities_external_ids_property_value_index on cities_external_ids (property, value);
+create index cities_external_ids_value_index on cities_external_ids (value);
+
 create table object_labels (
     id string not null,
     lang string not null,
@@ -73,3 +103,26 @@ create index object_labels_lang_index on object_labels (lang);
 create index object_labels_native_order_index on object_labels (native_order);
 
 create table missing_p17 (id string not null primary key);
+
+-- the dump this database was built from
+create table dump_info (
+    id integer not null primary key check (id = 0),
+    source string not null,
+    etag string,
+    -- text, since string columns would store YYYYMMDD as an integer
+    date text
+);
+
+-- position in the dump up to which everything has been written, for --resume
+create table build_checkpoint (
+    id integer not null primary key check (id = 0),
+    source string not null,
+    etag string,
+    length integer,
+    bit_offset integer not null,
+    level integer not null,
+    out_offset integer not null,
+    line_offset integer not null,
+    line_number integer not null,
+    complete boolean not null
+);
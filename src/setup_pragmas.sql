@@ -0,0 +1,7 @@
+-- since we are just writing to a fresh database,
+-- we can tune sqlite for speed at the expense of safety.
+-- once checkpoints are written, checkpoint_pragmas.sql makes writes crash-safe again
+PRAGMA synchronous = OFF;
+PRAGMA cache_size = 100000;
+PRAGMA journal_mode = MEMORY;
+PRAGMA temp_store = MEMORY;
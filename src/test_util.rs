@@ -0,0 +1,49 @@
+//! Builders for the classes and entities used in tests.
+
+use crate::wiki_sparql::Classes;
+use serde_json::{json, Value};
+
+/// Classes for cities (`Q515`), countries (`Q6256`), their subdivisions (`Q10864048`) and
+/// languages (`Q34770`). `Q123705` is excluded both as a territorial entity and as a settlement.
+pub fn classes() -> Classes {
+    let set = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+    Classes {
+        territorial_entities: set(&["Q56061", "Q6256", "Q10864048"]),
+        excluded_territorial_entities: set(&["Q123705"]),
+        second_level_admin_div: set(&["Q10864048"]),
+        human_settlements: set(&["Q515"]),
+        excluded_settlements: set(&["Q123705"]),
+        languages: set(&["Q34770"]),
+    }
+}
+
+/// A statement with the given value and normal rank.
+pub fn statement(value: Value) -> Value {
+    json!({ "mainsnak": { "snaktype": "value", "datavalue": { "value": value } } })
+}
+
+/// A statement with the given rank (`preferred`, `normal` or `deprecated`) and value.
+pub fn ranked_statement(rank: &str, value: Value) -> Value {
+    let mut statement = statement(value);
+    statement["rank"] = json!(rank);
+    statement
+}
+
+/// An item with the given labels, by language.
+pub fn item(id: &str, labels: &[(&str, &str)], claims: Value) -> Value {
+    let labels: serde_json::Map<_, _> = labels
+        .iter()
+        .map(|(lang, label)| {
+            (
+                lang.to_string(),
+                json!({ "language": lang, "value": label }),
+            )
+        })
+        .collect();
+    json!({
+        "type": "item",
+        "id": id,
+        "labels": labels,
+        "claims": claims,
+    })
+}
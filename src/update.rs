@@ -0,0 +1,718 @@
+//! Updates an existing database with the current data of entities that have changed since it was
+//! built, without rebuilding it.
+
+use crate::database::{self, DataEntry};
+use crate::input::http::USER_AGENT;
+use crate::post;
+use crate::rules::Rules;
+use crate::wiki_data_line::{self, EntityFormat, HandleLineError};
+use crate::wiki_sparql::Classes;
+use crate::wikibase::Wikibase;
+use reqwest::blocking::Client;
+use reqwest::StatusCode;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::io::{self, BufRead, BufReader};
+use std::time::Instant;
+use std::{fs, mem};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum UpdateError {
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("request error: {0}")]
+    Req(#[from] reqwest::Error),
+    #[error("database error: {0}")]
+    Sql(#[from] rusqlite::Error),
+    #[error("{0}")]
+    HandleLine(#[from] HandleLineError),
+    #[error("the database has already been cleaned up")]
+    CleanedUp,
+    #[error("the database is from an incomplete or failed build")]
+    Incomplete,
+    #[error("the database has only been partially post-processed")]
+    PartialPost,
+}
+
+/// What happened to the entities of an update.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct UpdateStats {
+    pub updated: usize,
+    /// Entities that no longer exist, or have been merged into another entity.
+    pub deleted: usize,
+    /// Entities that could not be fetched, whose rows were left unchanged.
+    pub failed: Vec<String>,
+}
+
+/// Reads entity IDs from a file (or stdin if the path is `-`), one per line.
+pub fn read_ids(path: &str) -> io::Result<Vec<String>> {
+    let reader: Box<dyn BufRead> = if path == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(fs::File::open(path)?))
+    };
+
+    let mut ids = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let id = line.trim();
+        if !id.is_empty() && !id.starts_with('#') {
+            ids.push(id.to_string());
+        }
+    }
+    Ok(ids)
+}
+
+pub fn client() -> reqwest::Result<Client> {
+    Client::builder().user_agent(USER_AGENT).build()
+}
+
+#[derive(Deserialize)]
+struct RecentChanges {
+    #[serde(rename = "continue")]
+    continuation: Option<RecentChangesContinue>,
+    query: RecentChangesQuery,
+}
+#[derive(Deserialize)]
+struct RecentChangesContinue {
+    rccontinue: String,
+}
+#[derive(Deserialize)]
+struct RecentChangesQuery {
+    recentchanges: Vec<RecentChange>,
+}
+#[derive(Deserialize)]
+struct RecentChange {
+    title: String,
+}
+
+/// Returns the IDs of items that have been changed since the given time (e.g.
+/// `2021-01-01T00:00:00Z`), according to the recent changes of the wiki.
+pub fn recent_changes(
+    client: &Client,
+    wikibase: &Wikibase,
+    since: &str,
+) -> Result<Vec<String>, UpdateError> {
+    let namespace = wikibase.item_namespace.to_string();
+    let mut ids = Vec::new();
+    let mut seen = HashSet::new();
+    let mut rccontinue = None;
+    loop {
+        let mut request = client.get(&wikibase.api_url).query(&[
+            ("action", "query"),
+            ("list", "recentchanges"),
+            ("rcprop", "title"),
+            ("rctype", "edit|new|log"),
+            ("rcnamespace", &namespace),
+            ("rcend", since),
+            ("rclimit", "500"),
+            ("format", "json"),
+            ("formatversion", "2"),
+        ]);
+        if let Some(rccontinue) = &rccontinue {
+            request = request.query(&[("rccontinue", rccontinue)]);
+        }
+        let response: RecentChanges = request.send()?.error_for_status()?.json()?;
+
+        for change in response.query.recentchanges {
+            // items outside of the main namespace have titles like Item:Q1
+            let id = change.title.rsplit(':').next().unwrap_or(&change.title);
+            if seen.insert(id.to_string()) {
+                ids.push(id.to_string());
+            }
+        }
+
+        match response.continuation {
+            Some(continuation) => rccontinue = Some(continuation.rccontinue),
+            None => break,
+        }
+    }
+    Ok(ids)
+}
+
+/// Fetches the JSON of an entity. Returns `None` if the entity has been deleted or merged into
+/// another entity.
+pub fn fetch_entity(
+    client: &Client,
+    wikibase: &Wikibase,
+    id: &str,
+) -> reqwest::Result<Option<String>> {
+    let response = client.get(wikibase.entity_data_url(id)).send()?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let json: Value = response.error_for_status()?.json()?;
+
+    // a merged entity redirects to the entity it was merged into
+    Ok(json
+        .get("entities")
+        .and_then(|entities| entities.get(id))
+        .filter(|entity| entity.get("missing").is_none())
+        .map(|entity| entity.to_string()))
+}
+
+/// How far a database has been processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DatabaseState {
+    Built,
+    PostProcessed,
+}
+
+fn database_state(conn: &Connection) -> Result<DatabaseState, UpdateError> {
+    // dropped during cleanup
+    let has_territorial_entities = conn
+        .query_row(
+            "select 1 from sqlite_master where type = 'table' and name = 'territorial_entities'",
+            [],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if !has_territorial_entities {
+        return Err(UpdateError::CleanedUp);
+    }
+
+    let complete: Option<bool> = conn
+        .query_row("select complete from build_checkpoint", [], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    if complete == Some(false) {
+        return Err(UpdateError::Incomplete);
+    }
+
+    // columns added by post-processing
+    const POST_COLUMNS: &[&str] = &[
+        "native_label",
+        "2nd_id",
+        "eo_label",
+        "2nd_native_label",
+        "2nd_eo_label",
+        "2nd_iso",
+    ];
+    let mut stmt = conn.prepare("select name from pragma_table_info('cities')")?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<HashSet<_>>>()?;
+    let post_columns = POST_COLUMNS
+        .iter()
+        .filter(|column| columns.contains(**column))
+        .count();
+
+    match post_columns {
+        0 => Ok(DatabaseState::Built),
+        n if n == POST_COLUMNS.len() => Ok(DatabaseState::PostProcessed),
+        _ => Err(UpdateError::PartialPost),
+    }
+}
+
+/// Replaces the rows of the given entities with their current data, and redoes post-processing
+/// for the affected cities if the database has been post-processed.
+///
+/// If `only_known` is set, entities that are not in the database yet are skipped, so new cities are
+/// not added.
+pub fn run(
+    db_file: &str,
+    ids: &[String],
+    only_known: bool,
+    wikibase: &Wikibase,
+    rules: &Rules,
+    classes: &Classes,
+) -> Result<UpdateStats, UpdateError> {
+    let mut conn = Connection::open(db_file)?;
+    let state = database_state(&conn)?;
+
+    let mut ids = ids.to_vec();
+    if only_known {
+        let count = ids.len();
+        let mut known = Vec::with_capacity(ids.len());
+        for id in ids {
+            if database::has_entity(&conn, &id)? {
+                known.push(id);
+            }
+        }
+        ids = known;
+        info!(
+            "{} of {} changed entities are in the database",
+            ids.len(),
+            count
+        );
+    }
+
+    let client = client()?;
+    let mut stats = UpdateStats::default();
+    let mut changes = Vec::with_capacity(ids.len());
+    let mut last_time = Instant::now();
+    for (i, id) in ids.iter().enumerate() {
+        if last_time.elapsed().as_secs() > 10 {
+            info!("Fetched {} of {} entities", i, ids.len());
+            last_time = Instant::now();
+        }
+
+        let entity = match fetch_entity(&client, wikibase, id) {
+            Ok(entity) => entity,
+            Err(e) => {
+                warn!("Failed to fetch entity {}: {}", id, e);
+                stats.failed.push(id.clone());
+                continue;
+            }
+        };
+
+        let (send, recv) = crossbeam::channel::unbounded();
+        match &entity {
+            Some(entity) => {
+                wiki_data_line::handle_line(
+                    entity,
+                    EntityFormat::Json,
+                    wikibase,
+                    rules,
+                    classes,
+                    &send,
+                    false,
+                )?;
+                stats.updated += 1;
+            }
+            None => stats.deleted += 1,
+        }
+        drop(send);
+        let entries: Vec<DataEntry> = recv.into_iter().collect();
+        changes.push((id, entries));
+    }
+
+    info!("Writing {} entities", changes.len());
+    let tx = conn.transaction()?;
+    tx.execute_batch(
+        "DROP TABLE IF EXISTS temp.updated_entities;
+        CREATE TEMP TABLE updated_entities (id string NOT NULL PRIMARY KEY);",
+    )?;
+    for (id, entries) in mem::take(&mut changes) {
+        database::replace_entity(&tx, id, entries)?;
+        tx.execute(
+            "insert or ignore into updated_entities (id) values (?1)",
+            params![id],
+        )?;
+    }
+
+    // post-processing happens in the same transaction, so that a failed update changes nothing
+    if state == DatabaseState::PostProcessed {
+        post::update(&tx)?;
+    }
+    tx.execute_batch("DROP TABLE updated_entities;")?;
+    tx.commit()?;
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::test_server::TestServer;
+    use crate::test_util::{classes, item, statement};
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    fn country() -> Value {
+        item(
+            "Q1",
+            &[("en", "Country")],
+            serde_json::json!({
+                "P31": [statement(serde_json::json!({ "id": "Q6256" }))],
+                "P297": [statement(serde_json::json!("CO"))],
+            }),
+        )
+    }
+
+    fn state(label: &str) -> Value {
+        item(
+            "Q2",
+            &[("en", label)],
+            serde_json::json!({
+                "P31": [statement(serde_json::json!({ "id": "Q10864048" }))],
+                "P131": [statement(serde_json::json!({ "id": "Q1" }))],
+            }),
+        )
+    }
+
+    fn city(id: &str, label: &str, population: u64) -> Value {
+        item(
+            id,
+            &[("en", label)],
+            serde_json::json!({
+                "P31": [statement(serde_json::json!({ "id": "Q515" }))],
+                "P17": [statement(serde_json::json!({ "id": "Q1" }))],
+                "P131": [statement(serde_json::json!({ "id": "Q2" }))],
+                "P1705": [statement(serde_json::json!({ "language": "en", "text": label }))],
+                "P1082": [{
+                    "mainsnak": { "snaktype": "value", "datavalue": { "value": {
+                        "amount": format!("+{}", population), "unit": "1"
+                    } } },
+                    "qualifiers": { "P585": [{ "snaktype": "value", "datavalue": { "value": {
+                        "time": "+2020-01-01T00:00:00Z", "timezone": 0
+                    } } }] },
+                }],
+            }),
+        )
+    }
+
+    /// Builds a database from the given entities in a temporary file.
+    fn build(name: &str, entities: &[Value], post_process: bool) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "geo-db-update-test-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let (send, recv) = crossbeam::channel::unbounded();
+        for entity in entities {
+            wiki_data_line::handle_line(
+                &entity.to_string(),
+                EntityFormat::Json,
+                &Wikibase::default(),
+                &Rules::default(),
+                &classes(),
+                &send,
+                false,
+            )
+            .unwrap();
+        }
+        drop(send);
+        database::db_writer(path.to_str().unwrap(), false, None, recv).unwrap();
+        if post_process {
+            post::run(path.to_str().unwrap(), true, false).unwrap();
+        }
+        path
+    }
+
+    fn entity_data(entities: &[Value]) -> HashMap<String, Vec<Vec<u8>>> {
+        entities
+            .iter()
+            .map(|entity| {
+                let id = entity["id"].as_str().unwrap();
+                let body = serde_json::json!({ "entities": { id: entity } });
+                (
+                    format!("/entity/{}.json", id),
+                    vec![body.to_string().into_bytes()],
+                )
+            })
+            .collect()
+    }
+
+    fn wikibase(server: &TestServer) -> Wikibase {
+        Wikibase {
+            entity_data_url: server.url_of("/entity/{id}.json"),
+            api_url: server.url_of("/api.php"),
+            ..Default::default()
+        }
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    fn population(path: &PathBuf, id: &str) -> Option<Option<u64>> {
+        Connection::open(path)
+            .unwrap()
+            .query_row("select population from cities where id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()
+            .unwrap()
+    }
+
+    fn labels(path: &PathBuf, id: &str) -> Vec<String> {
+        let conn = Connection::open(path).unwrap();
+        let mut stmt = conn
+            .prepare("select label from object_labels where id = ?1 order by label")
+            .unwrap();
+        let labels = stmt
+            .query_map([id], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        labels
+    }
+
+    #[test]
+    fn replaces_and_deletes_entities() {
+        let path = build(
+            "replace",
+            &[
+                country(),
+                state("State"),
+                city("Q10", "Old", 100),
+                city("Q11", "Gone", 50),
+            ],
+            false,
+        );
+        let server = TestServer::routes(entity_data(&[city("Q10", "New", 200)]));
+
+        let stats = run(
+            path.to_str().unwrap(),
+            &ids(&["Q10", "Q11"]),
+            false,
+            &wikibase(&server),
+            &Rules::default(),
+            &classes(),
+        )
+        .unwrap();
+        assert_eq!(
+            stats,
+            UpdateStats {
+                updated: 1,
+                deleted: 1,
+                failed: vec![],
+            }
+        );
+        assert_eq!(population(&path, "Q10"), Some(Some(200)));
+        assert_eq!(labels(&path, "Q10"), vec!["New", "New"]);
+        assert_eq!(population(&path, "Q11"), None);
+        assert!(labels(&path, "Q11").is_empty());
+        // other entities are untouched
+        assert_eq!(labels(&path, "Q2"), vec!["State"]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn redoes_post_processing_of_affected_cities() {
+        let path = build(
+            "post",
+            &[
+                country(),
+                state("State"),
+                city("Q10", "Ten", 100),
+                city("Q11", "Eleven", 50),
+            ],
+            true,
+        );
+        assert_eq!(population(&path, "Q10"), Some(Some(100)));
+
+        let server = TestServer::routes(entity_data(&[city("Q10", "Renamed", 100)]));
+        run(
+            path.to_str().unwrap(),
+            &ids(&["Q10"]),
+            false,
+            &wikibase(&server),
+            &Rules::default(),
+            &classes(),
+        )
+        .unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        let row = |id: &str| -> (Option<String>, Option<String>, Option<String>) {
+            conn.query_row(
+                r#"select native_label, country, "2nd_id" from cities where id = ?1"#,
+                [id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            row("Q10"),
+            (Some("Renamed".into()), Some("Q1".into()), Some("Q2".into()))
+        );
+        assert_eq!(
+            row("Q11"),
+            (Some("Eleven".into()), Some("Q1".into()), Some("Q2".into()))
+        );
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn updates_cities_within_updated_entities() {
+        let path = build(
+            "within",
+            &[
+                country(),
+                state("State"),
+                city("Q10", "Ten", 100),
+                city("Q11", "Eleven", 50),
+            ],
+            true,
+        );
+
+        // the state no longer is a first-level subdivision
+        let mut moved = state("State");
+        moved["claims"]["P31"] =
+            serde_json::json!([statement(serde_json::json!({ "id": "Q56061" }))]);
+        let server = TestServer::routes(entity_data(&[moved]));
+        run(
+            path.to_str().unwrap(),
+            &ids(&["Q2"]),
+            false,
+            &wikibase(&server),
+            &Rules::default(),
+            &classes(),
+        )
+        .unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        let subdivisions: Vec<Option<String>> = conn
+            .prepare(r#"select "2nd_id" from cities order by id"#)
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(subdivisions, vec![None, None]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn updates_cities_in_updated_time_zones() {
+        let time_zone = |id: &str, tz: &str| {
+            item(
+                id,
+                &[("en", tz)],
+                serde_json::json!({ "P6687": [statement(serde_json::json!(tz))] }),
+            )
+        };
+        let mut state = state("State");
+        state["claims"]["P421"] =
+            serde_json::json!([statement(serde_json::json!({ "id": "Q20" }))]);
+        let mut own = city("Q10", "Ten", 100);
+        own["claims"]["P421"] = serde_json::json!([
+            statement(serde_json::json!({ "id": "Q99" })),
+            statement(serde_json::json!({ "id": "Q21" })),
+        ]);
+        let path = build(
+            "time-zones",
+            &[
+                country(),
+                state,
+                time_zone("Q20", "Europe/Berlin"),
+                time_zone("Q21", "Europe/Paris"),
+                own,
+                city("Q11", "Eleven", 50),
+            ],
+            true,
+        );
+
+        let time_zones = || -> Vec<Option<String>> {
+            Connection::open(&path)
+                .unwrap()
+                .prepare("select time_zone from cities order by id")
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap()
+        };
+        assert_eq!(
+            time_zones(),
+            vec![Some("Europe/Paris".into()), Some("Europe/Berlin".into())]
+        );
+
+        let server = TestServer::routes(entity_data(&[time_zone("Q20", "Europe/Vienna")]));
+        run(
+            path.to_str().unwrap(),
+            &ids(&["Q20"]),
+            false,
+            &wikibase(&server),
+            &Rules::default(),
+            &classes(),
+        )
+        .unwrap();
+        assert_eq!(
+            time_zones(),
+            vec![Some("Europe/Paris".into()), Some("Europe/Vienna".into())]
+        );
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn skips_unknown_and_unreachable_entities() {
+        let path = build(
+            "unknown",
+            &[country(), state("State"), city("Q10", "Ten", 100)],
+            false,
+        );
+        let server = TestServer::routes(entity_data(&[city("Q10", "Ten", 300)]));
+        let mut wikibase = wikibase(&server);
+
+        let stats = run(
+            path.to_str().unwrap(),
+            &ids(&["Q10", "Q99"]),
+            true,
+            &wikibase,
+            &Rules::default(),
+            &classes(),
+        )
+        .unwrap();
+        assert_eq!(stats.updated, 1);
+        assert_eq!(population(&path, "Q10"), Some(Some(300)));
+
+        // nothing is listening on port 9
+        wikibase.entity_data_url = "http://127.0.0.1:9/entity/{id}.json".into();
+        let stats = run(
+            path.to_str().unwrap(),
+            &ids(&["Q10"]),
+            false,
+            &wikibase,
+            &Rules::default(),
+            &classes(),
+        )
+        .unwrap();
+        assert_eq!(stats.failed, vec!["Q10".to_string()]);
+        assert_eq!(population(&path, "Q10"), Some(Some(300)));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn refuses_cleaned_up_databases() {
+        let path = build("cleanup", &[country(), city("Q10", "Ten", 100)], false);
+        post::run(path.to_str().unwrap(), true, true).unwrap();
+        let server = TestServer::routes(HashMap::new());
+        let result = run(
+            path.to_str().unwrap(),
+            &ids(&["Q10"]),
+            false,
+            &wikibase(&server),
+            &Rules::default(),
+            &classes(),
+        );
+        assert!(
+            matches!(result, Err(UpdateError::CleanedUp)),
+            "{:?}",
+            result
+        );
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reads_recent_changes() {
+        let pages = [
+            serde_json::json!({
+                "continue": { "rccontinue": "20210101000000|2", "continue": "-||" },
+                "query": { "recentchanges": [{ "title": "Q1" }, { "title": "Item:Q2" }] },
+            }),
+            serde_json::json!({
+                "query": { "recentchanges": [{ "title": "Q1" }, { "title": "Q3" }] },
+            }),
+        ];
+        let mut routes = HashMap::new();
+        routes.insert(
+            "/api.php".to_string(),
+            pages
+                .iter()
+                .map(|page| page.to_string().into_bytes())
+                .collect(),
+        );
+        let server = TestServer::routes(routes);
+
+        let ids = recent_changes(
+            &client().unwrap(),
+            &wikibase(&server),
+            "2021-01-01T00:00:00Z",
+        )
+        .unwrap();
+        assert_eq!(ids, vec!["Q1", "Q2", "Q3"]);
+
+        let targets = server.targets();
+        assert_eq!(targets.len(), 2);
+        assert!(targets[0].contains("rcend=2021-01-01T00%3A00%3A00Z"));
+        assert!(targets[1].contains("rccontinue=20210101000000%7C2"));
+    }
+}
@@ -1,32 +1,67 @@
 use crate::database::DataEntry;
-use crate::json_get;
-use crate::wiki_sparql::Classes;
-use crate::wiki_time::{
-    is_object_active, is_object_end_active, is_object_start_active, parse_wikidata_time,
+use crate::rules::{
+    self, CountryColumns, HumanSettlementColumns, LanguageColumns, PropertyRule, Rules,
+    TerritorialEntityColumns, TimeZoneColumns,
 };
+use crate::wiki_entity::{DataValue, Entity, Snak, SnakType, Statement};
+use crate::wiki_quantity::{Dimension, QuantityError, Units};
+use crate::wiki_sparql::Classes;
+use crate::wiki_time::{format_date, is_object_start_active, parse_wikidata_time};
+use crate::wiki_triples::{self, TripleError};
+use crate::wikibase::Wikibase;
 use crossbeam::channel::Sender;
-use serde_json::Value;
 use std::collections::HashSet;
 use thiserror::Error;
 
+/// The format of the dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntityFormat {
+    /// One JSON entity per line (`latest-all.json.bz2`).
+    Json,
+    /// N-Triples with only the best-ranked statements (`latest-truthy.nt.bz2`), where each job
+    /// contains all triples of an entity.
+    ///
+    /// Since there are no qualifiers, statements that have ended, have not started yet or only
+    /// apply to a part of the entity are not filtered out, and the most recent population can't
+    /// be picked by its date.
+    Truthy,
+}
+
+impl EntityFormat {
+    /// Guesses the format from the file name of a path or URL of the dump, e.g.
+    /// `latest-truthy.nt.bz2`.
+    pub fn from_file_name(name: &str) -> Self {
+        let name = name.split(&['?', '#'][..]).next().unwrap_or(name);
+        let name = name.rsplit(&['/', '\\'][..]).next().unwrap_or(name);
+        let name = [".bz2", ".gz", ".zst", ".zstd"]
+            .iter()
+            .find_map(|ext| name.strip_suffix(ext))
+            .unwrap_or(name);
+        if name.ends_with(".nt") {
+            EntityFormat::Truthy
+        } else {
+            EntityFormat::Json
+        }
+    }
+}
+
 /// both human settlements and territorial entities
-fn handle_place(obj: &Value, sink: &Sender<DataEntry>) -> Result<(), HandleLineError> {
-    let obj_id = json_get!(value(obj).id: string).unwrap();
-    if let Some(parents) = json_get!(value(obj).claims.P131: array) {
+fn handle_place(
+    entity: &Entity,
+    parents: &[PropertyRule],
+    sink: &Sender<DataEntry>,
+) -> Result<(), HandleLineError> {
+    if let Some((rule, parents)) = rules::statements(entity, parents) {
         for parent in parents {
-            if !is_object_active(json_get!(value(parent).qualifiers: object)) {
-                continue;
-            }
-
-            if let Some(parent) = json_get!(value(parent).mainsnak.datavalue.value.id: string) {
+            if let Some(parent) = parent.value().and_then(DataValue::entity_id) {
                 sink.send(DataEntry::TerritorialEntityParent {
-                    id: obj_id.into(),
+                    id: entity.id.clone(),
                     parent: parent.into(),
                 })?;
             } else {
                 warn!(
-                    "skipping TE {} P131 parent because it has no datavalue ID",
-                    obj_id
+                    "skipping TE {} {} parent because it has no datavalue ID",
+                    entity.id, rule.property
                 );
             }
         }
@@ -34,297 +69,416 @@ fn handle_place(obj: &Value, sink: &Sender<DataEntry>) -> Result<(), HandleLineE
     Ok(())
 }
 
-fn handle_territorial_entity(
-    obj: &Value,
-    is_2nd: bool,
+fn handle_languages(
+    entity: &Entity,
+    languages: &[PropertyRule],
     sink: &Sender<DataEntry>,
 ) -> Result<(), HandleLineError> {
-    let obj_id = json_get!(value(obj).id: string).unwrap();
-
-    let iso = if is_2nd {
-        json_get!(value(obj).claims.P300[0].mainsnak.datavalue.value: string).map(|s| s.to_string())
-    } else {
-        None
-    };
-
-    sink.send(DataEntry::TerritorialEntity {
-        id: obj_id.into(),
-        is_2nd,
-        iso,
-    })?;
-
-    handle_place(obj, sink)?;
-
-    // P37: official language
-    // P2936: language used
-    if let Some(langs) =
-        json_get!(value(obj).claims.P37: array).or(json_get!(value(obj).claims.P2936: array))
-    {
+    if let Some((rule, langs)) = rules::statements(entity, languages) {
         let mut lang_index = 0;
         for lang in langs {
-            if json_get!(value(lang).mainsnak.snaktype: string) != Some("value") {
-                continue;
-            }
-            if !is_object_active(json_get!(value(lang).qualifiers: object)) {
-                continue;
-            }
-            if let Some(lang_id) = json_get!(value(lang).mainsnak.datavalue.value.id: string) {
+            let value = match lang.value() {
+                Some(value) => value,
+                None => continue,
+            };
+            if let Some(lang_id) = value.entity_id() {
                 sink.send(DataEntry::ObjectLanguage {
-                    id: obj_id.into(),
+                    id: entity.id.clone(),
                     lang_id: lang_id.into(),
                     index: lang_index,
                 })?;
                 lang_index += 1;
             } else {
                 warn!(
-                    "skipping TE {} P37 lang because it has no datavalue ID",
-                    obj_id
+                    "skipping {} {} lang because it has no datavalue ID",
+                    entity.id, rule.property
                 );
             }
         }
     }
+    Ok(())
+}
 
-    if let Some(labels) = json_get!(value(obj).labels: object) {
-        for label in labels.values() {
-            if let (Some(lang), Some(label)) = (
-                json_get!(value(label).language: string),
-                json_get!(value(label).value: string),
-            ) {
-                sink.send(DataEntry::ObjectLabel {
-                    id: obj_id.into(),
-                    lang: lang.into(),
-                    label: label.into(),
-                    native_order: None,
+fn handle_time_zones(
+    entity: &Entity,
+    time_zones: &[PropertyRule],
+    sink: &Sender<DataEntry>,
+) -> Result<(), HandleLineError> {
+    if let Some((rule, time_zones)) = rules::statements(entity, time_zones) {
+        let mut tz_index = 0;
+        for time_zone in time_zones {
+            let value = match time_zone.value() {
+                Some(value) => value,
+                None => continue,
+            };
+            if let Some(time_zone_id) = value.entity_id() {
+                sink.send(DataEntry::ObjectTimeZone {
+                    id: entity.id.clone(),
+                    time_zone_id: time_zone_id.into(),
+                    index: tz_index,
                 })?;
+                tz_index += 1;
             } else {
-                warn!("skipping {} label because it has invalid type", obj_id);
+                warn!(
+                    "skipping {} {} time zone because it has no datavalue ID",
+                    entity.id, rule.property
+                );
             }
         }
     }
+    Ok(())
+}
 
+fn handle_external_ids(
+    entity: &Entity,
+    external_ids: &[PropertyRule],
+    sink: &Sender<DataEntry>,
+) -> Result<(), HandleLineError> {
+    for rule in external_ids {
+        let statements = match rules::statements(entity, std::slice::from_ref(rule)) {
+            Some((_, statements)) => statements,
+            None => continue,
+        };
+        for statement in statements {
+            let value = match statement.value() {
+                Some(value) => value,
+                None => continue,
+            };
+            if let Some(value) = value.as_str() {
+                sink.send(DataEntry::ExternalId {
+                    id: entity.id.clone(),
+                    property: rule.property.clone(),
+                    value: value.into(),
+                })?;
+            } else {
+                warn!(
+                    "skipping {} {} external ID because it is not a string",
+                    entity.id, rule.property
+                );
+            }
+        }
+    }
     Ok(())
 }
 
-fn handle_language(obj: &Value, sink: &Sender<DataEntry>) -> Result<(), HandleLineError> {
-    let obj_id = json_get!(value(obj).id: string).unwrap();
-    if let Some(wikimedia_code) =
-        json_get!(value(obj).claims.P424[0].mainsnak.datavalue.value: string)
-    {
+fn handle_labels(entity: &Entity, sink: &Sender<DataEntry>) -> Result<(), HandleLineError> {
+    for label in entity.labels.values() {
+        sink.send(DataEntry::ObjectLabel {
+            id: entity.id.clone(),
+            lang: label.language.clone(),
+            label: label.value.clone(),
+            native_order: None,
+        })?;
+    }
+    Ok(())
+}
+
+/// Returns the value of the first statement for a column.
+fn first_value<'a>(entity: &'a Entity, column: &'a [PropertyRule]) -> Option<&'a DataValue> {
+    let (_, mut statements) = rules::statements(entity, column)?;
+    statements.next()?.value()
+}
+
+/// Returns the first value of a column that is in a known unit of the dimension, converted to the
+/// unit of the dimension.
+fn first_measure(
+    entity: &Entity,
+    column: &[PropertyRule],
+    dimension: Dimension,
+    units: &Units,
+) -> Option<f64> {
+    let (rule, statements) = rules::statements(entity, column)?;
+    for statement in statements {
+        let quantity = match statement.value() {
+            Some(value) => match value.quantity() {
+                Some(quantity) => quantity,
+                None => {
+                    warn!(
+                        "skipping {} {} value because it is not a quantity",
+                        entity.id, rule.property
+                    );
+                    continue;
+                }
+            },
+            None => continue,
+        };
+        match units.measure(quantity, dimension) {
+            Ok(measure) => return Some(measure.amount),
+            // e.g. all quantities of the truthy dump, which has no units
+            Err(QuantityError::Unit(_)) => (),
+            Err(e) => warn!("skipping {} {} value: {}", entity.id, rule.property, e),
+        }
+    }
+    None
+}
+
+fn handle_country(
+    entity: &Entity,
+    columns: &CountryColumns,
+    sink: &Sender<DataEntry>,
+) -> Result<(), HandleLineError> {
+    if let Some(iso) = first_value(entity, &columns.iso).and_then(DataValue::as_str) {
+        sink.send(DataEntry::Country {
+            id: entity.id.clone(),
+            iso: iso.to_ascii_lowercase(),
+        })?;
+    }
+
+    handle_languages(entity, &columns.languages, sink)
+}
+
+fn handle_territorial_entity(
+    entity: &Entity,
+    is_2nd: bool,
+    columns: &TerritorialEntityColumns,
+    sink: &Sender<DataEntry>,
+) -> Result<(), HandleLineError> {
+    let iso = if is_2nd {
+        first_value(entity, &columns.iso)
+            .and_then(DataValue::as_str)
+            .map(|s| s.to_string())
+    } else {
+        None
+    };
+
+    sink.send(DataEntry::TerritorialEntity {
+        id: entity.id.clone(),
+        is_2nd,
+        iso,
+    })?;
+
+    handle_place(entity, &columns.parents, sink)?;
+    handle_languages(entity, &columns.languages, sink)?;
+    handle_time_zones(entity, &columns.time_zones, sink)?;
+    handle_labels(entity, sink)?;
+
+    Ok(())
+}
+
+fn handle_language(
+    entity: &Entity,
+    columns: &LanguageColumns,
+    sink: &Sender<DataEntry>,
+) -> Result<(), HandleLineError> {
+    if let Some(wikimedia_code) = first_value(entity, &columns.code).and_then(DataValue::as_str) {
         sink.send(DataEntry::Language {
-            id: obj_id.into(),
+            id: entity.id.clone(),
             code: wikimedia_code.into(),
         })?;
     } else {
-        // warn!("skipping lang {} because it has no wikimedia language code", obj_id);
+        // warn!("skipping lang {} because it has no wikimedia language code", entity.id);
     }
     Ok(())
 }
 
-fn handle_human_settlement(obj: &Value, sink: &Sender<DataEntry>) -> Result<(), HandleLineError> {
-    let obj_id = json_get!(value(obj).id: string).unwrap();
-    let country_entries = match json_get!(value(obj).claims.P17: array) {
+fn handle_time_zone(
+    entity: &Entity,
+    columns: &TimeZoneColumns,
+    sink: &Sender<DataEntry>,
+) -> Result<(), HandleLineError> {
+    if let Some(tz) = first_value(entity, &columns.tz).and_then(DataValue::as_str) {
+        sink.send(DataEntry::TimeZone {
+            id: entity.id.clone(),
+            tz: tz.into(),
+        })?;
+    }
+    Ok(())
+}
+
+fn handle_human_settlement(
+    entity: &Entity,
+    format: EntityFormat,
+    columns: &HumanSettlementColumns,
+    units: &Units,
+    sink: &Sender<DataEntry>,
+) -> Result<(), HandleLineError> {
+    let obj_id = &entity.id;
+    let (country_rule, country_entries) = match rules::statements(entity, &columns.country) {
         Some(country_entries) => country_entries,
         None => {
-            sink.send(DataEntry::MissingP17 { id: obj_id.into() })?;
+            sink.send(DataEntry::MissingP17 { id: obj_id.clone() })?;
             return Ok(()); // we cannot use the entry without its country
         }
     };
 
-    handle_place(obj, sink)?;
-
-    for (i, country_entry) in country_entries.iter().enumerate() {
-        let qualifiers = json_get!(value(country_entry).qualifiers: object);
-        let start_active = is_object_start_active(qualifiers);
-        let end_active = is_object_end_active(qualifiers);
-        if end_active == Some(false) || start_active == Some(false) {
-            continue;
-        }
+    handle_place(entity, &columns.parents, sink)?;
 
+    for (i, country_entry) in country_entries.enumerate() {
         // prefer country entries with a start date over entries that don't have one
-        let priority = if start_active.is_some() {
+        let priority = if is_object_start_active(country_entry).is_some() {
             i as u32
         } else {
             1000 + i as u32
         };
 
-        if let Some(id) = json_get!(value(country_entry).mainsnak.datavalue.value.id: string) {
+        if let Some(id) = country_entry.value().and_then(DataValue::entity_id) {
             sink.send(DataEntry::CityCountry {
-                id: obj_id.into(),
+                id: obj_id.clone(),
                 country: id.into(),
                 priority,
             })
             .unwrap();
         } else {
             warn!(
-                "skipping HS {} P17 country entry because it has no datavalue id",
-                obj_id
+                "skipping HS {} {} country entry because it has no datavalue id",
+                obj_id, country_rule.property
             );
         }
     }
 
     let mut population = None;
     let mut population_time = None;
-    if let Some(population_entries) = json_get!(value(obj).claims.P1082: array) {
+    let mut population_date = None;
+    if let Some((rule, population_entries)) = rules::statements(entity, &columns.population) {
+        let property = &rule.property;
+        let mut best_rank = None;
+        let mut recorded = HashSet::new();
         for population_entry in population_entries {
             let mut new_population_time = None;
-            if let Some(population_time) =
-                json_get!(value(population_entry).qualifiers.P585[0]: object)
-            {
-                if json_get!((population_time).snaktype: string) != Some("value") {
+            let mut new_population_date = None;
+            let date = rule
+                .date_qualifier
+                .as_ref()
+                .and_then(|date_qualifier| population_entry.qualifier(date_qualifier));
+            if let Some(date) = date {
+                if date.snaktype != SnakType::Value {
                     continue;
                 }
-                if let Some(time_obj) = json_get!((population_time).datavalue.value: object) {
-                    if let (Some(time), Some(zone)) = (
-                        json_get!((time_obj).time: string),
-                        json_get!((time_obj).timezone: number),
-                    ) {
-                        if let Ok(time) = parse_wikidata_time(time, zone) {
-                            new_population_time = Some(time);
-                        }
-                    } else {
-                        warn!(
-                            "skipping {} P1082/P585 population entry because it has invalid time",
-                            obj_id
-                        );
+                if let Some(time) = date.value().and_then(DataValue::time) {
+                    if let Ok(parsed) = parse_wikidata_time(&time.time, time.timezone as f64) {
+                        new_population_time = Some(parsed);
+                        new_population_date = format_date(time);
                     }
                 } else {
                     warn!(
-                        "skipping {} P1082/P585 population entry because it has no time value",
-                        obj_id
+                        "skipping {} {} population entry because its date has no time value",
+                        obj_id, property
                     );
                 }
             } else {
-                // warn!("skipping {} P1082 population entry because it has no P585 entry", obj_id);
+                // warn!("skipping {} population entry because it has no date", obj_id);
             }
 
-            if let Some(_) = json_get!(value(population_entry).qualifiers.P518[0]: object) {
-                // "applies to part" - but we want the entire population
-                new_population_time = None; // reset to none
+            let value = match population_entry.value().and_then(DataValue::quantity) {
+                Some(quantity) => match units.measure(quantity, Dimension::Count) {
+                    Ok(measure) => measure.count(),
+                    // population is unitless!
+                    Err(QuantityError::Unit(_)) => continue,
+                    Err(e) => {
+                        warn!("skipping {} {} population entry: {}", obj_id, property, e);
+                        continue;
+                    }
+                },
+                None => {
+                    warn!(
+                        "skipping {} {} population entry because it is not a quantity",
+                        obj_id, property
+                    );
+                    continue;
+                }
+            };
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    warn!(
+                        "skipping {} {} population entry because it is negative",
+                        obj_id, property
+                    );
+                    continue;
+                }
+            };
+
+            let method = rule
+                .method_qualifier
+                .as_ref()
+                .and_then(|method_qualifier| population_entry.qualifier(method_qualifier))
+                .and_then(Snak::value)
+                .and_then(DataValue::entity_id);
+            let reference = rule
+                .reference_properties
+                .iter()
+                .find_map(|property| population_entry.reference_value(property))
+                .and_then(|value| value.as_str().or_else(|| value.entity_id()));
+            // the same population is sometimes stated several times, e.g. with different sources
+            if recorded.insert((value, new_population_date.clone())) {
+                sink.send(DataEntry::PopulationHistory {
+                    id: obj_id.clone(),
+                    population: value,
+                    date: new_population_date.clone(),
+                    method: method.map(String::from),
+                    reference: reference.map(String::from),
+                })?;
             }
-            if let Some(_) = json_get!(value(population_entry).qualifiers.P1539[0]: object) {
-                // this is only the female population
-                new_population_time = None; // reset to none
+
+            // the truthy dump has no dates, but only contains the preferred population anyway
+            if new_population_time.is_none() && format != EntityFormat::Truthy {
+                continue;
             }
-            if let Some(_) = json_get!(value(population_entry).qualifiers.P1540[0]: object) {
-                // this is only the male population
-                new_population_time = None; // reset to none
+
+            // only the preferred populations that can be picked are picked from, if there are any
+            if *best_rank.get_or_insert(population_entry.rank) != population_entry.rank {
+                continue;
             }
 
-            if let Some(new_time) = new_population_time {
-                if population_time
-                    .as_ref()
-                    .map_or(true, |old| new_time >= *old)
-                {
-                    if let (Some(value), Some(unit)) = (
-                        json_get!(value(population_entry).mainsnak.datavalue.value.amount: string),
-                        json_get!(value(population_entry).mainsnak.datavalue.value.unit: string),
-                    ) {
-                        // wikidata population is stored as "value" and "unit" strings
-                        if unit != "1" {
-                            // population is unitless!
-                            continue;
-                        }
-
-                        if let Some(value) = parse_quantity(value) {
-                            population = Some(value);
-                            population_time = Some(new_time);
-                        } else {
-                            warn!("skipping {} P1082 population entry because its amount value could not be parsed as a number", obj_id);
-                        }
-                    } else {
-                        warn!("skipping {} P1082 population entry because its amount value either does not exist or is an unexpected type", obj_id);
-                    }
-                }
+            let is_newer = match new_population_time {
+                Some(new_time) => population_time.as_ref().is_none_or(|old| new_time >= *old),
+                None => population.is_none(),
+            };
+            if is_newer {
+                population = Some(value);
+                population_time = new_population_time;
+                population_date = new_population_date;
             }
         }
     }
 
     let mut lat_lon = None;
-    if let Some(coords) = json_get!(value(obj).claims.P625[0].mainsnak: object) {
-        if json_get!((coords).snaktype: string) == Some("value") {
-            if let (Some(lat), Some(lon)) = (
-                json_get!((coords).datavalue.value.latitude: number),
-                json_get!((coords).datavalue.value.longitude: number),
-            ) {
-                lat_lon = Some((lat, lon));
-            } else {
-                warn!(
-                    "skipping {} lat/lon because lat/lon are invalid types",
-                    obj_id
-                );
-            }
+    let coords = rules::statements(entity, &columns.coordinates)
+        .and_then(|(_, mut statements)| statements.next())
+        .and_then(Statement::value);
+    if let Some(coords) = coords {
+        if let Some(coords) = coords.globe_coordinate() {
+            lat_lon = Some((coords.latitude, coords.longitude));
+        } else {
+            warn!(
+                "skipping {} lat/lon because it is not a globe coordinate",
+                obj_id
+            );
         }
     } else {
-        // warn!("skipping {} lat/lon because it has no P625 entry", obj_id);
+        // warn!("skipping {} lat/lon because it has no coordinates", obj_id);
     }
 
     sink.send(DataEntry::City {
-        id: obj_id.into(),
+        id: obj_id.clone(),
         population,
+        population_date,
         lat: lat_lon.map(|(lat, _)| lat),
         lon: lat_lon.map(|(_, lon)| lon),
+        elevation: first_measure(entity, &columns.elevation, Dimension::Length, units),
+        area: first_measure(entity, &columns.area, Dimension::Area, units),
     })?;
 
-    if let Some(labels) = json_get!(value(obj).labels: object) {
-        for label in labels.values() {
-            if let (Some(lang), Some(label)) = (
-                json_get!(value(label).language: string),
-                json_get!(value(label).value: string),
-            ) {
-                sink.send(DataEntry::ObjectLabel {
-                    id: obj_id.into(),
-                    lang: lang.into(),
-                    label: label.into(),
-                    native_order: None,
-                })?;
-            } else {
-                warn!("skipping {} label because it has invalid type", obj_id);
-            }
-        }
-    }
+    handle_time_zones(entity, &columns.time_zones, sink)?;
+    handle_external_ids(entity, &columns.external_ids, sink)?;
+    handle_labels(entity, sink)?;
 
     // Insert native labels
-    let mut native_order_index = 0;
-    if let Some(native_labels) = json_get!(value(obj).claims.P1705: array) {
+    if let Some((rule, native_labels)) = rules::statements(entity, &columns.native_labels) {
+        let mut native_order_index = 0;
         for claim in native_labels {
-            if let (Some(lang), Some(label)) = (
-                json_get!(value(claim).mainsnak.datavalue.value.language: string),
-                json_get!(value(claim).mainsnak.datavalue.value.text: string),
-            ) {
+            if let Some(text) = claim.value().and_then(DataValue::monolingual_text) {
                 sink.send(DataEntry::ObjectLabel {
-                    id: obj_id.into(),
-                    lang: lang.into(),
-                    label: label.into(),
+                    id: obj_id.clone(),
+                    lang: text.language.clone(),
+                    label: text.text.clone(),
                     native_order: Some(native_order_index),
                 })?;
                 native_order_index += 1;
             } else {
                 warn!(
-                    "skipping {} P1705 native label because it has invalid type",
-                    obj_id
-                );
-            }
-        }
-    } else if let Some(official_names) = json_get!(value(obj).claims.P1448: array) {
-        for claim in official_names {
-            if !is_object_active(json_get!(value(claim).qualifiers: object)) {
-                continue;
-            }
-            if let (Some(lang), Some(label)) = (
-                json_get!(value(claim).mainsnak.datavalue.value.language: string),
-                json_get!(value(claim).mainsnak.datavalue.value.text: string),
-            ) {
-                sink.send(DataEntry::ObjectLabel {
-                    id: obj_id.into(),
-                    lang: lang.into(),
-                    label: label.into(),
-                    native_order: Some(native_order_index),
-                })?;
-                native_order_index += 1;
-            } else {
-                warn!(
-                    "skipping {} P1448 native label because it has invalid type",
-                    obj_id
+                    "skipping {} {} native label because it has invalid type",
+                    obj_id, rule.property
                 );
             }
         }
@@ -333,101 +487,177 @@ fn handle_human_settlement(obj: &Value, sink: &Sender<DataEntry>) -> Result<(),
     Ok(())
 }
 
+/// Handles one entity: a line of the JSON dump, or all triples of the entity in the truthy dump.
 pub fn handle_line(
     mut line: &str,
+    format: EntityFormat,
+    wikibase: &Wikibase,
+    rules: &Rules,
     classes: &Classes,
     sink: &Sender<DataEntry>,
     debug: bool,
 ) -> Result<(), HandleLineError> {
-    if line.len() <= 1 {
-        // this is an empty line or one of the [ or ] array boundary lines
-        return Ok(());
+    let entity: Entity = match format {
+        EntityFormat::Json => {
+            if line.len() <= 1 {
+                // this is an empty line or one of the [ or ] array boundary lines
+                return Ok(());
+            }
+
+            if line.ends_with(',') {
+                line = &line[..line.len() - 1];
+            }
+            serde_json::from_str(line)?
+        }
+        EntityFormat::Truthy => match wiki_triples::parse_entity(line, wikibase)? {
+            Some(entity) => entity,
+            None => return Ok(()),
+        },
+    };
+    handle_entity(entity, format, wikibase, rules, classes, sink, debug)
+}
+
+/// Like [`handle_line`], but lines of the JSON dump are parsed in place with simd-json if the
+/// `simd-json` feature is enabled. The line is left empty in that case, so that its buffer can
+/// still be reused.
+// only needs a str without the feature
+#[cfg_attr(not(feature = "simd-json"), allow(clippy::ptr_arg))]
+pub fn handle_line_mut(
+    line: &mut String,
+    format: EntityFormat,
+    wikibase: &Wikibase,
+    rules: &Rules,
+    classes: &Classes,
+    sink: &Sender<DataEntry>,
+    debug: bool,
+) -> Result<(), HandleLineError> {
+    #[cfg(feature = "simd-json")]
+    if format == EntityFormat::Json {
+        return match simd::parse_line(line)? {
+            Some(entity) => handle_entity(entity, format, wikibase, rules, classes, sink, debug),
+            None => Ok(()),
+        };
     }
+    handle_line(line, format, wikibase, rules, classes, sink, debug)
+}
+
+#[cfg(feature = "simd-json")]
+mod simd {
+    use super::HandleLineError;
+    use crate::wiki_entity::Entity;
+    use simd_json::Buffers;
+    use std::cell::RefCell;
 
-    if line.ends_with(',') {
-        line = &line[..line.len() - 1];
+    thread_local! {
+        /// Scratch space of the parser, which would otherwise be allocated for every line.
+        static BUFFERS: RefCell<Buffers> = RefCell::new(Buffers::default());
     }
-    let obj: Value = serde_json::from_str(line)?;
-    let obj_id = json_get!(value(obj).id: string).expect("object has no id!");
 
-    // P1366 replaced by but not P518 applies to part
-    let replaced_by = json_get!(value(obj).claims.P1366: array).map_or(false, |a| {
-        let replaced_by = !a.is_empty();
-        let mut applies_to_part = false;
-        for item in a {
-            if json_get!(value(item).qualifiers.P518: array).map_or(false, |a| !a.is_empty()) {
-                applies_to_part = true;
-                break;
-            }
+    /// Parses a line of the JSON dump, which simd-json overwrites while parsing it.
+    pub fn parse_line(line: &mut String) -> Result<Option<Entity>, HandleLineError> {
+        if line.len() <= 1 {
+            // this is an empty line or one of the [ or ] array boundary lines
+            return Ok(None);
         }
-        replaced_by && !applies_to_part
-    });
 
-    if replaced_by || json_get!(value(obj).claims.P576: array).map_or(false, |a| !a.is_empty()) {
-        // P1366: "replaced by"
-        // P576: "dissolved date"
-        // -> don't care about this object
-        if debug {
-            println!("entity has P1366 (replaced by) or P576 (dissolved date); skipping");
+        if line.ends_with(',') {
+            line.pop();
         }
-        return Ok(());
+        let mut bytes = std::mem::take(line).into_bytes();
+        let entity = BUFFERS.with(|buffers| {
+            simd_json::serde::from_slice_with_buffers(&mut bytes, &mut buffers.borrow_mut())
+        });
+        // the contents may no longer be valid UTF-8
+        bytes.clear();
+        *line = String::from_utf8(bytes).expect("empty line is not valid UTF-8");
+        Ok(Some(entity?))
     }
 
-    if let Some(code_entries) = json_get!(value(obj).claims.P297: array) {
-        let mut code_entry = None;
-        for entry in code_entries {
-            if is_object_active(json_get!(value(entry).qualifiers: object)) {
-                code_entry = Some(entry);
-                break;
-            }
-        }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn parses_like_serde_json() {
+            let json = r#"{"type":"item","id":"Q64","labels":{"de":{"language":"de","value":"Berl\u00edn \"B\""}},"claims":{"P1082":[{"mainsnak":{"snaktype":"value","property":"P1082","datavalue":{"value":{"amount":"+3644826","unit":"1"},"type":"quantity"}},"type":"statement","qualifiers":{"P585":[{"snaktype":"value","datavalue":{"value":{"time":"+2018-12-31T00:00:00Z","timezone":0,"precision":11},"type":"time"}}]},"rank":"preferred"}],"P625":[{"mainsnak":{"snaktype":"value","property":"P625","datavalue":{"value":{"latitude":52.516666666667,"longitude":13,"altitude":null,"precision":0.00027777777777778,"globe":"http://www.wikidata.org/entity/Q2"},"type":"globecoordinate"}},"type":"statement","rank":"normal"}],"P17":[{"mainsnak":{"snaktype":"novalue","property":"P17"},"type":"statement","rank":"normal"}]},"sitelinks":{}}"#;
+            let expected: Entity = serde_json::from_str(json).unwrap();
 
-        if let Some(iso) = json_get!(optval(code_entry).mainsnak.datavalue.value: string) {
-            sink.send(DataEntry::Country {
-                id: obj_id.into(),
-                iso: iso.to_ascii_lowercase(),
-            })?;
+            let mut line = format!("{},", json);
+            let capacity = line.capacity();
+            assert_eq!(parse_line(&mut line).unwrap(), Some(expected));
+            assert!(line.is_empty());
+            assert_eq!(line.capacity(), capacity);
+
+            assert_eq!(parse_line(&mut "[".to_string()).unwrap(), None);
+            assert!(parse_line(&mut r#"{"id":"Q1","claims":"#.to_string()).is_err());
         }
+    }
+}
 
-        let mut lang_index = 0;
-        if let Some(langs) = json_get!(value(obj).claims.P37: array) {
-            for lang in langs {
-                if !is_object_active(json_get!(value(lang).qualifiers: object)) {
-                    continue;
-                }
-                if let Some(lang_id) = json_get!(value(lang).mainsnak.datavalue.value.id: string) {
-                    sink.send(DataEntry::ObjectLanguage {
-                        id: obj_id.into(),
-                        lang_id: lang_id.into(),
-                        index: lang_index,
-                    })?;
-                    lang_index += 1;
-                }
-            }
+/// Handles a parsed entity.
+fn handle_entity(
+    mut entity: Entity,
+    format: EntityFormat,
+    wikibase: &Wikibase,
+    rules: &Rules,
+    classes: &Classes,
+    sink: &Sender<DataEntry>,
+    debug: bool,
+) -> Result<(), HandleLineError> {
+    wikibase.map_to_wikidata(&mut entity);
+    let entity = &entity;
+
+    if rules.is_skipped(entity) {
+        // e.g. P1366 (replaced by) or P576 (dissolved date)
+        // -> don't care about this object
+        if debug {
+            println!("entity has a property that is skipped");
         }
+        return Ok(());
     }
 
+    if rules::statements(entity, &rules.country.columns.iso).is_some() {
+        handle_country(entity, &rules.country.columns, sink)?;
+    }
+    if rules::statements(entity, &rules.time_zone.columns.tz).is_some() {
+        handle_time_zone(entity, &rules.time_zone.columns, sink)?;
+    }
+
+    let instance_of = &rules.instance_of;
     let is_territorial_entity = is_subclass_of(
-        &obj,
+        entity,
+        instance_of,
         &classes.territorial_entities,
         debug,
         "territorial entity",
     );
-    let is_human_settlement =
-        is_subclass_of(&obj, &classes.human_settlements, debug, "human settlement");
-    let is_excluded = is_subclass_of(&obj, &classes.excluded, debug, "excluded");
-    let is_language = is_subclass_of(&obj, &classes.languages, debug, "languages");
+    let is_human_settlement = is_subclass_of(
+        entity,
+        instance_of,
+        &classes.human_settlements,
+        debug,
+        "human settlement",
+    );
+    let is_language = is_subclass_of(entity, instance_of, &classes.languages, debug, "languages");
 
     if debug {
         info!("is territorial entity: {is_territorial_entity}");
         info!("is human settlement: {is_human_settlement}");
-        info!("is excluded: {is_excluded}");
         info!("is language: {is_language}");
     }
 
-    if is_territorial_entity && !is_excluded {
+    if is_territorial_entity
+        && !is_subclass_of(
+            entity,
+            instance_of,
+            &classes.excluded_territorial_entities,
+            debug,
+            "excluded territorial entities",
+        )
+    {
         let is_2nd = is_subclass_of(
-            &obj,
+            entity,
+            instance_of,
             &classes.second_level_admin_div,
             debug,
             "second level admin div",
@@ -437,12 +667,12 @@ pub fn handle_line(
             info!("is a non-excluded territorial entity - calling handler");
         }
 
-        handle_territorial_entity(&obj, is_2nd, sink)?;
+        handle_territorial_entity(entity, is_2nd, &rules.territorial_entity.columns, sink)?;
     }
     if is_human_settlement
-        && !is_excluded
         && !is_subclass_of(
-            &obj,
+            entity,
+            instance_of,
             &classes.excluded_settlements,
             debug,
             "excluded settlements",
@@ -452,40 +682,41 @@ pub fn handle_line(
             info!("is a non-excluded human settlement - calling handler");
         }
 
-        handle_human_settlement(&obj, sink)?;
+        handle_human_settlement(
+            entity,
+            format,
+            &rules.human_settlement.columns,
+            &rules.units,
+            sink,
+        )?;
     }
     if is_language {
-        handle_language(&obj, sink)?;
+        handle_language(entity, &rules.language.columns, sink)?;
     }
 
     Ok(())
 }
 
-fn is_subclass_of(obj: &Value, classes: &HashSet<String>, debug: bool, debug_label: &str) -> bool {
+fn is_subclass_of(
+    entity: &Entity,
+    instance_of: &PropertyRule,
+    classes: &HashSet<String>,
+    debug: bool,
+    debug_label: &str,
+) -> bool {
     if debug {
         info!("checking - is object subclass of {debug_label}?");
     }
 
-    if let Some(parents) = json_get!(value(obj).claims.P31: array) {
-        for parent in parents {
-            if let Some(id) = json_get!(value(parent).mainsnak.datavalue.value.id: string) {
-                if classes.contains(id) {
-                    // check if this relation is expired
-                    if !is_object_active(json_get!(value(parent).qualifiers: object)) {
-                        continue;
-                    }
-
-                    if json_get!(value(parent).qualifiers.P1366[0]: object).is_some() {
-                        // P1366: "replaced by"
-                        continue;
-                    }
-
-                    if debug {
-                        info!("is a subclass because class {id} is in the set");
-                    }
-
-                    return true;
+    for parent in entity.statements(&instance_of.property).unwrap_or_default() {
+        if let Some(id) = parent.value().and_then(DataValue::entity_id) {
+            // check if this relation is expired or has been replaced
+            if classes.contains(id) && instance_of.accepts(parent) {
+                if debug {
+                    info!("is a subclass because class {id} is in the set");
                 }
+
+                return true;
             }
         }
     }
@@ -500,24 +731,271 @@ fn is_subclass_of(obj: &Value, classes: &HashSet<String>, debug: bool, debug_lab
 pub enum HandleLineError {
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
+    #[cfg(feature = "simd-json")]
+    #[error("JSON error: {0}")]
+    SimdJson(#[from] simd_json::Error),
+    #[error("N-Triples error: {0}")]
+    Triples(#[from] TripleError),
     #[error("crossbeam channel send error: {0}")]
-    Sink(#[from] crossbeam::channel::SendError<DataEntry>),
+    Sink(Box<crossbeam::channel::SendError<DataEntry>>),
 }
 
-fn parse_quantity(n: &str) -> Option<u64> {
-    let should_keep_char = |c: &char| match c {
-        c if c.is_whitespace() => false,
-        ',' | '.' | '+' => false, // thousands separators and leading +
-        _ => true,
-    };
+// boxed, since it contains the entry that could not be sent
+impl From<crossbeam::channel::SendError<DataEntry>> for HandleLineError {
+    fn from(error: crossbeam::channel::SendError<DataEntry>) -> Self {
+        HandleLineError::Sink(Box::new(error))
+    }
+}
 
-    if n.contains(|c| !should_keep_char(&c)) {
-        n.chars()
-            .filter(should_keep_char)
-            .collect::<String>()
-            .parse()
-            .ok()
-    } else {
-        n.parse().ok()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{classes, ranked_statement};
+    use serde_json::{json, Value};
+
+    fn population(rank: &str, amount: u64, year: u32) -> Value {
+        let mut statement = ranked_statement(
+            rank,
+            json!({ "amount": format!("+{}", amount), "unit": "1" }),
+        );
+        statement["qualifiers"] = json!({ "P585": [{ "snaktype": "value", "datavalue": { "value": {
+            "time": format!("+{}-01-01T00:00:00Z", year), "timezone": 0
+        } } }] });
+        statement
+    }
+
+    fn entries(entity: Value) -> Vec<DataEntry> {
+        let (send, recv) = crossbeam::channel::unbounded();
+        handle_line(
+            &entity.to_string(),
+            EntityFormat::Json,
+            &Wikibase::default(),
+            &Rules::default(),
+            &classes(),
+            &send,
+            false,
+        )
+        .unwrap();
+        drop(send);
+        recv.into_iter().collect()
+    }
+
+    #[test]
+    fn guesses_formats_from_file_names() {
+        for (name, format) in [
+            ("latest-truthy.nt.bz2", EntityFormat::Truthy),
+            ("/dumps/latest-truthy.nt", EntityFormat::Truthy),
+            ("https://example.org/truthy.nt.gz?x=1", EntityFormat::Truthy),
+            ("latest-all.json.bz2", EntityFormat::Json),
+            ("/mnt/dumps.ntfs/latest-all.json.bz2", EntityFormat::Json),
+            (
+                "https://host.nt.example/latest-all.json.zst",
+                EntityFormat::Json,
+            ),
+            ("latest.ntriples.bz2", EntityFormat::Json),
+        ] {
+            assert_eq!(EntityFormat::from_file_name(name), format, "{}", name);
+        }
+    }
+
+    #[test]
+    fn honors_ranks() {
+        let coordinates = |latitude: f64| json!({ "latitude": latitude, "longitude": 13. });
+        let entries = entries(json!({
+            "id": "Q1",
+            "claims": {
+                "P31": [ranked_statement("normal", json!({ "id": "Q515" }))],
+                "P17": [
+                    ranked_statement("deprecated", json!({ "id": "Q2" })),
+                    ranked_statement("normal", json!({ "id": "Q3" })),
+                    ranked_statement("preferred", json!({ "id": "Q4" })),
+                ],
+                "P1082": [
+                    population("deprecated", 5000, 2021),
+                    population("normal", 3000, 2020),
+                    population("preferred", 1000, 2010),
+                    population("preferred", 2000, 2015),
+                ],
+                "P625": [
+                    ranked_statement("normal", coordinates(1.)),
+                    ranked_statement("preferred", coordinates(2.)),
+                ],
+                // deprecated statements don't make it a dissolved city
+                "P576": [ranked_statement("deprecated", json!({ "time": "+2000-01-01T00:00:00Z" }))],
+            },
+        }));
+
+        let countries: Vec<_> = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                DataEntry::CityCountry {
+                    country, priority, ..
+                } => Some((country.as_str(), *priority)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(countries, [("Q4", 1000), ("Q3", 1001)]);
+
+        let city = entries.iter().find_map(|entry| match entry {
+            DataEntry::City {
+                population, lat, ..
+            } => Some((*population, *lat)),
+            _ => None,
+        });
+        assert_eq!(city, Some((Some(2000), Some(2.))));
+
+        // an undated preferred population can't be picked, so the dated normal ones are used
+        let undated = self::entries(json!({
+            "id": "Q5",
+            "claims": {
+                "P31": [ranked_statement("normal", json!({ "id": "Q515" }))],
+                "P17": [ranked_statement("normal", json!({ "id": "Q4" }))],
+                "P1082": [
+                    ranked_statement("preferred", json!({ "amount": "+9000", "unit": "1" })),
+                    population("normal", 3000, 2020),
+                    population("normal", 1000, 2010),
+                ],
+            },
+        }));
+        let population = undated.iter().find_map(|entry| match entry {
+            DataEntry::City { population, .. } => Some(*population),
+            _ => None,
+        });
+        assert_eq!(population, Some(Some(3000)));
+    }
+
+    #[test]
+    fn converts_elevations_and_areas() {
+        let quantity = |amount: &str, unit: &str| {
+            ranked_statement(
+                "normal",
+                json!({ "amount": amount, "unit": format!("http://www.wikidata.org/entity/{}", unit) }),
+            )
+        };
+        let mut part = quantity("+12", "Q712226");
+        part["qualifiers"] = json!({ "P518": [{ "snaktype": "value" }] });
+        let entries = entries(json!({
+            "id": "Q1",
+            "claims": {
+                "P31": [ranked_statement("normal", json!({ "id": "Q515" }))],
+                "P17": [ranked_statement("normal", json!({ "id": "Q2" }))],
+                "P2044": [quantity("+100", "Q11574"), quantity("+1000", "Q3710")],
+                "P2046": [part, quantity("+250", "Q35852")],
+                "P421": [ranked_statement("normal", json!({ "id": "Q6655" }))],
+            },
+        }));
+
+        let city = entries.iter().find_map(|entry| match entry {
+            DataEntry::City {
+                elevation, area, ..
+            } => Some((*elevation, *area)),
+            _ => None,
+        });
+        let (elevation, area) = city.unwrap();
+        assert!((elevation.unwrap() - 304.8).abs() < 1e-9);
+        assert!((area.unwrap() - 2.5).abs() < 1e-9);
+        assert!(entries.iter().any(|entry| matches!(
+            entry,
+            DataEntry::ObjectTimeZone { time_zone_id, index: 0, .. } if time_zone_id == "Q6655"
+        )));
+    }
+
+    #[test]
+    fn extracts_external_ids() {
+        let entries = entries(json!({
+            "id": "Q1",
+            "claims": {
+                "P31": [ranked_statement("normal", json!({ "id": "Q515" }))],
+                "P17": [ranked_statement("normal", json!({ "id": "Q2" }))],
+                "P1566": [
+                    ranked_statement("normal", json!("2")),
+                    ranked_statement("preferred", json!("1")),
+                    ranked_statement("deprecated", json!("3")),
+                ],
+                "P439": [ranked_statement("normal", json!("11000000"))],
+                "P402": [ranked_statement("normal", json!({ "id": "Q3" }))],
+                // not an external ID in the rules
+                "P214": [ranked_statement("normal", json!("4"))],
+            },
+        }));
+
+        let ids: Vec<_> = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                DataEntry::ExternalId {
+                    property, value, ..
+                } => Some((property.as_str(), value.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ids, [("P1566", "1"), ("P1566", "2"), ("P439", "11000000")]);
+    }
+
+    #[test]
+    fn records_population_history() {
+        let mut census = population("normal", 1000, 2010);
+        census["qualifiers"]["P585"][0]["datavalue"]["value"]["precision"] = json!(9);
+        census["qualifiers"]["P459"] =
+            json!([{ "snaktype": "value", "datavalue": { "value": { "id": "Q39825" } } }]);
+        census["references"] = json!([
+            { "snaks": { "P248": [{ "snaktype": "value", "datavalue": { "value": { "id": "Q5" } } }] } },
+            { "snaks": { "P854": [{ "snaktype": "value", "datavalue": { "value": "https://example.org" } }] } },
+        ]);
+        let mut part = population("normal", 10, 2022);
+        part["qualifiers"]["P518"] = json!([{ "snaktype": "value" }]);
+        let entries = entries(json!({
+            "id": "Q1",
+            "claims": {
+                "P31": [ranked_statement("normal", json!({ "id": "Q515" }))],
+                "P17": [ranked_statement("normal", json!({ "id": "Q2" }))],
+                "P1082": [
+                    census,
+                    population("normal", 1200, 2020),
+                    population("normal", 1200, 2020),
+                    part,
+                ],
+            },
+        }));
+
+        let history: Vec<_> = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                DataEntry::PopulationHistory {
+                    population,
+                    date,
+                    method,
+                    reference,
+                    ..
+                } => Some((
+                    *population,
+                    date.as_deref(),
+                    method.as_deref(),
+                    reference.as_deref(),
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            history,
+            [
+                (
+                    1000,
+                    Some("2010"),
+                    Some("Q39825"),
+                    Some("https://example.org")
+                ),
+                (1200, Some("2020-01-01"), None, None),
+            ]
+        );
+
+        let city = entries.iter().find_map(|entry| match entry {
+            DataEntry::City {
+                population,
+                population_date,
+                ..
+            } => Some((*population, population_date.as_deref())),
+            _ => None,
+        });
+        assert_eq!(city, Some((Some(1200), Some("2020-01-01"))));
     }
 }
@@ -0,0 +1,322 @@
+//! Typed model of the entities in the JSON dump.
+//!
+//! Only the fields that are used for extraction are deserialized; everything else (descriptions,
+//! aliases, sitelinks, the hashes of statements, …) is skipped without being materialized.
+
+use serde::de::{self, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Entity {
+    pub id: String,
+    #[serde(default)]
+    pub labels: HashMap<String, Label>,
+    #[serde(default)]
+    pub claims: HashMap<String, Vec<Statement>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Label {
+    pub language: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Statement {
+    pub mainsnak: Snak,
+    #[serde(default)]
+    pub qualifiers: HashMap<String, Vec<Snak>>,
+    #[serde(default)]
+    pub rank: Rank,
+    #[serde(default)]
+    pub references: Vec<Reference>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Reference {
+    pub snaks: HashMap<String, Vec<Snak>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rank {
+    Preferred,
+    #[default]
+    Normal,
+    /// Known to be wrong, e.g. a disproven population.
+    Deprecated,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Snak {
+    pub snaktype: SnakType,
+    #[serde(default, deserialize_with = "deserialize_datavalue")]
+    pub datavalue: Option<DataValue>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnakType {
+    Value,
+    /// An unknown value.
+    SomeValue,
+    NoValue,
+}
+
+/// The value of a snak. The variant is determined by the fields of the value rather than by the
+/// `type` of the data value, which the converted entities of the truthy dump don't have.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataValue {
+    EntityId(String),
+    String(String),
+    Time(Time),
+    Quantity(Quantity),
+    GlobeCoordinate(GlobeCoordinate),
+    MonolingualText(MonolingualText),
+    /// A type of value that is not used for extraction.
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Time {
+    /// e.g. `+2021-01-01T00:00:00Z`
+    pub time: String,
+    /// Offset from UTC in minutes.
+    pub timezone: i64,
+    /// 9 for a year, 10 for a month, 11 for a day.
+    pub precision: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantity {
+    /// A decimal with a sign, e.g. `+1234`.
+    pub amount: String,
+    /// The range of the amount, if it is uncertain.
+    pub lower_bound: Option<String>,
+    pub upper_bound: Option<String>,
+    /// `1` for unitless quantities, or the IRI of the unit's item.
+    pub unit: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobeCoordinate {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonolingualText {
+    pub language: String,
+    pub text: String,
+}
+
+impl Entity {
+    /// Returns the statements for a property, if the entity has any.
+    pub fn statements(&self, property: &str) -> Option<&[Statement]> {
+        self.claims.get(property).map(Vec::as_slice)
+    }
+}
+
+impl Statement {
+    /// Returns the first qualifier of the given property.
+    pub fn qualifier(&self, property: &str) -> Option<&Snak> {
+        self.qualifiers.get(property)?.first()
+    }
+
+    pub fn has_qualifier(&self, property: &str) -> bool {
+        self.qualifiers
+            .get(property)
+            .is_some_and(|snaks| !snaks.is_empty())
+    }
+
+    /// Returns the value of the statement, unless it is unknown or has no value.
+    pub fn value(&self) -> Option<&DataValue> {
+        self.mainsnak.value()
+    }
+
+    /// Returns the first value of the given property in any of the references.
+    pub fn reference_value(&self, property: &str) -> Option<&DataValue> {
+        self.references
+            .iter()
+            .filter_map(|reference| reference.snaks.get(property))
+            .flatten()
+            .find_map(Snak::value)
+    }
+}
+
+impl Snak {
+    pub fn value(&self) -> Option<&DataValue> {
+        match self.snaktype {
+            SnakType::Value => self.datavalue.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl DataValue {
+    pub fn entity_id(&self) -> Option<&str> {
+        match self {
+            DataValue::EntityId(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            DataValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn time(&self) -> Option<&Time> {
+        match self {
+            DataValue::Time(time) => Some(time),
+            _ => None,
+        }
+    }
+
+    pub fn quantity(&self) -> Option<&Quantity> {
+        match self {
+            DataValue::Quantity(quantity) => Some(quantity),
+            _ => None,
+        }
+    }
+
+    pub fn globe_coordinate(&self) -> Option<&GlobeCoordinate> {
+        match self {
+            DataValue::GlobeCoordinate(coords) => Some(coords),
+            _ => None,
+        }
+    }
+
+    pub fn monolingual_text(&self) -> Option<&MonolingualText> {
+        match self {
+            DataValue::MonolingualText(text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the `value` of a data value and ignores its `type`.
+fn deserialize_datavalue<'de, D>(deserializer: D) -> Result<Option<DataValue>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct RawDataValue {
+        value: DataValue,
+    }
+    Ok(Some(RawDataValue::deserialize(deserializer)?.value))
+}
+
+/// The fields of all types of values that are used.
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "lowercase")]
+enum ValueField {
+    Id,
+    Time,
+    Timezone,
+    Precision,
+    Amount,
+    #[serde(rename = "lowerBound")]
+    LowerBound,
+    #[serde(rename = "upperBound")]
+    UpperBound,
+    Unit,
+    Latitude,
+    Longitude,
+    Language,
+    Text,
+    #[serde(other)]
+    Other,
+}
+
+impl<'de> Deserialize<'de> for DataValue {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(DataValueVisitor)
+    }
+}
+
+struct DataValueVisitor;
+
+impl<'de> Visitor<'de> for DataValueVisitor {
+    type Value = DataValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a data value")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<DataValue, E> {
+        Ok(DataValue::String(v.into()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<DataValue, E> {
+        Ok(DataValue::String(v))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<DataValue, A::Error> {
+        let mut id = None;
+        let mut time = None;
+        let mut timezone = None;
+        let mut precision = None;
+        let mut amount = None;
+        let mut lower_bound = None;
+        let mut upper_bound = None;
+        let mut unit = None;
+        let mut latitude = None;
+        let mut longitude = None;
+        let mut language = None;
+        let mut text = None;
+        while let Some(field) = map.next_key()? {
+            match field {
+                ValueField::Id => id = Some(map.next_value()?),
+                ValueField::Time => time = Some(map.next_value()?),
+                ValueField::Timezone => timezone = Some(map.next_value()?),
+                // also in globe coordinates, where it is a fraction of a degree or null
+                ValueField::Precision => precision = map.next_value::<Option<f64>>()?,
+                ValueField::Amount => amount = Some(map.next_value()?),
+                ValueField::LowerBound => lower_bound = Some(map.next_value()?),
+                ValueField::UpperBound => upper_bound = Some(map.next_value()?),
+                ValueField::Unit => unit = Some(map.next_value()?),
+                ValueField::Latitude => latitude = map.next_value()?,
+                ValueField::Longitude => longitude = map.next_value()?,
+                ValueField::Language => language = Some(map.next_value()?),
+                ValueField::Text => text = Some(map.next_value()?),
+                ValueField::Other => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        Ok(match (id, time, amount, latitude.zip(longitude), text) {
+            (Some(id), ..) => DataValue::EntityId(id),
+            (_, Some(time), ..) => DataValue::Time(Time {
+                time,
+                timezone: timezone.unwrap_or(0),
+                precision: precision.map_or(11, |precision| precision as u8),
+            }),
+            (_, _, Some(amount), ..) => DataValue::Quantity(Quantity {
+                amount,
+                lower_bound,
+                upper_bound,
+                unit: unit.unwrap_or_else(|| "1".into()),
+            }),
+            (_, _, _, Some((latitude, longitude)), _) => {
+                DataValue::GlobeCoordinate(GlobeCoordinate {
+                    latitude,
+                    longitude,
+                })
+            }
+            (.., Some(text)) => match language {
+                Some(language) => DataValue::MonolingualText(MonolingualText { language, text }),
+                None => return Err(de::Error::missing_field("language")),
+            },
+            _ => DataValue::Other,
+        })
+    }
+}
@@ -0,0 +1,183 @@
+//! Quantities of the dump: decimal amounts with optional bounds and a unit, which are converted
+//! to a common unit for each dimension.
+
+use crate::wiki_entity::Quantity;
+use serde::Deserialize;
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum QuantityError {
+    #[error("invalid decimal {0:?}")]
+    Decimal(String),
+    #[error("unknown unit {0}")]
+    Unit(String),
+}
+
+/// What a quantity measures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dimension {
+    /// Unitless, e.g. a population.
+    Count,
+    /// In metres.
+    Length,
+    /// In square kilometres.
+    Area,
+}
+
+/// Factors that convert amounts to the unit of a dimension, by the ID of the unit's item (the
+/// last segment of the unit IRI).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Units {
+    #[serde(default)]
+    pub length: HashMap<String, f64>,
+    #[serde(default)]
+    pub area: HashMap<String, f64>,
+}
+
+/// A quantity in the unit of its dimension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measure {
+    pub amount: f64,
+    pub lower_bound: Option<f64>,
+    pub upper_bound: Option<f64>,
+}
+
+impl Units {
+    /// Converts a quantity to the unit of the dimension.
+    pub fn measure(
+        &self,
+        quantity: &Quantity,
+        dimension: Dimension,
+    ) -> Result<Measure, QuantityError> {
+        let factors = match dimension {
+            Dimension::Count if quantity.unit == "1" => None,
+            Dimension::Count => return Err(QuantityError::Unit(quantity.unit.clone())),
+            Dimension::Length => Some(&self.length),
+            Dimension::Area => Some(&self.area),
+        };
+        let factor = match factors {
+            Some(factors) => *factors
+                .get(unit_id(&quantity.unit))
+                .ok_or_else(|| QuantityError::Unit(quantity.unit.clone()))?,
+            None => 1.,
+        };
+
+        let convert = |amount: &str| match parse_decimal(amount) {
+            Some(amount) => Ok(amount * factor),
+            None => Err(QuantityError::Decimal(amount.into())),
+        };
+        Ok(Measure {
+            amount: convert(&quantity.amount)?,
+            lower_bound: quantity.lower_bound.as_deref().map(convert).transpose()?,
+            upper_bound: quantity.upper_bound.as_deref().map(convert).transpose()?,
+        })
+    }
+}
+
+impl Measure {
+    /// Returns the amount as a whole number, e.g. for populations, rounding it if necessary.
+    pub fn count(&self) -> Option<u64> {
+        if self.amount.is_finite() && self.amount >= 0. {
+            Some(self.amount.round() as u64)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the ID at the end of a unit IRI, e.g. `Q11573` for
+/// `http://www.wikidata.org/entity/Q11573`.
+fn unit_id(unit: &str) -> &str {
+    unit.rsplit('/').next().unwrap_or(unit)
+}
+
+/// Parses a decimal as it is written in the dump, e.g. `+1234.5` or `-3`. Unlike `f64`'s parser,
+/// this doesn't accept exponents, `inf` or `NaN`.
+pub fn parse_decimal(s: &str) -> Option<f64> {
+    let digits = s.strip_prefix(['+', '-']).unwrap_or(s);
+    let (int, fraction) = match digits.split_once('.') {
+        Some((int, fraction)) => (int, Some(fraction)),
+        None => (digits, None),
+    };
+    let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !is_digits(int) || !fraction.is_none_or(is_digits) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::Rules;
+
+    fn quantity(amount: &str, bounds: Option<(&str, &str)>, unit: &str) -> Quantity {
+        Quantity {
+            amount: amount.into(),
+            lower_bound: bounds.map(|(lower, _)| lower.into()),
+            upper_bound: bounds.map(|(_, upper)| upper.into()),
+            unit: unit.into(),
+        }
+    }
+
+    #[test]
+    fn parses_decimals() {
+        assert_eq!(parse_decimal("+1234"), Some(1234.));
+        assert_eq!(parse_decimal("+1234.5"), Some(1234.5));
+        assert_eq!(parse_decimal("-0.25"), Some(-0.25));
+        assert_eq!(parse_decimal("17"), Some(17.));
+        for invalid in [
+            "", "+", "1.", ".5", "1,234", "1 234", "1e3", "+inf", "NaN", "--1",
+        ] {
+            assert_eq!(parse_decimal(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn converts_units() {
+        let units = Rules::default().units;
+        let entity = |id: &str| format!("http://www.wikidata.org/entity/{}", id);
+
+        let population = units
+            .measure(
+                &quantity("+1234.5", Some(("+1000", "+1500")), "1"),
+                Dimension::Count,
+            )
+            .unwrap();
+        assert_eq!(
+            population,
+            Measure {
+                amount: 1234.5,
+                lower_bound: Some(1000.),
+                upper_bound: Some(1500.),
+            }
+        );
+        assert_eq!(population.count(), Some(1235));
+        assert_eq!(
+            units.measure(&quantity("+5", None, &entity("Q11573")), Dimension::Count),
+            Err(QuantityError::Unit(entity("Q11573")))
+        );
+
+        let feet = units
+            .measure(
+                &quantity("+1000", None, &entity("Q3710")),
+                Dimension::Length,
+            )
+            .unwrap();
+        assert!((feet.amount - 304.8).abs() < 1e-9);
+        let square_miles = units
+            .measure(&quantity("+2", None, &entity("Q232291")), Dimension::Area)
+            .unwrap();
+        assert!((square_miles.amount - 5.179976220672).abs() < 1e-9);
+        assert_eq!(
+            units.measure(&quantity("+2", None, "1"), Dimension::Area),
+            Err(QuantityError::Unit("1".into()))
+        );
+        assert_eq!(
+            units.measure(&quantity("+1,5", None, "1"), Dimension::Count),
+            Err(QuantityError::Decimal("+1,5".into()))
+        );
+    }
+}
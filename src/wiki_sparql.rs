@@ -1,17 +1,28 @@
 use crate::input::http::USER_AGENT;
+use crate::rules::Rules;
+use crate::wikibase::Wikibase;
 use reqwest::header;
 use reqwest::Url;
 use serde::Deserialize;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-const BASE_URL: &str = "https://query.wikidata.org/sparql";
-
-pub fn load_subclasses(parent_class: &str) -> reqwest::Result<HashSet<String>> {
+/// Loads all subclasses of a class, which must be given with its ID in the Wikibase instance.
+pub fn load_subclasses(
+    wikibase: &Wikibase,
+    parent_class: &str,
+) -> reqwest::Result<HashSet<String>> {
     debug!("Loading subclasses for {:?}", parent_class);
-    let mut url = Url::parse(BASE_URL).expect("bad BASE_URL!");
+    let mut url = Url::parse(&wikibase.sparql_url).expect("bad SPARQL URL!");
+    // full IRIs, since other query services may not define the wd: and wdt: prefixes
     url.query_pairs_mut().append_pair(
         "query",
-        &format!("SELECT ?s WHERE {{ ?s wdt:P279+ wd:{} . }}", parent_class),
+        &format!(
+            "SELECT ?s WHERE {{ ?s <{}{}>+ <{}{}> . }}",
+            wikibase.direct_property_uri,
+            wikibase.property("P279"),
+            wikibase.entity_uri,
+            parent_class
+        ),
     );
 
     #[derive(Deserialize)]
@@ -51,7 +62,7 @@ pub fn load_subclasses(parent_class: &str) -> reqwest::Result<HashSet<String>> {
                 Url::parse(&entity.s.value)
                     .ok()?
                     .path_segments()?
-                    .last()?
+                    .next_back()?
                     .to_string(),
             )
         })
@@ -66,66 +77,44 @@ pub fn load_subclasses(parent_class: &str) -> reqwest::Result<HashSet<String>> {
     Ok(classes)
 }
 
+/// The classes (including subclasses) of entities to extract, in the Wikibase instance.
 pub struct Classes {
     pub territorial_entities: HashSet<String>,
+    pub excluded_territorial_entities: HashSet<String>,
+    /// First-level administrative country subdivisions.
+    pub second_level_admin_div: HashSet<String>,
     pub human_settlements: HashSet<String>,
-    pub excluded: HashSet<String>,
     pub excluded_settlements: HashSet<String>,
-    pub second_level_admin_div: HashSet<String>,
     pub languages: HashSet<String>,
 }
 
 impl Classes {
-    pub fn new_from_http() -> reqwest::Result<Classes> {
-        let mut territorial_entities = load_subclasses("Q56061")?;
-        territorial_entities.insert("Q56061".into());
-
-        let mut human_settlements = load_subclasses("Q486972")?;
-        human_settlements.insert("Q486972".into());
-
-        let mut excluded: HashSet<String> = HashSet::new();
-        excluded.insert("Q2974842".into()); // lost cities
-        excluded.insert("Q123705".into()); // neighborhoods, including stuff like shipyards
-        excluded.insert("Q131596".into()); // farm
-        excluded.insert("Q40357".into()); // prisons
-        excluded.insert("Q695850".into()); // airbase
-
-        // unexcluded because this causes moscow (->Q4442912) and others to be excluded
-        // excluded.insert("Q19953632".into()); // former administrative territorial entities
-
-        for superclass in excluded.clone() {
-            for subclass in load_subclasses(&superclass)? {
-                excluded.insert(subclass);
-            }
-        }
-
-        let mut excluded_settlements: HashSet<String> = HashSet::new();
-        excluded_settlements.insert("Q941150".into()); // Plusregio (administrative unit in NL)
-        excluded_settlements.insert("Q159313".into()); // urban agglomeration
-        excluded_settlements.insert("Q106505045".into()); // linear pottery culture
-        excluded_settlements.insert("Q22969563".into()); // bodendenkmal
-
-        for superclass in excluded_settlements.clone() {
-            for subclass in load_subclasses(&superclass)? {
-                excluded_settlements.insert(subclass);
+    /// Loads the subclasses of the classes in the rules.
+    pub fn new_from_http(wikibase: &Wikibase, rules: &Rules) -> reqwest::Result<Classes> {
+        // the same classes are excluded for several handlers
+        let mut loaded: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut load = |roots: &[String]| -> reqwest::Result<HashSet<String>> {
+            let mut classes = HashSet::new();
+            for root in roots {
+                // the Wikidata IDs of classes are mapped to the IDs in the Wikibase instance
+                let root = wikibase.class(root).to_string();
+                if !loaded.contains_key(&root) {
+                    let subclasses = load_subclasses(wikibase, &root)?;
+                    loaded.insert(root.clone(), subclasses);
+                }
+                classes.extend(loaded[&root].iter().cloned());
+                classes.insert(root);
             }
-        }
-
-        // classes & subclasses of "first-level administrative country subdivision"
-        // (why is this field called second_level? uh)
-        let mut second_level_admin_div = load_subclasses("Q10864048")?;
-        second_level_admin_div.insert("Q10864048".into());
-
-        let mut languages = load_subclasses("Q34770")?;
-        languages.insert("Q34770".into());
+            Ok(classes)
+        };
 
         Ok(Classes {
-            human_settlements,
-            territorial_entities,
-            excluded,
-            excluded_settlements,
-            second_level_admin_div,
-            languages,
+            territorial_entities: load(&rules.territorial_entity.classes)?,
+            excluded_territorial_entities: load(&rules.territorial_entity.excluded_classes)?,
+            second_level_admin_div: load(&rules.territorial_entity.first_level_classes)?,
+            human_settlements: load(&rules.human_settlement.classes)?,
+            excluded_settlements: load(&rules.human_settlement.excluded_classes)?,
+            languages: load(&rules.language.classes)?,
         })
     }
 }
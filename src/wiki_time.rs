@@ -1,6 +1,5 @@
-use crate::json_get;
+use crate::wiki_entity::{Statement, Time};
 use chrono::{Datelike, Timelike};
-use serde_json::Value;
 use std::fmt;
 use std::fmt::Formatter;
 use std::num::ParseIntError;
@@ -136,64 +135,59 @@ pub fn parse_wikidata_time(datetime: &str, zone_off: f64) -> Result<WikiTime, Ti
     Ok(wiki_time.add_seconds(zone_off as i32 * 60))
 }
 
-pub fn is_object_start_active(qualifiers: Option<&serde_json::Map<String, Value>>) -> Option<bool> {
-    qualifiers.and_then(|qualifiers| {
-        if let Some(start) = json_get!((qualifiers).P580[0]: object) {
-            if json_get!((start).snaktype: string) == Some("value") {
-                if let Some(time) = json_get!((start).datavalue.value: object) {
-                    if let (Some(datetime), Some(zone)) = (
-                        json_get!((time).time: string),
-                        json_get!((time).timezone: number),
-                    ) {
-                        if let Ok(time) = parse_wikidata_time(datetime, zone) {
-                            if time > WikiTime::now() {
-                                return Some(false);
-                            }
-                        }
-                    }
-                }
-            }
-            return Some(true);
-        }
-        None
+/// Formats the date of a time as far as it is known, e.g. `2021` if its precision is a year, or
+/// `2021-06-15` if it is a day.
+pub fn format_date(time: &Time) -> Option<String> {
+    let date = time.time.split('T').next()?;
+    let (sign, date) = match date.strip_prefix('-') {
+        Some(date) => ("-", date),
+        None => ("", date.strip_prefix('+').unwrap_or(date)),
+    };
+    let mut parts = date.splitn(3, '-');
+    let year = parts.next().filter(|year| !year.is_empty())?;
+    // an unknown month or day is 00
+    let month = parts.next().filter(|month| *month != "00");
+    let day = parts.next().filter(|day| *day != "00");
+
+    Some(match (time.precision, month, day) {
+        (11.., Some(month), Some(day)) => format!("{}{}-{}-{}", sign, year, month, day),
+        (10.., Some(month), _) => format!("{}{}-{}", sign, year, month),
+        _ => format!("{}{}", sign, year),
     })
 }
 
-pub fn is_object_end_active(qualifiers: Option<&serde_json::Map<String, Value>>) -> Option<bool> {
-    qualifiers.and_then(|qualifiers| {
-        if let Some(end) = json_get!((qualifiers).P582[0]: object) {
-            if json_get!((end).snaktype: string) == Some("value") {
-                if let Some(time) = json_get!((end).datavalue.value: object) {
-                    if let (Some(datetime), Some(zone)) = (
-                        json_get!((time).time: string),
-                        json_get!((time).timezone: number),
-                    ) {
-                        if let Ok(time) = parse_wikidata_time(datetime, zone) {
-                            if time < WikiTime::now() {
-                                return Some(false);
-                            }
-                        }
-                    }
-                }
-            }
-            return Some(true);
-        }
-        None
-    })
+/// Returns the time of a qualifier, if it has a valid one.
+fn qualifier_time(statement: &Statement, property: &str) -> Option<WikiTime> {
+    let time = statement.qualifier(property)?.value()?.time()?;
+    parse_wikidata_time(&time.time, time.timezone as f64).ok()
 }
 
-pub fn is_object_active(qualifiers: Option<&serde_json::Map<String, Value>>) -> bool {
-    if qualifiers.is_none() {
-        return true; // assume true if no qualifiers given
+/// Returns whether the statement has started, if it has a start time (P580).
+pub fn is_object_start_active(statement: &Statement) -> Option<bool> {
+    statement.qualifier("P580")?;
+    match qualifier_time(statement, "P580") {
+        Some(time) if time > WikiTime::now() => Some(false),
+        _ => Some(true),
     }
+}
+
+/// Returns whether the statement has not ended yet, if it has an end time (P582).
+pub fn is_object_end_active(statement: &Statement) -> Option<bool> {
+    statement.qualifier("P582")?;
+    match qualifier_time(statement, "P582") {
+        Some(time) if time < WikiTime::now() => Some(false),
+        _ => Some(true),
+    }
+}
 
+pub fn is_object_active(statement: &Statement) -> bool {
     // check if it already ended
-    if is_object_end_active(qualifiers) == Some(false) {
+    if is_object_end_active(statement) == Some(false) {
         return false;
     }
 
     // check if it hasn't started yet
-    if is_object_start_active(qualifiers) == Some(false) {
+    if is_object_start_active(statement) == Some(false) {
         return false;
     }
 
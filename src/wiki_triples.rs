@@ -0,0 +1,520 @@
+//! Reads entities from the truthy N-Triples dump (`latest-truthy.nt.bz2`).
+//!
+//! The triples of an entity are converted to the same [`Entity`] as the JSON dump, so that the rest
+//! of the extraction doesn't need to know about the format. The truthy dump only contains
+//! the best-ranked statements of each property, without qualifiers or units.
+
+use crate::wiki_entity::{
+    DataValue, Entity, GlobeCoordinate, Label, MonolingualText, Quantity, Rank, Snak, SnakType,
+    Statement, Time,
+};
+use crate::wikibase::Wikibase;
+use std::collections::HashMap;
+use thiserror::Error;
+
+const LABEL: &str = "http://www.w3.org/2000/01/rdf-schema#label";
+const XSD_DECIMAL: &str = "http://www.w3.org/2001/XMLSchema#decimal";
+const XSD_DATE_TIME: &str = "http://www.w3.org/2001/XMLSchema#dateTime";
+const WKT_LITERAL: &str = "http://www.opengis.net/ont/geosparql#wktLiteral";
+/// Path of skolemized blank nodes, e.g. `http://www.wikidata.org/.well-known/genid/0123abcd`.
+const GENID_PATH: &str = "/.well-known/genid/";
+
+#[derive(Debug, Error)]
+pub enum TripleError {
+    #[error("invalid triple: {0}")]
+    Syntax(String),
+    #[error("invalid escape sequence in literal")]
+    Escape,
+}
+
+/// The object of a triple.
+#[derive(Debug, PartialEq)]
+enum Object<'a> {
+    Iri(&'a str),
+    Literal {
+        value: String,
+        lang: Option<&'a str>,
+        datatype: Option<&'a str>,
+    },
+    /// A blank node, which is how unknown values are represented. Dumps skolemize them into
+    /// IRIs under `/.well-known/genid/`.
+    Blank,
+}
+
+/// Returns the entity ID (e.g. `Q64`) if the subject of the triple is an entity.
+pub fn entity_subject<'a>(line: &'a str, wikibase: &Wikibase) -> Option<&'a str> {
+    line.strip_prefix('<')?
+        .strip_prefix(wikibase.entity_uri.as_str())?
+        .split('>')
+        .next()
+}
+
+/// Converts the triples of an entity (one per line) to an entity like those of the JSON dump.
+///
+/// Returns `None` if the first triple is not about an entity.
+pub fn parse_entity(triples: &str, wikibase: &Wikibase) -> Result<Option<Entity>, TripleError> {
+    let id = match triples
+        .lines()
+        .next()
+        .and_then(|line| entity_subject(line, wikibase))
+    {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+
+    let mut labels = HashMap::new();
+    let mut claims: HashMap<String, Vec<Statement>> = HashMap::new();
+    for line in triples.lines() {
+        if line.is_empty() || line.starts_with('#') || entity_subject(line, wikibase) != Some(id) {
+            // e.g. sitelinks, which are about the article rather than the entity
+            continue;
+        }
+        let (_, predicate, object) = parse_triple(line)?;
+
+        if predicate == LABEL {
+            if let Object::Literal {
+                value,
+                lang: Some(lang),
+                ..
+            } = object
+            {
+                let label = Label {
+                    language: lang.into(),
+                    value,
+                };
+                labels.insert(lang.into(), label);
+            }
+        } else if let Some(property) = predicate.strip_prefix(wikibase.direct_property_uri.as_str())
+        {
+            if let Some(value) = data_value(object, wikibase) {
+                claims.entry(property.into()).or_default().push(Statement {
+                    mainsnak: Snak {
+                        snaktype: SnakType::Value,
+                        datavalue: Some(value),
+                    },
+                    qualifiers: HashMap::new(),
+                    // the ranks are lost, but all statements have the best rank
+                    rank: Rank::Normal,
+                    references: Vec::new(),
+                });
+            }
+        }
+    }
+
+    Ok(Some(Entity {
+        id: id.into(),
+        labels,
+        claims,
+    }))
+}
+
+/// Converts an object to the value of a statement in the JSON dump.
+fn data_value(object: Object, wikibase: &Wikibase) -> Option<DataValue> {
+    match object {
+        Object::Iri(iri) => Some(match iri.strip_prefix(wikibase.entity_uri.as_str()) {
+            Some(id) => DataValue::EntityId(id.into()),
+            // e.g. Commons media or URLs
+            None => DataValue::String(iri.into()),
+        }),
+        Object::Literal {
+            value,
+            lang: Some(lang),
+            ..
+        } => Some(DataValue::MonolingualText(MonolingualText {
+            language: lang.into(),
+            text: value,
+        })),
+        Object::Literal {
+            value,
+            datatype: Some(XSD_DECIMAL),
+            ..
+        } => {
+            // units are only in the full RDF dump
+            Some(DataValue::Quantity(Quantity {
+                amount: value,
+                lower_bound: None,
+                upper_bound: None,
+                unit: "1".into(),
+            }))
+        }
+        Object::Literal {
+            value,
+            datatype: Some(XSD_DATE_TIME),
+            ..
+        } => {
+            let time = if value.starts_with('-') || value.starts_with('+') {
+                value
+            } else {
+                format!("+{}", value)
+            };
+            // the precision is only in the full RDF dump
+            Some(DataValue::Time(Time {
+                time,
+                timezone: 0,
+                precision: 11,
+            }))
+        }
+        Object::Literal {
+            value,
+            datatype: Some(WKT_LITERAL),
+            ..
+        } => {
+            // e.g. "Point(13.38 52.51)", optionally preceded by the globe
+            let point = value.split("Point(").nth(1)?.strip_suffix(')')?;
+            let mut coords = point.split_whitespace().map(str::parse::<f64>);
+            match (coords.next(), coords.next()) {
+                (Some(Ok(longitude)), Some(Ok(latitude))) => {
+                    Some(DataValue::GlobeCoordinate(GlobeCoordinate {
+                        latitude,
+                        longitude,
+                    }))
+                }
+                _ => None,
+            }
+        }
+        Object::Literal { value, .. } => Some(DataValue::String(value)),
+        Object::Blank => None,
+    }
+}
+
+fn parse_triple(line: &str) -> Result<(&str, &str, Object<'_>), TripleError> {
+    let syntax_error = || TripleError::Syntax(line.into());
+
+    let (subject, rest) = parse_iri(line).ok_or_else(syntax_error)?;
+    let (predicate, rest) = parse_iri(rest.trim_start()).ok_or_else(syntax_error)?;
+    let rest = rest.trim_start();
+
+    let (object, rest) = if rest.starts_with('<') {
+        let (iri, rest) = parse_iri(rest).ok_or_else(syntax_error)?;
+        if iri.contains(GENID_PATH) {
+            (Object::Blank, rest)
+        } else {
+            (Object::Iri(iri), rest)
+        }
+    } else if rest.starts_with("_:") {
+        let end = rest.find(char::is_whitespace).ok_or_else(syntax_error)?;
+        (Object::Blank, &rest[end..])
+    } else if let Some(literal) = rest.strip_prefix('"') {
+        let end = literal_end(literal).ok_or_else(syntax_error)?;
+        let value = unescape(&literal[..end])?;
+        let rest = &literal[end + 1..];
+
+        if let Some(tagged) = rest.strip_prefix('@') {
+            let end = tagged
+                .find(|c: char| c.is_whitespace() || c == '.')
+                .ok_or_else(syntax_error)?;
+            let object = Object::Literal {
+                value,
+                lang: Some(&tagged[..end]),
+                datatype: None,
+            };
+            (object, &tagged[end..])
+        } else if let Some(typed) = rest.strip_prefix("^^") {
+            let (datatype, rest) = parse_iri(typed).ok_or_else(syntax_error)?;
+            let object = Object::Literal {
+                value,
+                lang: None,
+                datatype: Some(datatype),
+            };
+            (object, rest)
+        } else {
+            let object = Object::Literal {
+                value,
+                lang: None,
+                datatype: None,
+            };
+            (object, rest)
+        }
+    } else {
+        return Err(syntax_error());
+    };
+
+    if rest.trim() != "." {
+        return Err(syntax_error());
+    }
+    Ok((subject, predicate, object))
+}
+
+/// Parses an IRI in angle brackets at the start of the string.
+fn parse_iri(s: &str) -> Option<(&str, &str)> {
+    let s = s.strip_prefix('<')?;
+    let end = s.find('>')?;
+    Some((&s[..end], &s[end + 1..]))
+}
+
+/// Returns the position of the quote that ends a literal.
+fn literal_end(literal: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in literal.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+fn unescape(s: &str) -> Result<String, TripleError> {
+    if !s.contains('\\') {
+        return Ok(s.into());
+    }
+
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next().ok_or(TripleError::Escape)? {
+            't' => out.push('\t'),
+            'b' => out.push('\u{8}'),
+            'n' => out.push('\n'),
+            'r' => out.push('\r'),
+            'f' => out.push('\u{c}'),
+            c @ ('"' | '\'' | '\\') => out.push(c),
+            c @ ('u' | 'U') => {
+                let len = if c == 'u' { 4 } else { 8 };
+                let hex: String = chars.by_ref().take(len).collect();
+                let c = u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == len)
+                    .and_then(char::from_u32)
+                    .ok_or(TripleError::Escape)?;
+                out.push(c);
+            }
+            _ => return Err(TripleError::Escape),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::DataEntry;
+    use crate::rules::Rules;
+    use crate::test_util::{classes, item, statement};
+    use crate::wiki_data_line::{handle_line, EntityFormat};
+    use serde_json::json;
+
+    const ENTITY: &str = "http://www.wikidata.org/entity/";
+    const DIRECT: &str = "http://www.wikidata.org/prop/direct/";
+
+    fn entries(line: &str, format: EntityFormat) -> Vec<DataEntry> {
+        let (send, recv) = crossbeam::channel::unbounded();
+        handle_line(
+            line,
+            format,
+            &Wikibase::default(),
+            &Rules::default(),
+            &classes(),
+            &send,
+            false,
+        )
+        .unwrap();
+        drop(send);
+        let mut entries: Vec<_> = recv.into_iter().collect();
+
+        // the truthy dump has no qualifiers, so dates are the only difference
+        for entry in &mut entries {
+            match entry {
+                DataEntry::City {
+                    population_date, ..
+                } => *population_date = None,
+                DataEntry::PopulationHistory { date, .. } => *date = None,
+                _ => (),
+            }
+        }
+        // labels come in the order of a hash map
+        for labels in entries.chunk_by_mut(|a, b| {
+            matches!(a, DataEntry::ObjectLabel { .. }) && matches!(b, DataEntry::ObjectLabel { .. })
+        }) {
+            labels.sort_by_key(|label| match label {
+                DataEntry::ObjectLabel { lang, label, .. } => (lang.clone(), label.clone()),
+                _ => Default::default(),
+            });
+        }
+        entries
+    }
+
+    #[test]
+    fn parses_triples() {
+        let (subject, predicate, object) = parse_triple(
+            r#"<http://www.wikidata.org/entity/Q64> <http://www.w3.org/2000/01/rdf-schema#label> "Ber\"lin\\ é\t\U0001F600"@de-ch ."#,
+        )
+        .unwrap();
+        assert_eq!(subject, "http://www.wikidata.org/entity/Q64");
+        assert_eq!(predicate, LABEL);
+        assert_eq!(
+            object,
+            Object::Literal {
+                value: "Ber\"lin\\ é\t😀".into(),
+                lang: Some("de-ch"),
+                datatype: None,
+            }
+        );
+
+        let (_, _, object) =
+            parse_triple(r#"<a> <b> "+3644826"^^<http://www.w3.org/2001/XMLSchema#decimal> ."#)
+                .unwrap();
+        assert_eq!(
+            object,
+            Object::Literal {
+                value: "+3644826".into(),
+                lang: None,
+                datatype: Some(XSD_DECIMAL),
+            }
+        );
+
+        let (_, _, object) = parse_triple("<a> <b> _:b0 .").unwrap();
+        assert_eq!(object, Object::Blank);
+        let (_, _, object) =
+            parse_triple("<a> <b> <http://www.wikidata.org/.well-known/genid/8f3a0b2c4d5e6f70> .")
+                .unwrap();
+        assert_eq!(object, Object::Blank);
+
+        assert!(matches!(
+            parse_triple(r#"<a> <b> "\x" ."#),
+            Err(TripleError::Escape)
+        ));
+        assert!(matches!(
+            parse_triple(r#"<a> <b> "\u00E" ."#),
+            Err(TripleError::Escape)
+        ));
+        assert!(matches!(
+            parse_triple(r#"<a> <b> "unterminated ."#),
+            Err(TripleError::Syntax(_))
+        ));
+        assert!(matches!(
+            parse_triple("<a> <b> <c>"),
+            Err(TripleError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn parses_coordinates() {
+        let wikibase = Wikibase::default();
+        let coordinates = |wkt: &str| {
+            let object = Object::Literal {
+                value: wkt.into(),
+                lang: None,
+                datatype: Some(WKT_LITERAL),
+            };
+            match data_value(object, &wikibase) {
+                Some(DataValue::GlobeCoordinate(coordinates)) => {
+                    Some((coordinates.latitude, coordinates.longitude))
+                }
+                _ => None,
+            }
+        };
+        assert_eq!(coordinates("Point(13.38 52.51)"), Some((52.51, 13.38)));
+        assert_eq!(
+            coordinates("<http://www.wikidata.org/entity/Q405> Point(-2.5 10)"),
+            Some((10., -2.5))
+        );
+        assert_eq!(coordinates("Point(13.38)"), None);
+        assert_eq!(coordinates("Polygon((0 0, 1 1))"), None);
+    }
+
+    #[test]
+    fn produces_the_same_entries_as_the_json_dump() {
+        let json_line = item(
+            "Q64",
+            &[("de", "Berlin \"Mitte\" é"), ("eo", "Berlino")],
+            json!({
+                "P31": [statement(json!({ "id": "Q515" }))],
+                "P17": [
+                    statement(json!({ "id": "Q183" })),
+                    // an unknown value
+                    { "mainsnak": { "snaktype": "somevalue" } },
+                ],
+                "P131": [statement(json!({ "id": "Q1208" }))],
+                "P625": [statement(json!({ "latitude": 52.516666666667, "longitude": 13.383333333333 }))],
+                "P1082": [{
+                    "mainsnak": { "snaktype": "value", "datavalue": { "value": {
+                        "amount": "+3644826", "unit": "1"
+                    } } },
+                    "qualifiers": { "P585": [{ "snaktype": "value", "datavalue": { "value": {
+                        "time": "+2020-01-01T00:00:00Z", "timezone": 0, "precision": 9
+                    } } }] },
+                }],
+                "P1705": [statement(json!({ "language": "de", "text": "Berlin" }))],
+                "P1566": [
+                    statement(json!("2950159")),
+                    { "mainsnak": { "snaktype": "somevalue" } },
+                ],
+            }),
+        );
+        let triple = |predicate: &str, object: &str| {
+            format!("<{}Q64> <{}> {} .\n", ENTITY, predicate, object)
+        };
+        let direct =
+            |property: &str, object: &str| triple(&format!("{}{}", DIRECT, property), object);
+        let entity = |id: &str| format!("<{}{}>", ENTITY, id);
+        let triples = [
+            triple(LABEL, r#""Berlin \"Mitte\" é"@de"#),
+            triple("http://schema.org/name", r#""Berlin \"Mitte\" é"@de"#),
+            triple(LABEL, r#""Berlino"@eo"#),
+            direct("P31", &entity("Q515")),
+            direct("P17", &entity("Q183")),
+            direct(
+                "P17",
+                "<http://www.wikidata.org/.well-known/genid/8f3a0b2c4d5e6f70>",
+            ),
+            direct("P131", &entity("Q1208")),
+            direct(
+                "P625",
+                &format!(
+                    r#""Point(13.383333333333 52.516666666667)"^^<{}>"#,
+                    WKT_LITERAL
+                ),
+            ),
+            direct("P1082", &format!(r#""+3644826"^^<{}>"#, XSD_DECIMAL)),
+            direct("P1705", r#""Berlin"@de"#),
+            direct("P1566", r#""2950159""#),
+            direct(
+                "P1566",
+                "<http://www.wikidata.org/.well-known/genid/0123456789abcdef>",
+            ),
+            // sitelinks are about the article
+            format!(
+                "<https://de.wikipedia.org/wiki/Berlin> <http://schema.org/about> {} .\n",
+                entity("Q64")
+            ),
+        ]
+        .concat();
+
+        let from_json = entries(&format!("{},", json_line), EntityFormat::Json);
+        let from_triples = entries(&triples, EntityFormat::Truthy);
+        assert_eq!(from_triples, from_json);
+
+        assert!(from_json.contains(&DataEntry::City {
+            id: "Q64".into(),
+            population: Some(3644826),
+            population_date: None,
+            lat: Some(52.516666666667),
+            lon: Some(13.383333333333),
+            elevation: None,
+            area: None,
+        }));
+        assert!(from_json.contains(&DataEntry::ObjectLabel {
+            id: "Q64".into(),
+            lang: "de".into(),
+            label: "Berlin \"Mitte\" é".into(),
+            native_order: None,
+        }));
+        let countries = from_json
+            .iter()
+            .filter(|entry| matches!(entry, DataEntry::CityCountry { .. }))
+            .count();
+        assert_eq!(countries, 1);
+        let external_ids = from_json
+            .iter()
+            .filter(|entry| matches!(entry, DataEntry::ExternalId { .. }))
+            .count();
+        assert_eq!(external_ids, 1);
+    }
+}
@@ -0,0 +1,121 @@
+//! Configuration of the Wikibase instance to build from, which is Wikidata by default.
+
+use crate::wiki_entity::Entity;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::{fs, io};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Wikibase {
+    /// Directory containing `latest-all.json.bz2` and a directory with the dumps of each date,
+    /// if the dumps are published like Wikidata's.
+    pub dumps_url: Option<String>,
+    /// Prefix of the file names of dated dumps, e.g. `wikidata` in
+    /// `wikidata-20210101-all.json.bz2`.
+    pub dump_name: String,
+    /// The SPARQL endpoint of the query service.
+    pub sparql_url: String,
+    /// URL of the JSON data of an entity, with `{id}` in place of the ID.
+    pub entity_data_url: String,
+    /// The MediaWiki action API, for recent changes.
+    pub api_url: String,
+    /// Namespace of items, whose page titles are their IDs.
+    pub item_namespace: u32,
+    /// Prefix of entity IRIs in RDF.
+    pub entity_uri: String,
+    /// Prefix of the IRIs of direct ("truthy") properties in RDF.
+    pub direct_property_uri: String,
+    /// Wikidata property IDs mapped to the IDs of the same properties in this instance. Properties
+    /// that are not mapped are assumed to have the same IDs.
+    pub properties: HashMap<String, String>,
+    /// Wikidata class IDs mapped to the IDs of the same classes in this instance, like
+    /// `properties`.
+    pub classes: HashMap<String, String>,
+}
+
+impl Default for Wikibase {
+    fn default() -> Self {
+        Wikibase {
+            dumps_url: Some("https://dumps.wikimedia.org/wikidatawiki/entities".into()),
+            dump_name: "wikidata".into(),
+            sparql_url: "https://query.wikidata.org/sparql".into(),
+            entity_data_url: "https://wikidata.org/wiki/Special:EntityData/{id}.json".into(),
+            api_url: "https://www.wikidata.org/w/api.php".into(),
+            item_namespace: 0,
+            entity_uri: "http://www.wikidata.org/entity/".into(),
+            direct_property_uri: "http://www.wikidata.org/prop/direct/".into(),
+            properties: HashMap::new(),
+            classes: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum WikibaseError {
+    #[error("could not read config: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid config: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl Wikibase {
+    pub fn load(path: &str) -> Result<Self, WikibaseError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Returns the ID of a Wikidata property in this instance.
+    pub fn property<'a>(&'a self, id: &'a str) -> &'a str {
+        self.properties.get(id).map_or(id, String::as_str)
+    }
+
+    /// Returns the ID of a Wikidata class in this instance.
+    pub fn class<'a>(&'a self, id: &'a str) -> &'a str {
+        self.classes.get(id).map_or(id, String::as_str)
+    }
+
+    pub fn entity_data_url(&self, id: &str) -> String {
+        self.entity_data_url.replace("{id}", id)
+    }
+
+    /// Renames the properties of an entity's statements and their qualifiers to the Wikidata
+    /// IDs, so that they can be read like Wikidata entities.
+    pub fn map_to_wikidata(&self, entity: &mut Entity) {
+        if self.properties.is_empty() {
+            return;
+        }
+        let wikidata_ids: HashMap<&str, &str> = self
+            .properties
+            .iter()
+            .map(|(wikidata_id, id)| (id.as_str(), wikidata_id.as_str()))
+            .collect();
+
+        self.map_properties(&mut entity.claims, &wikidata_ids);
+        for statements in entity.claims.values_mut() {
+            for statement in statements {
+                self.map_properties(&mut statement.qualifiers, &wikidata_ids);
+            }
+        }
+    }
+
+    fn map_properties<T>(
+        &self,
+        properties: &mut HashMap<String, T>,
+        wikidata_ids: &HashMap<&str, &str>,
+    ) {
+        let old = std::mem::take(properties);
+        for (id, value) in old {
+            match wikidata_ids.get(id.as_str()) {
+                Some(wikidata_id) => {
+                    properties.insert((*wikidata_id).into(), value);
+                }
+                // a Wikidata ID that means something else in this instance
+                None if self.properties.contains_key(&id) => (),
+                None => {
+                    properties.insert(id, value);
+                }
+            }
+        }
+    }
+}
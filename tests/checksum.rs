@@ -0,0 +1,62 @@
+//! Builds databases from a dump served over HTTP, with a correct and a wrong checksum.
+
+mod common;
+
+use common::{dump, geo_db, server, test_dir, wikibase};
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+
+/// Builds a database with the given checksum for the dump, and returns the output of the build.
+fn build(dir: &Path, md5: &str) -> Output {
+    let mut routes = HashMap::new();
+    routes.insert(
+        "/md5sums.txt".to_string(),
+        vec![format!("{}  latest-all.json.bz2\n", md5).into_bytes()],
+    );
+    let server = server(routes);
+    let wikibase = wikibase(dir, &server);
+
+    geo_db(&[
+        "--dump-url",
+        &server.url,
+        "--checksum",
+        &server.url_of("/md5sums.txt"),
+        "--cache",
+        dir.join("dump.json.bz2").to_str().unwrap(),
+        "--wikibase",
+        wikibase.to_str().unwrap(),
+        "--output",
+        dir.join("geo.db").to_str().unwrap(),
+    ])
+}
+
+#[test]
+fn fails_on_checksum_mismatches() {
+    let dir = test_dir("checksum");
+    let db = dir.join("geo.db");
+    let cache = dir.join("dump.json.bz2");
+
+    let md5 = format!("{:x}", Md5::digest(dump()));
+    let output = build(&dir, &md5);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(db.exists());
+    assert!(cache.exists());
+
+    fs::remove_file(&db).unwrap();
+    fs::remove_file(&cache).unwrap();
+    let output = build(&dir, "0123456789abcdef0123456789abcdef");
+    assert!(!output.status.success(), "{:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("does not match its checksum"));
+    assert!(!cache.exists());
+    assert!(!dir.join("dump.json.bz2.part").exists());
+    assert!(!db.exists());
+
+    let output = geo_db(&["post", db.to_str().unwrap()]);
+    assert!(!output.status.success(), "{:?}", output);
+    assert!(!db.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,62 @@
+//! Helpers for the integration tests, which run the binary against a `TestServer`.
+
+use bzip2::write::BzEncoder;
+use bzip2::Compression;
+use geo_db::input::test_server::{Fault, TestServer};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const DUMP: &str = r#"[
+{"type":"item","id":"Q1","labels":{"en":{"language":"en","value":"One"}},"claims":{}},
+]
+"#;
+
+pub fn geo_db(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_geo-db"))
+        .args(args)
+        .output()
+        .expect("failed to run geo-db")
+}
+
+/// Returns a small compressed dump.
+pub fn dump() -> Vec<u8> {
+    let mut encoder = BzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(DUMP.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Serves the dump as the latest one, along with the given responses and a SPARQL endpoint.
+pub fn server(mut routes: HashMap<String, Vec<Vec<u8>>>) -> TestServer {
+    // no classes, which isn't a problem since the dump has no places anyway
+    routes.insert(
+        "/sparql".to_string(),
+        vec![br#"{"results":{"bindings":[]}}"#.to_vec()],
+    );
+    TestServer::start_with_routes(dump(), Vec::new(), Fault::None, routes)
+}
+
+/// Writes a Wikibase config for the server to the directory and returns its path.
+pub fn wikibase(dir: &Path, server: &TestServer) -> PathBuf {
+    let path = dir.join("wikibase.json");
+    fs::write(
+        &path,
+        format!(
+            r#"{{ "sparql_url": "{}", "dumps_url": "{}" }}"#,
+            server.url_of("/sparql"),
+            server.url_of("")
+        ),
+    )
+    .unwrap();
+    path
+}
+
+/// Returns an empty directory for the given test.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("geo-db-{}-test-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,38 @@
+//! Builds a database from the latest dump of a Wikibase instance, as `geo-db` does by default.
+
+mod common;
+
+use common::{geo_db, server, test_dir, wikibase};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::fs;
+
+#[test]
+fn saves_the_date_of_the_latest_dump() {
+    let dir = test_dir("dump-info");
+    let server = server(HashMap::new());
+    let wikibase = wikibase(&dir, &server);
+    let db = dir.join("geo.db");
+
+    let output = geo_db(&[
+        "--wikibase",
+        wikibase.to_str().unwrap(),
+        "--output",
+        db.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(server.targets().last().unwrap(), "/latest-all.json.bz2");
+
+    let conn = Connection::open(&db).unwrap();
+    let (source, date): (String, Option<String>) = conn
+        .query_row("select source, date from dump_info", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .unwrap();
+    assert_eq!(source, server.url);
+    // from the Last-Modified header, since the file name has no date
+    assert_eq!(date.as_deref(), Some("20210102"));
+
+    drop(conn);
+    fs::remove_dir_all(&dir).unwrap();
+}